@@ -0,0 +1,89 @@
+use std::io::{BufRead, Write};
+
+use crate::{
+    error::CalcError,
+    eval::{evaluate_detailed, Context, EvalResult, Number},
+    parser::parse,
+    tokenizer::{parse_number, tokenize_with_syntax, Token},
+};
+
+/// Evaluates `expr` once for every data row of the CSV read from `input` and writes one result per
+/// line to `output`. Each result is written as `format` returns it, or as an empty line if it
+/// returns `None`.
+///
+/// The first line of the CSV is the header. Its column names must be valid variable names, which
+/// become variables that are set to the values of the current row before `expr` is evaluated.
+/// Constants like `pi` can't be columns. Errors in a row are wrapped in `CalcError::InCsvRow` with
+/// the 1-based number of the data row. Quoting is not supported.
+pub fn map_csv<R, W, F>(
+    expr: &str,
+    ctx: &mut Context,
+    input: R,
+    mut output: W,
+    format: F,
+) -> Result<(), CalcError>
+where
+    R: BufRead,
+    W: Write,
+    F: Fn(&EvalResult) -> Option<String>,
+{
+    let tokens = tokenize_with_syntax(expr, ctx.syntax())?;
+    let ast = parse(&tokens)?;
+
+    let mut lines = input.lines();
+    let header = match lines.next() {
+        Some(header) => header?,
+        None => return Err(CalcError::InvalidCsv("Missing header".to_owned())),
+    };
+    let columns: Vec<_> = header
+        .split(',')
+        .map(|name| name.trim().to_owned())
+        .collect();
+    for name in &columns {
+        if !matches!(
+            tokenize_with_syntax(name, ctx.syntax()).as_deref(),
+            Ok([Token::Identifier(_)])
+        ) {
+            return Err(CalcError::InvalidCsv(format!(
+                "Column name '{}' is not a valid variable name",
+                name
+            )));
+        }
+    }
+
+    let data_rows = lines.filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()));
+    for (idx, line) in data_rows.enumerate() {
+        let in_row = |error| CalcError::InCsvRow {
+            row: idx + 1,
+            error: Box::new(error),
+        };
+
+        let line = line?;
+        let fields: Vec<_> = line.split(',').collect();
+        if fields.len() != columns.len() {
+            return Err(in_row(CalcError::InvalidCsv(format!(
+                "Expected {} fields, got {}",
+                columns.len(),
+                fields.len()
+            ))));
+        }
+
+        for (name, field) in columns.iter().zip(fields) {
+            let value = parse_field(field).map_err(in_row)?;
+            ctx.assign_var(name.as_str(), value)
+                .map_err(|e| in_row(e.into()))?;
+        }
+        let result = evaluate_detailed(&ast, ctx).map_err(|e| in_row(e.into()))?;
+        writeln!(output, "{}", format(&result).unwrap_or_default())?;
+    }
+    Ok(())
+}
+
+/// Parses a CSV field, which is a number with an optional sign.
+fn parse_field(field: &str) -> Result<Number, CalcError> {
+    let field = field.trim();
+    match field.strip_prefix('-') {
+        Some(abs) => Ok(-parse_number(abs)?),
+        None => parse_number(field.strip_prefix('+').unwrap_or(field)),
+    }
+}
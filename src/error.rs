@@ -3,14 +3,109 @@ use std::{
     io,
 };
 
-use crate::tokenizer::Token;
+use crate::tokenizer::{Keyword, Token};
 
+/// Any error that can occur while evaluating source code.
+///
+/// The error enums are `#[non_exhaustive]`, since new kinds of errors are added along with new
+/// features. Matching on specific variants is fine, but a wildcard arm is required. The `is_*`
+/// helpers look through `InFile` and `InCsvRow`, so they also work for errors of files and CSV
+/// rows.
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum CalcError {
+    /// The source contains a character or literal that is not allowed
     TokenizeError(TokenizeError),
-    ParseError(ParseError),
+    /// The source is not syntactically valid. The location is the 1-based line and column where
+    /// parsing failed, if it is known.
+    ParseError(ParseError, Option<(usize, usize)>),
+    /// Evaluation of valid source failed
     EvalError(EvalError),
     IoError(io::Error),
+    /// Raw input is not valid UTF-8
+    InvalidEncoding {
+        offset: usize,
+    },
+    /// An error in the file at `path`
+    InFile {
+        path: String,
+        error: Box<CalcError>,
+    },
+    /// An error in the data row `row` (1-based) of a CSV file
+    InCsvRow {
+        row: usize,
+        error: Box<CalcError>,
+    },
+    /// The CSV file itself is malformed (e.g. a row has the wrong number of fields)
+    InvalidCsv(String),
+}
+
+impl CalcError {
+    /// Returns the innermost error, looking through `InFile` and `InCsvRow`.
+    pub fn root(&self) -> &CalcError {
+        match self {
+            CalcError::InFile { error, .. } | CalcError::InCsvRow { error, .. } => error.root(),
+            error => error,
+        }
+    }
+
+    pub fn eval_error(&self) -> Option<&EvalError> {
+        match self.root() {
+            CalcError::EvalError(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    pub fn parse_error(&self) -> Option<&ParseError> {
+        match self.root() {
+            CalcError::ParseError(error, _) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Returns the 1-based line and column of the source where parsing failed, if the error is a
+    /// parse error with a known location.
+    pub fn location(&self) -> Option<(usize, usize)> {
+        match self.root() {
+            CalcError::ParseError(_, location) => *location,
+            _ => None,
+        }
+    }
+
+    pub fn is_division_by_zero(&self) -> bool {
+        matches!(self.eval_error(), Some(EvalError::DivideByZero))
+    }
+
+    /// Returns the name of the variable if the error is about an undefined variable.
+    pub fn undefined_variable(&self) -> Option<&str> {
+        match self.eval_error() {
+            Some(EvalError::VariableNotDefined(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the function if the error is about an undefined function.
+    pub fn undefined_function(&self) -> Option<&str> {
+        match self.eval_error() {
+            Some(EvalError::FunctionNotDefined(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Checks whether a function was called with the wrong number of arguments.
+    pub fn is_wrong_arg_amount(&self) -> bool {
+        matches!(
+            self.eval_error(),
+            Some(EvalError::FunctionWrongArgAmount { .. } | EvalError::NoMatchingOverload { .. })
+        )
+    }
+
+    /// Checks whether the source could become valid by appending more input, see
+    /// `ParseError::Incomplete`.
+    pub fn is_incomplete_input(&self) -> bool {
+        matches!(self.parse_error(), Some(ParseError::Incomplete(_)))
+    }
 }
 
 impl Display for CalcError {
@@ -18,9 +113,22 @@ impl Display for CalcError {
         use CalcError::*;
         match self {
             TokenizeError(e) => write!(f, "Tokenize error: {}", e),
-            ParseError(e) => write!(f, "Parse error: {}", e),
+            ParseError(e, Some((line, column))) => {
+                write!(f, "line {}, column {}: Parse error: {}", line, column, e)
+            }
+            ParseError(e, None) => write!(f, "Parse error: {}", e),
             EvalError(e) => write!(f, "Eval error: {}", e),
             IoError(e) => write!(f, "IO error: {}", e),
+            InFile { path, error } => write!(f, "{}: {}", path, error),
+            InCsvRow { row, error } => write!(f, "row {}: {}", row, error),
+            InvalidCsv(msg) => write!(f, "Invalid CSV: {}", msg),
+            InvalidEncoding { offset } => {
+                write!(
+                    f,
+                    "Input is not valid UTF-8 (invalid byte at offset {})",
+                    offset
+                )
+            }
         }
     }
 }
@@ -33,7 +141,7 @@ impl From<TokenizeError> for CalcError {
 
 impl From<ParseError> for CalcError {
     fn from(e: ParseError) -> Self {
-        Self::ParseError(e)
+        Self::ParseError(e, None)
     }
 }
 
@@ -50,25 +158,45 @@ impl From<io::Error> for CalcError {
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum TokenizeError {
-    UnexpectedChar(char),
+    /// The char `ch` at the byte offset `pos` of the source can't appear at this place
+    UnexpectedChar {
+        ch: char,
+        pos: usize,
+    },
+    InvalidNumber(String),
 }
 
 impl Display for TokenizeError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         use TokenizeError::*;
         match self {
-            UnexpectedChar(c) => write!(f, "Unexpected char {}", c),
+            UnexpectedChar { ch, pos } => {
+                write!(f, "Unexpected character '{}' at position {}", ch, pos)
+            }
+            InvalidNumber(num) => write!(f, "Invalid number {}", num),
         }
     }
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ParseError {
+    /// The parser needed another token, but the input ended
     NoTokensLeft,
+    /// The token is not allowed at this position
     UnexpectedToken(Token),
+    /// Only the given token is allowed at this position
     ExpectedToken(Token),
+    /// Only a name is allowed at this position
     ExpectedIdentifier,
+    /// A keyword was used as the name of a variable, function or argument.
+    KeywordAsName(Keyword),
+    /// The input ended in the middle of a construct (e.g. an unclosed bracket or a line ending
+    /// with an operator), so it could become valid by appending more input. Contains the error
+    /// that occurred at the end.
+    Incomplete(Box<ParseError>),
 }
 
 impl Display for ParseError {
@@ -79,27 +207,59 @@ impl Display for ParseError {
             UnexpectedToken(t) => write!(f, "Unexpected token {:?}", t),
             ExpectedToken(t) => write!(f, "Expected token {:?}", t),
             ExpectedIdentifier => write!(f, "Expected identifier"),
+            KeywordAsName(keyword) => write!(
+                f,
+                "'{}' is a reserved keyword and can't be used as a name",
+                keyword.as_str()
+            ),
+            Incomplete(err) => write!(f, "Unexpected end of input ({})", err),
         }
     }
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum EvalError {
+    /// Division or modulo by zero
     DivideByZero,
+    /// A value is infinite or NaN
     Overflow,
     VariableNotDefined(String),
+    VariableAlreadyDefined(String),
+    /// Assignment to a variable that was declared with `const`
+    CannotReassignConstant(String),
     FunctionNotDefined(String),
+    /// A function of this name already exists in the scope or is a builtin
     FunctionAlreadyDefined(String),
+    /// A function was called with `got` instead of `expected` arguments.
     FunctionWrongArgAmount {
         name: String,
         expected: usize,
         got: usize,
     },
+    /// None of the variants of an overloaded function takes `got` arguments.
+    NoMatchingOverload {
+        name: String,
+        arg_counts: Vec<usize>,
+        got: usize,
+    },
+    /// A function definition uses the same argument name twice.
     DuplicateArgName {
         func_name: String,
         arg_name: String,
     },
+    /// Too many nested function calls
     CallStackOverflow,
+    /// A loop or a chain of self-recursive tail calls ran for more than `limit` iterations, see
+    /// `Context::set_max_iterations`
+    IterationLimit {
+        limit: usize,
+    },
+    /// A `return` statement was evaluated outside of a function body
+    ReturnOutsideFunction,
+    /// A builtin was called with an argument outside of its domain
+    InvalidArgument(String),
+    AliasTargetNotDefined(String),
 }
 
 impl Display for EvalError {
@@ -109,6 +269,12 @@ impl Display for EvalError {
             DivideByZero => write!(f, "Divide by zero"),
             Overflow => write!(f, "Overflow"),
             VariableNotDefined(name) => write!(f, "Variable with name '{}' is not defined", name),
+            VariableAlreadyDefined(name) => {
+                write!(f, "Variable with name '{}' is already defined", name)
+            }
+            CannotReassignConstant(name) => {
+                write!(f, "Cannot assign to '{}', since it is a constant", name)
+            }
             FunctionNotDefined(name) => write!(f, "Function with name '{}' is not defined", name),
             FunctionAlreadyDefined(name) => {
                 write!(f, "Function with name '{}' is already defined", name)
@@ -122,6 +288,20 @@ impl Display for EvalError {
                 "Function '{}' was called with {} arguments but expects {}",
                 name, got, expected
             ),
+            NoMatchingOverload {
+                name,
+                arg_counts,
+                got,
+            } => {
+                let arg_counts: Vec<_> = arg_counts.iter().map(usize::to_string).collect();
+                write!(
+                    f,
+                    "Function '{}' was called with {} arguments but expects {}",
+                    name,
+                    got,
+                    arg_counts.join(" or ")
+                )
+            }
             DuplicateArgName {
                 func_name,
                 arg_name,
@@ -131,6 +311,16 @@ impl Display for EvalError {
                 func_name, arg_name
             ),
             CallStackOverflow => write!(f, "Call stack overflow (too many nested function calls)"),
+            IterationLimit { limit } => write!(
+                f,
+                "Loop did not finish within the limit of {} iterations",
+                limit
+            ),
+            ReturnOutsideFunction => write!(f, "'return' can only be used in a function body"),
+            InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            AliasTargetNotDefined(name) => {
+                write!(f, "Cannot alias '{}', since it is not defined", name)
+            }
         }
     }
 }
@@ -3,32 +3,83 @@ use std::{
     rc::Rc,
 };
 
-use crate::{error::EvalError, parser::AST};
+use crate::{
+    error::{CalcError, EvalError},
+    parser::{parse_str, AST},
+    rng::Rng,
+    stats::Stats,
+    tokenizer::{number_value, Comparator, Syntax},
+};
 
 // TODO: Find out how to increase this limit, since the stack of the main thread can overflow if
 // this is too large.
 const CALL_STACK_SIZE_LIMIT: usize = 100;
 
+/// Default of `Context::max_iterations`.
+pub const DEFAULT_MAX_ITERATIONS: usize = 1_000_000;
+
 pub type Number = f64;
 
+/// Largest integer up to which every integer is exactly representable as a `Number`.
+const MAX_EXACT_INTEGER: u64 = 1 << 53;
+
+type BuiltinFn = Box<dyn Fn(&mut Context, &[Number]) -> Result<Number, EvalError>>;
+
 pub enum Function {
+    /// A function implemented in Rust. `n_args` is `None` for variadic functions, which accept
+    /// any number of arguments.
     Builtin {
-        n_args: usize,
-        func: Box<dyn Fn(&mut Context, &[Number]) -> Number>,
+        n_args: Option<usize>,
+        func: BuiltinFn,
     },
     UserDefined {
         arg_names: Vec<String>,
         body: AST,
     },
+    /// Several functions with different numbers of arguments under the same name. Calls use the
+    /// variant that takes as many arguments as the call provides. `name` is only used for the
+    /// error of a call that no variant matches.
+    Overloaded {
+        name: String,
+        variants: Vec<Rc<Function>>,
+    },
 }
 
 impl Function {
+    /// Number of arguments that `Context::functions` reports for variadic functions.
+    pub const VARIADIC: usize = usize::MAX;
+
     pub fn new_builtin<F>(n_args: usize, func: F) -> Self
     where
         F: Fn(&mut Context, &[Number]) -> Number + 'static,
+    {
+        Self::new_fallible_builtin(n_args, move |ctx, args| Ok(func(ctx, args)))
+    }
+
+    /// Like `new_builtin`, but for functions that can fail (e.g. because of invalid arguments).
+    pub fn new_fallible_builtin<F>(n_args: usize, func: F) -> Self
+    where
+        F: Fn(&mut Context, &[Number]) -> Result<Number, EvalError> + 'static,
+    {
+        Self::Builtin {
+            n_args: Some(n_args),
+            func: Box::new(func),
+        }
+    }
+
+    pub fn new_variadic_builtin<F>(func: F) -> Self
+    where
+        F: Fn(&mut Context, &[Number]) -> Number + 'static,
+    {
+        Self::new_fallible_variadic_builtin(move |ctx, args| Ok(func(ctx, args)))
+    }
+
+    pub fn new_fallible_variadic_builtin<F>(func: F) -> Self
+    where
+        F: Fn(&mut Context, &[Number]) -> Result<Number, EvalError> + 'static,
     {
         Self::Builtin {
-            n_args,
+            n_args: None,
             func: Box::new(func),
         }
     }
@@ -50,11 +101,33 @@ impl Function {
         Ok(Self::UserDefined { arg_names, body })
     }
 
+    /// Combines functions with fixed and distinct numbers of arguments into one overloaded
+    /// function. Only meant for the builtins, where a violation is a bug.
+    pub(crate) fn new_overloaded(name: &str, variants: Vec<Function>) -> Self {
+        let mut arg_counts = HashSet::new();
+        for variant in &variants {
+            let arg_count = variant
+                .get_arg_count()
+                .expect("Overloads must take a fixed number of arguments");
+            assert!(
+                arg_counts.insert(arg_count),
+                "Overloads must take distinct numbers of arguments"
+            );
+        }
+        Self::Overloaded {
+            name: name.to_owned(),
+            variants: variants.into_iter().map(Rc::new).collect(),
+        }
+    }
+
     pub fn call(&self, ctx: &mut Context, args: &[Number]) -> Result<Number, EvalError> {
         match self {
             Function::Builtin { n_args, func } => {
-                debug_assert!(args.len() == *n_args, "Invalid number of arguments");
-                Ok(func(ctx, args))
+                debug_assert!(
+                    n_args.is_none_or(|n| args.len() == n),
+                    "Invalid number of arguments"
+                );
+                func(ctx, args)
             }
             Function::UserDefined { arg_names, body } => {
                 debug_assert!(args.len() == arg_names.len(), "Invalid number of arguments");
@@ -63,7 +136,8 @@ impl Function {
                     return Err(EvalError::CallStackOverflow);
                 }
 
-                let mut scope = Scope::new();
+                // Reuse the allocations of a previous call's scope if there is one
+                let mut scope = ctx.scope_pool.pop().unwrap_or_else(Scope::new);
                 for (name, value) in arg_names.iter().zip(args.iter()) {
                     scope.set_var(name, *value);
                 }
@@ -73,23 +147,289 @@ impl Function {
                 }
                 ctx.function_scope = Some(scope);
 
-                let call_result = evaluate(body, ctx);
-                ctx.function_scope = ctx.call_stack.pop();
+                // Self-recursive calls in tail position reuse the current scope and rerun the body
+                // instead of recursing
+                let mut iterations = 0;
+                let call_result = loop {
+                    match evaluate_tail(body, self, ctx) {
+                        Ok(Tail::Value(value)) => break Ok(value),
+                        Ok(Tail::SelfCall(_)) if iterations == ctx.max_iterations => {
+                            break Err(EvalError::IterationLimit {
+                                limit: ctx.max_iterations,
+                            });
+                        }
+                        Ok(Tail::SelfCall(args)) => {
+                            iterations += 1;
+                            let scope = ctx.function_scope.as_mut().unwrap();
+                            scope.clear();
+                            for (name, value) in arg_names.iter().zip(args) {
+                                scope.set_var(name, value);
+                            }
+                        }
+                        Err(err) => break Err(err),
+                    }
+                };
+                let finished_scope =
+                    std::mem::replace(&mut ctx.function_scope, ctx.call_stack.pop());
+                if let Some(mut scope) = finished_scope {
+                    scope.clear();
+                    ctx.scope_pool.push(scope);
+                }
                 call_result
             }
+            Function::Overloaded { name, variants } => {
+                find_overload(name, variants, args.len())?.call(ctx, args)
+            }
         }
     }
 
-    fn get_arg_count(&self) -> usize {
+    /// Returns every number of arguments the function can be called with, where
+    /// `Function::VARIADIC` stands for any number.
+    fn arities(&self) -> Vec<usize> {
+        match self {
+            Function::Overloaded { variants, .. } => variants
+                .iter()
+                .flat_map(|variant| variant.arities())
+                .collect(),
+            _ => vec![self.get_arg_count().unwrap_or(Function::VARIADIC)],
+        }
+    }
+
+    /// Returns the number of arguments the function expects or `None` if it is variadic or
+    /// overloaded.
+    fn get_arg_count(&self) -> Option<usize> {
         match self {
             Function::Builtin { n_args, .. } => *n_args,
-            Function::UserDefined { arg_names, .. } => arg_names.len(),
+            Function::UserDefined { arg_names, .. } => Some(arg_names.len()),
+            Function::Overloaded { .. } => None,
         }
     }
 }
 
+/// Returns the variant of the overloaded function `name` that takes `n_args` arguments.
+fn find_overload<'a>(
+    name: &str,
+    variants: &'a [Rc<Function>],
+    n_args: usize,
+) -> Result<&'a Rc<Function>, EvalError> {
+    variants
+        .iter()
+        .find(|variant| variant.get_arg_count() == Some(n_args))
+        .ok_or_else(|| EvalError::NoMatchingOverload {
+            name: name.to_owned(),
+            arg_counts: variants.iter().filter_map(|v| v.get_arg_count()).collect(),
+            got: n_args,
+        })
+}
+
+/// Maps an angle in radians to the range `(-pi, pi]`.
+///
+/// `rem_euclid` reduces the angle without building up a quotient first, so large inputs don't lose
+/// more precision than the input itself already has.
+fn normalize_angle(angle: Number) -> Number {
+    use std::f64::consts::{PI, TAU};
+
+    let reduced = angle.rem_euclid(TAU);
+    if reduced > PI {
+        reduced - TAU
+    } else {
+        reduced
+    }
+}
+
+/// Converts an angle in degrees to radians for the `*d` trigonometric functions.
+///
+/// The angle is reduced to `[0, 360)` first, which is exact in degrees, so large angles like
+/// `sind(36000090)` are as precise as small ones.
+fn degrees_to_radians(angle: Number) -> Number {
+    angle.rem_euclid(360.0).to_radians()
+}
+
+/// Converts `value` to a whole number (a non-negative integer), which some builtins require as
+/// argument.
+///
+/// Values above 2^53 are rejected, since not every integer in that range can be represented.
+fn to_whole_number(func_name: &str, value: Number) -> Result<u64, EvalError> {
+    if value < 0.0 || value.fract() != 0.0 || value > MAX_EXACT_INTEGER as Number {
+        return Err(EvalError::InvalidArgument(format!(
+            "{} expects a whole number up to 2^53, got {}",
+            func_name, value
+        )));
+    }
+    Ok(value as u64)
+}
+
+fn is_prime(n: u64) -> bool {
+    if n < 4 {
+        return n >= 2;
+    }
+    if n.is_multiple_of(2) || n.is_multiple_of(3) {
+        return false;
+    }
+    // Every prime above 3 is of the form 6k - 1 or 6k + 1. Since `n` is at most 2^53, this
+    // takes at most about 3 * 10^7 iterations.
+    let mut divisor = 5;
+    while divisor * divisor <= n {
+        if n.is_multiple_of(divisor) || n.is_multiple_of(divisor + 2) {
+            return false;
+        }
+        divisor += 6;
+    }
+    true
+}
+
+fn next_prime(n: u64) -> Result<u64, EvalError> {
+    let mut candidate = n + 1;
+    while !is_prime(candidate) {
+        candidate += 1;
+    }
+    if candidate > MAX_EXACT_INTEGER {
+        return Err(EvalError::InvalidArgument(format!(
+            "next_prime({}) is larger than 2^53",
+            n
+        )));
+    }
+    Ok(candidate)
+}
+
+/// Greatest common divisor by the Euclidean algorithm, with `gcd(0, 0) = 0`.
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Least common multiple, which is 0 if one of the numbers is 0.
+fn lcm(a: u64, b: u64) -> Result<u64, EvalError> {
+    if a == 0 || b == 0 {
+        return Ok(0);
+    }
+    (a / gcd(a, b))
+        .checked_mul(b)
+        .filter(|&lcm| lcm <= MAX_EXACT_INTEGER)
+        .ok_or_else(|| EvalError::InvalidArgument(format!("lcm({}, {}) is larger than 2^53", a, b)))
+}
+
+/// Smaller of both values, used by `min` for each pair of arguments.
+///
+/// Unlike `f64::min`, the result doesn't depend on the order of the arguments: `-0` is considered
+/// smaller than `0`. NaN is not handled specially, since an argument that evaluates to NaN is
+/// already an error.
+fn minimum(a: Number, b: Number) -> Number {
+    if a == b {
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+/// Larger of both values, used by `max` for each pair of arguments. See `minimum` for the
+/// handling of zeros.
+fn maximum(a: Number, b: Number) -> Number {
+    if a == b {
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+/// Factorial of a non-negative integer, with `0! = 1`. Other values are an error instead of being
+/// extended with the gamma function.
+fn factorial(n: Number) -> Result<Number, EvalError> {
+    if n < 0.0 || n.fract() != 0.0 {
+        return Err(EvalError::InvalidArgument(format!(
+            "Factorial is only defined for non-negative integers, got {}",
+            n
+        )));
+    }
+    // 171! and above don't fit into a float, which is reported as an overflow
+    if n > 170.0 {
+        return Ok(Number::INFINITY);
+    }
+    Ok((2..=n as u32).map(Number::from).product())
+}
+
+/// The `n`th root of `x`, i.e. `x^(1/n)`.
+///
+/// Odd roots of negative numbers are negative, like `cbrt`. Even (and non-integer) roots of
+/// negative numbers are NaN and thus an error, like `sqrt(-1)`.
+fn nth_root(x: Number, n: Number) -> Result<Number, EvalError> {
+    if n == 0.0 {
+        return Err(EvalError::InvalidArgument(
+            "nroot is not defined for n = 0".to_owned(),
+        ));
+    }
+    let is_integer = n.fract() == 0.0;
+    if x < 0.0 && is_integer && n % 2.0 != 0.0 {
+        return Ok(-nth_root(-x, n)?);
+    }
+    let root = x.powf(1.0 / n);
+    // `1 / n` is rounded, so roots that should be whole numbers may be slightly off, e.g.
+    // `27^(1/3)` is 3.0000000000000004
+    let rounded = root.round();
+    if is_integer && rounded.powf(n) == x {
+        Ok(rounded)
+    } else {
+        Ok(root)
+    }
+}
+
+/// Remainder whose sign follows the divisor, like `%` in Python.
+fn floored_modulo(lhs: Number, rhs: Number) -> Number {
+    let remainder = lhs % rhs;
+    if remainder != 0.0 && (remainder < 0.0) != (rhs < 0.0) {
+        remainder + rhs
+    } else {
+        remainder
+    }
+}
+
+/// Decides whether a number counts as true in a condition.
+///
+/// Every number except zero is true. There is no tolerance, so even tiny results of rounding
+/// errors like `0.1 + 0.2 - 0.3` are true. Use a comparison with an explicit epsilon if that is not
+/// wanted.
+pub fn is_truthy(value: Number) -> bool {
+    value != 0.0
+}
+
+fn compare(cmp: Comparator, lhs: Number, rhs: Number) -> bool {
+    match cmp {
+        Comparator::Less => lhs < rhs,
+        Comparator::Greater => lhs > rhs,
+        Comparator::LessEqual => lhs <= rhs,
+        Comparator::GreaterEqual => lhs >= rhs,
+        Comparator::Equal => lhs == rhs,
+        Comparator::NotEqual => lhs != rhs,
+    }
+}
+
+/// Decides the sign of the result of the `%` operator if the operands have different signs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ModuloMode {
+    /// The result has the sign of the dividend (`-7 % 3 == -1`), like `%` in Rust and C.
+    #[default]
+    Truncated,
+    /// The result has the sign of the divisor (`-7 % 3 == 2`), like `%` in Python.
+    Floored,
+}
+
+#[derive(Clone)]
 struct Scope {
     variables: HashMap<String, Number>,
+    /// Lower and upper bound of variables that were declared with `bound`
+    bounds: HashMap<String, (Number, Number)>,
+    /// Names of variables that were declared with `const`
+    constants: HashSet<String>,
     functions: HashMap<String, Rc<Function>>,
 }
 
@@ -97,10 +437,20 @@ impl Scope {
     pub fn new() -> Self {
         Self {
             variables: HashMap::new(),
+            bounds: HashMap::new(),
+            constants: HashSet::new(),
             functions: HashMap::new(),
         }
     }
 
+    /// Removes all variables and functions, while keeping the allocated memory.
+    pub fn clear(&mut self) {
+        self.variables.clear();
+        self.bounds.clear();
+        self.constants.clear();
+        self.functions.clear();
+    }
+
     pub fn get_function(&self, name: &str) -> Option<Rc<Function>> {
         self.functions.get(name).cloned()
     }
@@ -110,7 +460,7 @@ impl Scope {
         name: impl Into<String> + AsRef<str>,
         func: Function,
     ) -> Result<(), EvalError> {
-        if self.functions.get(name.as_ref()).is_some() {
+        if self.functions.contains_key(name.as_ref()) {
             return Err(EvalError::FunctionAlreadyDefined(name.into()));
         }
         self.functions.insert(name.into(), Rc::new(func));
@@ -121,35 +471,240 @@ impl Scope {
         self.variables.get(name).copied()
     }
 
-    pub fn set_var(&mut self, name: impl Into<String>, val: Number) {
-        self.variables.insert(name.into(), val);
+    /// Sets the variable, clamping the value if the variable is bounded. Returns the stored value.
+    pub fn set_var(&mut self, name: impl Into<String>, val: Number) -> Number {
+        let name = name.into();
+        let val = match self.bounds.get(&name) {
+            Some(&(lo, hi)) => val.clamp(lo, hi),
+            None => val,
+        };
+        self.variables.insert(name, val);
+        val
+    }
+
+    /// Removes the variable and its bounds, returning the value and the bounds.
+    pub fn remove_var(&mut self, name: &str) -> Option<(Number, Option<(Number, Number)>)> {
+        let value = self.variables.remove(name)?;
+        Some((value, self.bounds.remove(name)))
     }
 }
 
+/// Evaluation state (variables, functions and the random number generator).
+///
+/// Cloning a context yields an independent copy: Changing variables or drawing random numbers in
+/// the clone doesn't affect the original. The standard functions are immutable and shared between
+/// the clones.
+#[derive(Clone)]
 pub struct Context {
+    /// The standard functions, which are added once in `new`
+    builtins: Rc<HashMap<String, Rc<Function>>>,
     global_scope: Scope,
     function_scope: Option<Scope>,
     call_stack: Vec<Scope>,
+    /// Cleared scopes of finished function calls, which are reused by later calls to avoid
+    /// allocating a new scope for every call.
+    scope_pool: Vec<Scope>,
+    /// Maps alias names to the names they refer to
+    aliases: HashMap<String, String>,
+    modulo_mode: ModuloMode,
+    syntax: Syntax,
+    /// Number of iterations after which a single loop is aborted
+    max_iterations: usize,
+    rng: Rng,
+    /// Accumulators of the `stats_*` functions, whose handles are the indices
+    stats: Vec<Stats>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Context {
     pub fn new() -> Self {
         let mut ctx = Self {
+            builtins: Rc::new(HashMap::new()),
             global_scope: Scope::new(),
             function_scope: None,
             call_stack: Vec::new(),
+            scope_pool: Vec::new(),
+            aliases: HashMap::new(),
+            modulo_mode: ModuloMode::default(),
+            syntax: Syntax::default(),
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            rng: Rng::new(),
+            stats: Vec::new(),
         };
         ctx.add_standard_variables();
         ctx.add_standard_functions()
             .expect("Failed to add standard functions");
+        ctx.builtins = Rc::new(std::mem::take(&mut ctx.global_scope.functions));
         ctx
     }
 
+    /// Creates a context with the standard variables and functions and then calls `setup` on it,
+    /// e.g. to add custom functions and constants or to change settings.
+    pub fn new_with<F>(setup: F) -> Self
+    where
+        F: FnOnce(&mut Context),
+    {
+        let mut ctx = Self::new();
+        setup(&mut ctx);
+        ctx
+    }
+
+    /// Creates a context for running code in a sandbox. The fork starts out with the variables,
+    /// user-defined functions and settings of `self`, but changes on either side are not visible to
+    /// the other one.
+    ///
+    /// This is cheap, since the standard functions are shared instead of being copied.
+    pub fn fork(&self) -> Self {
+        Self {
+            builtins: Rc::clone(&self.builtins),
+            global_scope: self.global_scope.clone(),
+            function_scope: None,
+            call_stack: Vec::new(),
+            scope_pool: Vec::new(),
+            aliases: self.aliases.clone(),
+            modulo_mode: self.modulo_mode,
+            syntax: self.syntax,
+            max_iterations: self.max_iterations,
+            rng: self.rng.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Returns true if both contexts use the same table of standard functions, e.g. because one
+    /// is a fork of the other.
+    pub fn shares_builtins_with(&self, other: &Context) -> bool {
+        Rc::ptr_eq(&self.builtins, &other.builtins)
+    }
+
+    /// Returns the source of all global user-defined functions, sorted by name and separated by
+    /// newlines.
+    ///
+    /// The source is generated from the stored function bodies, so formatting and redundant
+    /// brackets of the original definition are not preserved. Evaluating the source in another
+    /// context defines the same functions there.
+    pub fn functions_source(&self) -> String {
+        let mut functions: Vec<_> = self.global_scope.functions.iter().collect();
+        functions.sort_by_key(|(name, _)| name.as_str());
+        let definitions = functions
+            .into_iter()
+            .filter_map(|(name, func)| function_definition(name, func))
+            .collect();
+        AST::Lines(definitions).to_string()
+    }
+
+    /// Returns the names of the global user-defined functions with their number of arguments,
+    /// sorted by name. Unlike `functions`, this leaves out the standard functions and aliases.
+    pub fn user_functions(&self) -> Vec<(&str, usize)> {
+        let mut functions: Vec<_> = self
+            .global_scope
+            .functions
+            .iter()
+            .filter_map(|(name, func)| match func.as_ref() {
+                Function::UserDefined { arg_names, .. } => Some((name.as_str(), arg_names.len())),
+                Function::Builtin { .. } | Function::Overloaded { .. } => None,
+            })
+            .collect();
+        functions.sort_by_key(|(name, _)| *name);
+        functions
+    }
+
+    /// Returns the names of all functions that can currently be called with their number of
+    /// arguments, sorted by name and number of arguments. This includes the standard functions,
+    /// user-defined functions and aliases of functions.
+    ///
+    /// An overloaded function like `log` appears once for every number of arguments it takes.
+    /// Variadic functions like `sum` are reported with `Function::VARIADIC` arguments. The special
+    /// form `default(name, fallback)` is reported like a function with two arguments.
+    pub fn functions(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        let mut visible: HashMap<&str, &Function> = HashMap::new();
+        let scopes = [Some(&self.global_scope), self.function_scope.as_ref()];
+        let tables = std::iter::once(self.builtins.as_ref())
+            .chain(scopes.into_iter().flatten().map(|scope| &scope.functions));
+        // Inner tables shadow outer ones, like in `lookup_function`
+        for table in tables {
+            for (name, func) in table {
+                visible.insert(name, func);
+            }
+        }
+        for (alias, target) in &self.aliases {
+            if let Some(func) = visible.get(target.as_str()).copied() {
+                visible.entry(alias).or_insert(func);
+            }
+        }
+
+        let mut functions: Vec<_> = visible
+            .into_iter()
+            .flat_map(|(name, func)| func.arities().into_iter().map(move |arity| (name, arity)))
+            .chain(std::iter::once((DEFAULT_FORM, DEFAULT_FORM_ARGS)))
+            .collect();
+        functions.sort_unstable();
+        functions.into_iter()
+    }
+
+    /// Returns the source of the global user-defined function `name` like `functions_source`, or
+    /// `None` if there is no such function.
+    pub fn function_source(&self, name: &str) -> Option<String> {
+        let func = self.global_scope.functions.get(name)?;
+        function_definition(name, func).map(|definition| definition.to_string())
+    }
+
+    /// Removes the function `name` from the innermost scope, returning it if it was defined.
+    ///
+    /// Standard functions can't be removed.
+    pub fn remove_function(&mut self, name: &str) -> Option<Rc<Function>> {
+        let scope = self
+            .function_scope
+            .as_mut()
+            .unwrap_or(&mut self.global_scope);
+        scope.functions.remove(name)
+    }
+
+    /// Returns the global variables with their values, sorted by name.
+    ///
+    /// Constants (including the standard ones like `pi`) are left out.
+    pub fn vars(&self) -> Vec<(&str, Number)> {
+        let scope = &self.global_scope;
+        let mut vars: Vec<_> = scope
+            .variables
+            .iter()
+            .filter(|(name, _)| !scope.constants.contains(*name))
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        vars.sort_by_key(|(name, _)| *name);
+        vars
+    }
+
+    /// Returns the variables of `vars` as a JSON object like `{"a": 2.0, "b": 3.0}`.
+    ///
+    /// Non-finite values, which can only be stored with `set_var`, are written as `null`, since
+    /// JSON has no representation for them.
+    pub fn vars_json(&self) -> String {
+        let entries: Vec<_> = self
+            .vars()
+            .into_iter()
+            .map(|(name, value)| {
+                if value.is_finite() {
+                    format!("{}: {:?}", json_string(name), value)
+                } else {
+                    format!("{}: null", json_string(name))
+                }
+            })
+            .collect();
+        format!("{{{}}}", entries.join(", "))
+    }
+
     fn add_standard_variables(&mut self) {
         use std::f64::consts::{E, PI};
 
-        self.set_var("pi", PI);
-        self.set_var("e", E);
+        self.define_const("pi", PI)
+            .expect("Failed to add standard constants");
+        self.define_const("e", E)
+            .expect("Failed to add standard constants");
     }
 
     fn add_standard_functions(&mut self) -> Result<(), EvalError> {
@@ -164,14 +719,54 @@ impl Context {
             "acos",
             Function::new_builtin(1, |_ctx, args| args[0].acos()),
         )?;
+        // `atan(y, x)` is the same as `atan2(y, x)`
         self.add_function(
             "atan",
-            Function::new_builtin(1, |_ctx, args| args[0].atan()),
+            Function::new_overloaded(
+                "atan",
+                vec![
+                    Function::new_builtin(1, |_ctx, args| args[0].atan()),
+                    Function::new_builtin(2, |_ctx, args| args[0].atan2(args[1])),
+                ],
+            ),
         )?;
         self.add_function(
             "atan2",
             Function::new_builtin(2, |_ctx, args| args[0].atan2(args[1])),
         )?;
+        self.add_function(
+            "sind",
+            Function::new_builtin(1, |_ctx, args| degrees_to_radians(args[0]).sin()),
+        )?;
+        self.add_function(
+            "cosd",
+            Function::new_builtin(1, |_ctx, args| degrees_to_radians(args[0]).cos()),
+        )?;
+        self.add_function(
+            "tand",
+            Function::new_builtin(1, |_ctx, args| degrees_to_radians(args[0]).tan()),
+        )?;
+        self.add_function(
+            "asind",
+            Function::new_builtin(1, |_ctx, args| args[0].asin().to_degrees()),
+        )?;
+        self.add_function(
+            "acosd",
+            Function::new_builtin(1, |_ctx, args| args[0].acos().to_degrees()),
+        )?;
+        self.add_function(
+            "atand",
+            Function::new_builtin(1, |_ctx, args| args[0].atan().to_degrees()),
+        )?;
+        // Unlike the `*d` functions, the conversions don't reduce the angle, so `rad(720)` is 4pi
+        self.add_function(
+            "deg",
+            Function::new_builtin(1, |_ctx, args| args[0].to_degrees()),
+        )?;
+        self.add_function(
+            "rad",
+            Function::new_builtin(1, |_ctx, args| args[0].to_radians()),
+        )?;
         self.add_function(
             "tanh",
             Function::new_builtin(1, |_ctx, args| args[0].tanh()),
@@ -184,6 +779,15 @@ impl Context {
             "cosh",
             Function::new_builtin(1, |_ctx, args| args[0].cosh()),
         )?;
+        // Unlike `sqrt(x^2 + y^2)`, this doesn't overflow for large operands
+        self.add_function(
+            "hypot",
+            Function::new_builtin(2, |_ctx, args| args[0].hypot(args[1])),
+        )?;
+        self.add_function(
+            "norm_angle",
+            Function::new_builtin(1, |_ctx, args| normalize_angle(args[0])),
+        )?;
 
         self.add_function("ln", Function::new_builtin(1, |_ctx, args| args[0].ln()))?;
         self.add_function(
@@ -194,20 +798,64 @@ impl Context {
             "log10",
             Function::new_builtin(1, |_ctx, args| args[0].log10()),
         )?;
+        // `log(x)` is the common logarithm, `log(x, base)` uses the given base
         self.add_function(
             "log",
-            Function::new_builtin(2, |_ctx, args| args[0].log(args[1])),
+            Function::new_overloaded(
+                "log",
+                vec![
+                    Function::new_builtin(1, |_ctx, args| args[0].log10()),
+                    Function::new_builtin(2, |_ctx, args| args[0].log(args[1])),
+                ],
+            ),
         )?;
 
         self.add_function("abs", Function::new_builtin(1, |_ctx, args| args[0].abs()))?;
+        // Unlike `f64::signum`, zero (including -0) has the sign 0. NaN stays NaN, which is
+        // reported as an overflow like any other NaN result.
+        self.add_function(
+            "sign",
+            Function::new_builtin(1, |_ctx, args| {
+                if args[0] == 0.0 {
+                    0.0
+                } else {
+                    args[0].signum()
+                }
+            }),
+        )?;
         self.add_function(
             "min",
-            Function::new_builtin(2, |_ctx, args| args[0].min(args[1])),
+            Function::new_fallible_variadic_builtin(|_ctx, args| {
+                args.iter().copied().reduce(minimum).ok_or_else(|| {
+                    EvalError::InvalidArgument("min expects at least one argument".to_owned())
+                })
+            }),
         )?;
         self.add_function(
             "max",
-            Function::new_builtin(2, |_ctx, args| args[0].max(args[1])),
+            Function::new_fallible_variadic_builtin(|_ctx, args| {
+                args.iter().copied().reduce(maximum).ok_or_else(|| {
+                    EvalError::InvalidArgument("max expects at least one argument".to_owned())
+                })
+            }),
+        )?;
+        // The bounds are never swapped, since `lo > hi` is most likely a mistake
+        self.add_function(
+            "clamp",
+            Function::new_fallible_builtin(3, |_ctx, args| {
+                let (x, lo, hi) = (args[0], args[1], args[2]);
+                if lo.is_nan() || hi.is_nan() || lo > hi {
+                    return Err(EvalError::InvalidArgument(format!(
+                        "clamp expects lo <= hi, got lo = {} and hi = {}",
+                        lo, hi
+                    )));
+                }
+                Ok(maximum(lo, minimum(x, hi)))
+            }),
         )?;
+        // `floor`, `ceil` and `round` operate directly on the f64 value, so they never add error on
+        // top of the input. Values with a magnitude of at least 2^52 are already integers and are
+        // returned unchanged. `round` rounds half away from zero.
         self.add_function(
             "floor",
             Function::new_builtin(1, |_ctx, args| args[0].floor()),
@@ -220,13 +868,152 @@ impl Context {
             "round",
             Function::new_builtin(1, |_ctx, args| args[0].round()),
         )?;
+        // Rounds to the nearest multiple of the step, with halves rounded away from zero like in
+        // `round`. The sign of the step doesn't matter.
+        self.add_function(
+            "round_to_multiple",
+            Function::new_fallible_builtin(2, |_ctx, args| {
+                let (value, step) = (args[0], args[1].abs());
+                if step == 0.0 {
+                    return Err(EvalError::InvalidArgument(
+                        "round_to_multiple expects a non-zero step".to_owned(),
+                    ));
+                }
+                Ok((value / step).round() * step)
+            }),
+        )?;
+
+        self.add_function(
+            "is_prime",
+            Function::new_fallible_builtin(1, |_ctx, args| {
+                let n = to_whole_number("is_prime", args[0])?;
+                Ok(if is_prime(n) { 1.0 } else { 0.0 })
+            }),
+        )?;
+        self.add_function(
+            "next_prime",
+            Function::new_fallible_builtin(1, |_ctx, args| {
+                let n = to_whole_number("next_prime", args[0])?;
+                Ok(next_prime(n)? as Number)
+            }),
+        )?;
+        // Like the prime functions, `gcd` and `lcm` only accept whole numbers instead of
+        // truncating or taking the absolute value
+        self.add_function(
+            "gcd",
+            Function::new_fallible_builtin(2, |_ctx, args| {
+                let a = to_whole_number("gcd", args[0])?;
+                let b = to_whole_number("gcd", args[1])?;
+                Ok(gcd(a, b) as Number)
+            }),
+        )?;
+        self.add_function(
+            "lcm",
+            Function::new_fallible_builtin(2, |_ctx, args| {
+                let a = to_whole_number("lcm", args[0])?;
+                let b = to_whole_number("lcm", args[1])?;
+                Ok(lcm(a, b)? as Number)
+            }),
+        )?;
+        // Unlike `%` this always uses the floored modulo, so `mod(-7, 3)` is 2 in every modulo
+        // mode. It can only be called like this, since `mod` is also the word form of `%`.
+        self.add_function(
+            "mod",
+            Function::new_fallible_builtin(2, |_ctx, args| {
+                if args[1] == 0.0 {
+                    return Err(EvalError::DivideByZero);
+                }
+                Ok(floored_modulo(args[0], args[1]))
+            }),
+        )?;
+
+        self.add_function(
+            "product",
+            Function::new_variadic_builtin(|_ctx, args| args.iter().product()),
+        )?;
+        self.add_function(
+            "sum",
+            Function::new_variadic_builtin(|_ctx, args| args.iter().sum()),
+        )?;
+        self.add_function(
+            "mean",
+            Function::new_fallible_variadic_builtin(|_ctx, args| {
+                if args.is_empty() {
+                    return Err(EvalError::InvalidArgument(
+                        "mean expects at least one argument".to_owned(),
+                    ));
+                }
+                Ok(args.iter().sum::<Number>() / args.len() as Number)
+            }),
+        )?;
 
         self.add_function(
             "sqrt",
             Function::new_builtin(1, |_ctx, args| args[0].sqrt()),
         )?;
+        self.add_function(
+            "cbrt",
+            Function::new_builtin(1, |_ctx, args| args[0].cbrt()),
+        )?;
+        self.add_function(
+            "nroot",
+            Function::new_fallible_builtin(2, |_ctx, args| nth_root(args[0], args[1])),
+        )?;
         self.add_function("exp", Function::new_builtin(1, |_ctx, args| args[0].exp()))?;
 
+        self.add_function(
+            "random",
+            Function::new_builtin(0, |ctx, _args| ctx.rng.next_f64()),
+        )?;
+        self.add_function(
+            "seed",
+            Function::new_builtin(1, |ctx, args| {
+                ctx.rng = Rng::with_seed(args[0].to_bits());
+                args[0]
+            }),
+        )?;
+
+        // Streams of numbers are aggregated in accumulators, which are referred to by the handle that
+        // `stats_new` returns
+        self.add_function(
+            "stats_new",
+            Function::new_builtin(0, |ctx, _args| {
+                ctx.stats.push(Stats::new());
+                (ctx.stats.len() - 1) as Number
+            }),
+        )?;
+        self.add_function(
+            "stats_push",
+            Function::new_fallible_builtin(2, |ctx, args| {
+                let stats = ctx.stats_mut(args[0])?;
+                stats.push(args[1]);
+                Ok(stats.count() as Number)
+            }),
+        )?;
+        self.add_function(
+            "stats_count",
+            Function::new_fallible_builtin(1, |ctx, args| {
+                Ok(ctx.stats_mut(args[0])?.count() as Number)
+            }),
+        )?;
+        type StatsQuery = fn(&Stats) -> Option<Number>;
+        let queries: [(&str, StatsQuery); 4] = [
+            ("stats_mean", Stats::mean),
+            ("stats_variance", Stats::variance),
+            ("stats_min", Stats::min),
+            ("stats_max", Stats::max),
+        ];
+        for (name, query) in queries {
+            self.add_function(
+                name,
+                Function::new_fallible_builtin(1, move |ctx, args| {
+                    query(ctx.stats_mut(args[0])?).ok_or_else(|| {
+                        EvalError::InvalidArgument(format!("{} of empty stats", name))
+                    })
+                }),
+            )?;
+        }
+
         self.add_function(
             "inspect",
             Function::new_builtin(1, |_ctx, args| {
@@ -234,36 +1021,240 @@ impl Context {
                 args[0]
             }),
         )?;
+        // Same as `inspect`, but a line that only calls `print` doesn't produce a value (see
+        // `produces_value`)
+        self.add_function(
+            "print",
+            Function::new_builtin(1, |_ctx, args| {
+                println!("{}", args[0]);
+                args[0]
+            }),
+        )?;
 
         Ok(())
     }
 
     pub fn get_function(&mut self, name: &str) -> Option<Rc<Function>> {
+        self.lookup_function(name).or_else(|| {
+            let target = self.aliases.get(name)?;
+            self.lookup_function(target)
+        })
+    }
+
+    /// Looks up the function that is called by a call of `name` with `n_args` arguments. For an
+    /// overloaded function, this is the variant that takes `n_args` arguments.
+    fn get_callee(&mut self, name: &str, n_args: usize) -> Result<Rc<Function>, EvalError> {
+        let func = self
+            .get_function(name)
+            .ok_or_else(|| EvalError::FunctionNotDefined(name.to_owned()))?;
+        match func.as_ref() {
+            // The error names the function like the call, which may use an alias
+            Function::Overloaded { variants, .. } => find_overload(name, variants, n_args).cloned(),
+            _ => Ok(func),
+        }
+    }
+
+    fn lookup_function(&self, name: &str) -> Option<Rc<Function>> {
         self.function_scope
             .as_ref()
             .and_then(|s| s.get_function(name))
             .or_else(|| self.global_scope.get_function(name))
+            .or_else(|| self.builtins.get(name).cloned())
     }
 
+    /// Adds a function to the innermost scope.
+    ///
+    /// Functions defined inside a function body only live as long as the call that defined them.
+    /// They are visible in the rest of that body, may shadow global functions and are gone once
+    /// the call returns.
+    ///
+    /// The name of the special form `default` is reserved, so it can't be used for a function.
     pub fn add_function(
         &mut self,
         name: impl Into<String> + AsRef<str>,
         func: Function,
     ) -> Result<(), EvalError> {
-        let scope = self
-            .function_scope
-            .as_mut()
-            .unwrap_or(&mut self.global_scope);
-        scope.add_function(name, func)
+        if name.as_ref() == DEFAULT_FORM {
+            return Err(EvalError::FunctionAlreadyDefined(name.into()));
+        }
+        match self.function_scope.as_mut() {
+            Some(scope) => scope.add_function(name, func),
+            None if self.builtins.contains_key(name.as_ref()) => {
+                Err(EvalError::FunctionAlreadyDefined(name.into()))
+            }
+            None => self.global_scope.add_function(name, func),
+        }
+    }
+
+    pub fn modulo_mode(&self) -> ModuloMode {
+        self.modulo_mode
+    }
+
+    pub fn set_modulo_mode(&mut self, mode: ModuloMode) {
+        self.modulo_mode = mode;
+    }
+
+    /// Returns the syntax extensions that are used when source code is evaluated with this
+    /// context.
+    pub fn syntax(&self) -> Syntax {
+        self.syntax
+    }
+
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+    }
+
+    /// Returns the number of iterations after which a loop fails with
+    /// `EvalError::IterationLimit`, which guards against accidental infinite loops. Self-recursive
+    /// tail calls run in a loop, so they count as iterations, too.
+    pub fn max_iterations(&self) -> usize {
+        self.max_iterations
+    }
+
+    pub fn set_max_iterations(&mut self, max_iterations: usize) {
+        self.max_iterations = max_iterations;
     }
 
     pub fn get_var(&self, name: &str) -> Option<Number> {
+        self.lookup_var(name).or_else(|| {
+            let target = self.aliases.get(name)?;
+            self.lookup_var(target)
+        })
+    }
+
+    fn lookup_var(&self, name: &str) -> Option<Number> {
         self.function_scope
             .as_ref()
             .and_then(|s| s.get_var(name))
             .or_else(|| self.global_scope.get_var(name))
     }
 
+    /// Makes `new_name` refer to the function or variable called `existing_name`.
+    ///
+    /// The alias is resolved on every lookup, so it follows changes to the variable it refers to.
+    /// A function or variable that is actually called `new_name` takes precedence over the alias.
+    pub fn define_alias(
+        &mut self,
+        new_name: impl Into<String>,
+        existing_name: &str,
+    ) -> Result<(), EvalError> {
+        let target = self
+            .aliases
+            .get(existing_name)
+            .map_or(existing_name, String::as_str);
+        if self.lookup_function(target).is_none() && self.lookup_var(target).is_none() {
+            return Err(EvalError::AliasTargetNotDefined(existing_name.to_owned()));
+        }
+        let target = target.to_owned();
+        self.aliases.insert(new_name.into(), target);
+        Ok(())
+    }
+
+    /// Moves the value of the variable `old_name` in the innermost scope to `new_name`.
+    ///
+    /// Only values are stored, so variables that were computed from `old_name` keep their values
+    /// and are not affected. Fails if `old_name` is not defined or `new_name` already is.
+    pub fn rename_var(
+        &mut self,
+        old_name: &str,
+        new_name: impl Into<String> + AsRef<str>,
+    ) -> Result<(), EvalError> {
+        if self.lookup_var(new_name.as_ref()).is_some() {
+            return Err(EvalError::VariableAlreadyDefined(new_name.into()));
+        }
+        let scope = self
+            .function_scope
+            .as_mut()
+            .unwrap_or(&mut self.global_scope);
+        let (value, bounds) = scope
+            .remove_var(old_name)
+            .ok_or_else(|| EvalError::VariableNotDefined(old_name.to_owned()))?;
+        if let Some(bounds) = bounds {
+            scope.bounds.insert(new_name.as_ref().to_owned(), bounds);
+        }
+        if scope.constants.remove(old_name) {
+            scope.constants.insert(new_name.as_ref().to_owned());
+        }
+        scope.set_var(new_name, value);
+        Ok(())
+    }
+
+    /// Evaluates `src` and stores the result in the variable `name`, returning the result.
+    ///
+    /// If evaluation fails, the variable is left unchanged. Assignments in `src` are executed as
+    /// usual, so `eval_into("a", "b = 2")` sets both `a` and `b` to 2. Like an assignment, this
+    /// fails for constants.
+    pub fn eval_into(&mut self, name: impl Into<String>, src: &str) -> Result<Number, CalcError> {
+        let value = self.eval_src(src)?;
+        Ok(self.assign_var(name, value)?)
+    }
+
+    /// Evaluates each of `sources` in this context in order and returns the result of each.
+    ///
+    /// A failing source doesn't stop the evaluation of the following ones, which see the changes
+    /// made by all sources before them.
+    pub fn eval_many(&mut self, sources: &[&str]) -> Vec<Result<Number, CalcError>> {
+        sources.iter().map(|src| self.eval_src(src)).collect()
+    }
+
+    /// Parses `src` with the syntax of the context and evaluates it, like `eval_str_ctx`.
+    fn eval_src(&mut self, src: &str) -> Result<Number, CalcError> {
+        let ast = parse_str(src, self.syntax)?;
+        Ok(evaluate(&ast, self)?)
+    }
+
+    /// Returns the accumulator of the `stats_*` functions with the given handle.
+    fn stats_mut(&mut self, handle: Number) -> Result<&mut Stats, EvalError> {
+        let invalid = || EvalError::InvalidArgument(format!("{} is not a stats handle", handle));
+        if handle.fract() != 0.0 || handle < 0.0 {
+            return Err(invalid());
+        }
+        self.stats.get_mut(handle as usize).ok_or_else(invalid)
+    }
+
+    /// Removes the variable `name` from the innermost scope, returning its value if it was defined.
+    ///
+    /// Bounds and constness are removed as well, so a variable of the same name that is defined
+    /// later is a normal variable again.
+    pub fn remove_var(&mut self, name: &str) -> Option<Number> {
+        let scope = self
+            .function_scope
+            .as_mut()
+            .unwrap_or(&mut self.global_scope);
+        scope.constants.remove(name);
+        scope.remove_var(name).map(|(value, _)| value)
+    }
+
+    /// Removes all variables, user-defined functions, aliases and stats accumulators.
+    ///
+    /// The standard functions and variables (like `pi`) are kept, as are the settings and the
+    /// state of the random number generator.
+    pub fn clear(&mut self) {
+        self.global_scope.clear();
+        self.aliases.clear();
+        self.stats.clear();
+        self.add_standard_variables();
+    }
+
+    /// Calls the function `name` with `args` like a call in the evaluated code, e.g.
+    /// `ctx.call("max", &[1.0, 2.0])`.
+    ///
+    /// This works for standard, user-defined and overloaded functions as well as aliases. The
+    /// special form `default` takes a variable name instead of a value, so it can't be called.
+    pub fn call(&mut self, name: &str, args: &[Number]) -> Result<Number, CalcError> {
+        let func = self.get_callee(name, args.len())?;
+        check_arg_count(name, &func, args.len())?;
+        let result = func.call(self, args)?;
+        if !result.is_finite() {
+            return Err(EvalError::Overflow.into());
+        }
+        Ok(result)
+    }
+
+    /// Sets a variable in the innermost scope. If the variable is bounded, the value is clamped to
+    /// its bounds.
+    ///
+    /// Unlike an assignment in the evaluated code, this also overwrites constants.
     pub fn set_var(&mut self, name: impl Into<String>, val: Number) {
         let scope = self
             .function_scope
@@ -271,6 +1262,275 @@ impl Context {
             .unwrap_or(&mut self.global_scope);
         scope.set_var(name, val);
     }
+
+    /// Assigns to a variable in the innermost scope like `set_var`, but fails for constants.
+    /// Returns the value that was stored.
+    ///
+    /// Inside a function, a global constant can't be assigned to either, unless a local variable
+    /// or constant of the same name (e.g. an argument) shadows it.
+    pub(crate) fn assign_var(
+        &mut self,
+        name: impl Into<String>,
+        val: Number,
+    ) -> Result<Number, EvalError> {
+        let name = name.into();
+        let is_constant = match &self.function_scope {
+            Some(scope) if scope.get_var(&name).is_some() => scope.constants.contains(&name),
+            _ => self.global_scope.constants.contains(&name),
+        };
+        if is_constant {
+            return Err(EvalError::CannotReassignConstant(name));
+        }
+        let scope = self
+            .function_scope
+            .as_mut()
+            .unwrap_or(&mut self.global_scope);
+        Ok(scope.set_var(name, val))
+    }
+
+    /// Declares a variable in the innermost scope that can't be assigned to by the evaluated code
+    /// anymore. An existing variable of the same name is replaced, unless it is a constant itself.
+    pub fn define_const(&mut self, name: impl Into<String>, val: Number) -> Result<(), EvalError> {
+        let name = name.into();
+        let scope = self
+            .function_scope
+            .as_mut()
+            .unwrap_or(&mut self.global_scope);
+        if scope.constants.contains(&name) {
+            return Err(EvalError::CannotReassignConstant(name));
+        }
+        scope.bounds.remove(&name);
+        scope.set_var(name.clone(), val);
+        scope.constants.insert(name);
+        Ok(())
+    }
+
+    /// Declares a variable in the innermost scope whose value is always clamped to `[lo, hi]`,
+    /// including the initial value `val`. Returns the stored value.
+    ///
+    /// Declaring the variable again replaces the bounds.
+    pub fn declare_bounded_var(
+        &mut self,
+        name: impl Into<String>,
+        val: Number,
+        lo: Number,
+        hi: Number,
+    ) -> Result<Number, EvalError> {
+        let name = name.into();
+        if lo.is_nan() || hi.is_nan() || lo > hi {
+            return Err(EvalError::InvalidArgument(format!(
+                "bounds of '{}' must satisfy lo <= hi, got [{}, {}]",
+                name, lo, hi
+            )));
+        }
+        let scope = self
+            .function_scope
+            .as_mut()
+            .unwrap_or(&mut self.global_scope);
+        if scope.constants.contains(&name) {
+            return Err(EvalError::CannotReassignConstant(name));
+        }
+        scope.bounds.insert(name.clone(), (lo, hi));
+        Ok(scope.set_var(name, val))
+    }
+}
+
+/// Builds the definition of a user-defined function, which can be rendered as its source.
+fn function_definition(name: &str, func: &Function) -> Option<AST> {
+    match func {
+        Function::UserDefined { arg_names, body } => Some(AST::FunctionDefinition {
+            name: name.to_owned(),
+            arg_names: arg_names.clone(),
+            body: Box::new(body.clone()),
+        }),
+        Function::Builtin { .. } | Function::Overloaded { .. } => None,
+    }
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            ch if ch.is_control() => quoted.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Name of the special form `default(name, fallback)`, which is called like a function.
+const DEFAULT_FORM: &str = "default";
+/// Number of arguments of the special form `default`.
+const DEFAULT_FORM_ARGS: usize = 2;
+
+/// Evaluates the special form `default(name, fallback)`.
+///
+/// Unlike a normal function call, the first argument is not evaluated but must be a variable name.
+/// If the variable is defined, its value is returned. Otherwise `fallback` is evaluated and
+/// returned.
+fn evaluate_default(args_ast: &[AST], ctx: &mut Context) -> Result<Number, EvalError> {
+    match args_ast {
+        [AST::Variable(name), fallback] => match ctx.get_var(name) {
+            Some(value) => Ok(value),
+            None => evaluate(fallback, ctx),
+        },
+        [_, _] => Err(EvalError::InvalidArgument(
+            "default expects a variable name as first argument".to_owned(),
+        )),
+        _ => Err(EvalError::FunctionWrongArgAmount {
+            name: DEFAULT_FORM.to_owned(),
+            expected: DEFAULT_FORM_ARGS,
+            got: args_ast.len(),
+        }),
+    }
+}
+
+/// Evaluates the right hand side of `+` or `-`, whose left hand side has the value `lval`.
+///
+/// A percentage there is relative to the left hand side, so `200 + 10%` is `200 + 20`. In every
+/// other place (including `200 + (10%)`), a percentage is just divided by 100.
+fn evaluate_summand(lval: Number, rhs: &AST, ctx: &mut Context) -> Result<Number, EvalError> {
+    match rhs {
+        AST::Percent(percentage) => Ok(lval * evaluate(percentage, ctx)? / 100.0),
+        _ => evaluate(rhs, ctx),
+    }
+}
+
+/// Checks that `func`, which is called as `name`, takes `got_args` arguments.
+fn check_arg_count(name: &str, func: &Function, got_args: usize) -> Result<(), EvalError> {
+    match func.get_arg_count() {
+        Some(expected_args) if got_args != expected_args => {
+            Err(EvalError::FunctionWrongArgAmount {
+                name: name.to_owned(),
+                expected: expected_args,
+                got: got_args,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks the number of arguments of a call to `func` and evaluates them.
+fn evaluate_args(
+    name: &str,
+    func: &Function,
+    args_ast: &[AST],
+    ctx: &mut Context,
+) -> Result<Vec<Number>, EvalError> {
+    check_arg_count(name, func, args_ast.len())?;
+    args_ast.iter().map(|ast| evaluate(ast, ctx)).collect()
+}
+
+/// Result of evaluating the body of a user-defined function in tail position.
+enum Tail {
+    /// The body was evaluated to a value.
+    Value(Number),
+    /// The body ends with a call to the function itself with these arguments, which has not been
+    /// executed yet.
+    SelfCall(Vec<Number>),
+}
+
+/// Evaluates `ast`, which is in tail position of the body of `func`, i.e. its value becomes the
+/// return value of the current call.
+///
+/// Instead of executing a call to `func` itself in tail position, which would grow the Rust stack,
+/// the evaluated arguments are returned, so the caller can rerun the body in a loop.
+fn evaluate_tail(ast: &AST, func: &Function, ctx: &mut Context) -> Result<Tail, EvalError> {
+    match ast {
+        AST::Lines(lines) => match lines.split_last() {
+            Some((last, init)) if produces_value(last) => {
+                for line in init {
+                    if let Some(tail) = evaluate_tail_statement(line, func, ctx)? {
+                        return Ok(tail);
+                    }
+                }
+                evaluate_tail(last, func, ctx)
+            }
+            _ => Ok(Tail::Value(evaluate_flow(ast, ctx)?.value())),
+        },
+        AST::Brackets(inner) | AST::Return(inner) => evaluate_tail(inner, func, ctx),
+        // An if statement always has the value 0, so only a `return` in its branches can end the
+        // call
+        AST::IfStatement { .. } => {
+            Ok(evaluate_tail_statement(ast, func, ctx)?.unwrap_or(Tail::Value(0.0)))
+        }
+        AST::FunctionCall(name, args_ast) if name != DEFAULT_FORM => {
+            let callee = ctx.get_callee(name, args_ast.len())?;
+            let args = evaluate_args(name, &callee, args_ast, ctx)?;
+            if std::ptr::eq(callee.as_ref(), func) {
+                Ok(Tail::SelfCall(args))
+            } else {
+                Ok(Tail::Value(callee.call(ctx, &args)?))
+            }
+        }
+        _ => Ok(Tail::Value(evaluate_flow(ast, ctx)?.value())),
+    }
+}
+
+/// Decides whether a line of a block produces a value, i.e. whether it can become the value of the
+/// block.
+///
+/// A line that only calls `print` is used for its output and a function definition only defines
+/// the function, so neither of them produces a value. Assignments and declarations produce the
+/// stored value. The value of a block is the value of its last line that produces a value, or 0 if
+/// there is none.
+fn produces_value(line: &AST) -> bool {
+    !matches!(line, AST::FunctionCall(name, _) if name == "print")
+        && !matches!(line, AST::FunctionDefinition { .. })
+}
+
+/// What the evaluation of source code did, as returned by `evaluate_detailed`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalResult {
+    /// An expression (or a statement like `if`) produced the value
+    Value(Number),
+    /// An assignment or declaration stored the value in the variable
+    Assigned(String, Number),
+    /// A function definition defined the function and nothing produced a value
+    Defined(String),
+    /// Nothing produced a value, e.g. for empty source or a call of `print`
+    NoValue,
+}
+
+impl EvalResult {
+    /// Returns the value like `evaluate` does, which is 0 if nothing produced a value.
+    pub fn value(&self) -> Number {
+        match self {
+            EvalResult::Value(value) | EvalResult::Assigned(_, value) => *value,
+            EvalResult::Defined(_) | EvalResult::NoValue => 0.0,
+        }
+    }
+}
+
+/// Like `evaluate`, but describes what the last line that produces a value did instead of only
+/// returning its value. If no line produces a value, the result is the last function that was
+/// defined, if any.
+pub fn evaluate_detailed(ast: &AST, ctx: &mut Context) -> Result<EvalResult, EvalError> {
+    let lines = match ast {
+        AST::Lines(lines) => lines.as_slice(),
+        line => std::slice::from_ref(line),
+    };
+    let mut result = EvalResult::NoValue;
+    for line in lines {
+        let value = evaluate(line, ctx)?;
+        result = match line {
+            AST::Assign(name, _)
+            | AST::ConstDeclaration(name, _)
+            | AST::BoundDeclaration { name, .. } => EvalResult::Assigned(name.clone(), value),
+            AST::FunctionDefinition { name, .. } => match result {
+                EvalResult::Defined(_) | EvalResult::NoValue => EvalResult::Defined(name.clone()),
+                result => result,
+            },
+            line if produces_value(line) => EvalResult::Value(value),
+            _ => result,
+        };
+    }
+    Ok(result)
 }
 
 pub fn evaluate(ast: &AST, ctx: &mut Context) -> Result<Number, EvalError> {
@@ -284,19 +1544,25 @@ pub fn evaluate(ast: &AST, ctx: &mut Context) -> Result<Number, EvalError> {
             ctx.add_function(name, func)?;
             0.0
         }
-        AST::Lines(lines) => {
-            let mut result = 0.0;
-            for line in lines.into_iter() {
-                result = evaluate(line, ctx)?;
+        // Only a function call can handle a `return`, see `Function::call`
+        AST::Lines(_) | AST::IfStatement { .. } | AST::WhileLoop { .. } | AST::Return(_) => {
+            match evaluate_flow(ast, ctx)? {
+                Flow::Normal(value) => value,
+                Flow::Return(_) => return Err(EvalError::ReturnOutsideFunction),
             }
-            result
         }
-        AST::Number(n) => n.parse().map_err(|_| EvalError::Overflow)?,
+        AST::Number(n) => number_value(n)?,
         AST::Variable(name) => ctx
             .get_var(name)
             .ok_or_else(|| EvalError::VariableNotDefined(name.clone()))?,
-        AST::Add(lhs, rhs) => evaluate(lhs, ctx)? + evaluate(rhs, ctx)?,
-        AST::Subtract(lhs, rhs) => evaluate(lhs, ctx)? - evaluate(rhs, ctx)?,
+        AST::Add(lhs, rhs) => {
+            let lval = evaluate(lhs, ctx)?;
+            lval + evaluate_summand(lval, rhs, ctx)?
+        }
+        AST::Subtract(lhs, rhs) => {
+            let lval = evaluate(lhs, ctx)?;
+            lval - evaluate_summand(lval, rhs, ctx)?
+        }
         AST::Multiply(lhs, rhs) => evaluate(lhs, ctx)? * evaluate(rhs, ctx)?,
         AST::Divide(lhs, rhs) => {
             let lval = evaluate(lhs, ctx)?;
@@ -306,56 +1572,109 @@ pub fn evaluate(ast: &AST, ctx: &mut Context) -> Result<Number, EvalError> {
             }
             lval / rval
         }
+        AST::IntDivide(lhs, rhs) => {
+            let lval = evaluate(lhs, ctx)?;
+            let rval = evaluate(rhs, ctx)?;
+            if rval == 0.0 {
+                return Err(EvalError::DivideByZero);
+            }
+            (lval / rval).floor()
+        }
         AST::Modulo(lhs, rhs) => {
             let lval = evaluate(lhs, ctx)?;
             let rval = evaluate(rhs, ctx)?;
             if rval == 0.0 {
                 return Err(EvalError::DivideByZero);
             }
-            lval % rval
+            match ctx.modulo_mode {
+                ModuloMode::Truncated => lval % rval,
+                ModuloMode::Floored => floored_modulo(lval, rval),
+            }
         }
         AST::Power(lhs, rhs) => {
             let lval = evaluate(lhs, ctx)?;
             let rval = evaluate(rhs, ctx)?;
-            lval.powf(rval)
+            // `0 ^ 0` is defined as 1, like in bc. `powf` already does this, but we don't want to
+            // rely on it for something that is part of the language semantics.
+            if lval == 0.0 && rval == 0.0 {
+                1.0
+            } else {
+                lval.powf(rval)
+            }
         }
         AST::UnaryMinus(rhs) => -evaluate(rhs, ctx)?,
+        AST::Factorial(lhs) => factorial(evaluate(lhs, ctx)?)?,
+        AST::Percent(lhs) => evaluate(lhs, ctx)? / 100.0,
+        AST::Not(rhs) => {
+            if is_truthy(evaluate(rhs, ctx)?) {
+                0.0
+            } else {
+                1.0
+            }
+        }
+        AST::Comparison { first, rest } => {
+            let mut lval = evaluate(first, ctx)?;
+            let mut holds = true;
+            for (cmp, rhs) in rest {
+                let rval = evaluate(rhs, ctx)?;
+                if !compare(*cmp, lval, rval) {
+                    // Like `&&`, the rest of the chain doesn't need to be evaluated anymore
+                    holds = false;
+                    break;
+                }
+                lval = rval;
+            }
+            if holds {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        // The result is always 0 or 1, not the value of an operand. Rust's `&&` and `||` already
+        // skip the right hand side.
+        AST::And(lhs, rhs) => {
+            if is_truthy(evaluate(lhs, ctx)?) && is_truthy(evaluate(rhs, ctx)?) {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        AST::Or(lhs, rhs) => {
+            if is_truthy(evaluate(lhs, ctx)?) || is_truthy(evaluate(rhs, ctx)?) {
+                1.0
+            } else {
+                0.0
+            }
+        }
         AST::Brackets(inner) => evaluate(inner, ctx)?,
         AST::Assign(name, rhs) => {
             let rval = evaluate(rhs, ctx)?;
-            ctx.set_var(name, rval);
-            rval
+            ctx.assign_var(name, rval)?
+        }
+        AST::ConstDeclaration(name, value) => {
+            let value = evaluate(value, ctx)?;
+            ctx.define_const(name, value)?;
+            value
+        }
+        AST::BoundDeclaration {
+            name,
+            value,
+            lo,
+            hi,
+        } => {
+            let value = evaluate(value, ctx)?;
+            let lo = evaluate(lo, ctx)?;
+            let hi = evaluate(hi, ctx)?;
+            ctx.declare_bounded_var(name, value, lo, hi)?
+        }
+        AST::FunctionCall(name, args_ast) if name == DEFAULT_FORM => {
+            evaluate_default(args_ast, ctx)?
         }
         AST::FunctionCall(name, args_ast) => {
-            let func = ctx
-                .get_function(name)
-                .ok_or_else(|| EvalError::FunctionNotDefined(name.clone()))?;
-
-            let expected_args = func.get_arg_count();
-            let got_args = args_ast.len();
-            if got_args != expected_args {
-                return Err(EvalError::FunctionWrongArgAmount {
-                    name: name.clone(),
-                    expected: expected_args,
-                    got: got_args,
-                });
-            }
-
-            let args: Vec<_> = args_ast
-                .iter()
-                .map(|ast| evaluate(ast, ctx))
-                .collect::<Result<_, _>>()?;
+            let func = ctx.get_callee(name, args_ast.len())?;
+            let args = evaluate_args(name, &func, args_ast, ctx)?;
             func.call(ctx, &args)?
         }
-        AST::IfStatement { condition, if_body, else_body } => {
-            let condition = evaluate(condition, ctx)?;
-            if condition != 0.0 {
-                evaluate(if_body, ctx)?;
-            } else if let Some(else_body) = else_body {
-                evaluate(else_body, ctx)?;
-            }
-            0.0
-        }
     };
 
     if !result.is_finite() {
@@ -364,3 +1683,115 @@ pub fn evaluate(ast: &AST, ctx: &mut Context) -> Result<Number, EvalError> {
 
     Ok(result)
 }
+
+/// Evaluates a line of the body of `func` that is not in tail position. If the line returns, the
+/// result of the call is returned, where the value of the `return` is in tail position.
+fn evaluate_tail_statement(
+    line: &AST,
+    func: &Function,
+    ctx: &mut Context,
+) -> Result<Option<Tail>, EvalError> {
+    match line {
+        AST::Return(value) => Ok(Some(evaluate_tail(value, func, ctx)?)),
+        AST::Lines(lines) => {
+            for line in lines {
+                if let Some(tail) = evaluate_tail_statement(line, func, ctx)? {
+                    return Ok(Some(tail));
+                }
+            }
+            Ok(None)
+        }
+        AST::IfStatement {
+            condition,
+            if_body,
+            else_body,
+        } => {
+            let condition = evaluate(condition, ctx)?;
+            if is_truthy(condition) {
+                evaluate_tail_statement(if_body, func, ctx)
+            } else if let Some(else_body) = else_body {
+                evaluate_tail_statement(else_body, func, ctx)
+            } else {
+                Ok(None)
+            }
+        }
+        _ => match evaluate_flow(line, ctx)? {
+            Flow::Normal(_) => Ok(None),
+            Flow::Return(value) => Ok(Some(Tail::Value(value))),
+        },
+    }
+}
+
+/// Result of evaluating a statement, which may end the current function call early.
+enum Flow {
+    /// Evaluation continues after the statement, which has this value
+    Normal(Number),
+    /// A `return` statement was evaluated with this value
+    Return(Number),
+}
+
+impl Flow {
+    fn value(self) -> Number {
+        match self {
+            Flow::Normal(value) | Flow::Return(value) => value,
+        }
+    }
+}
+
+/// Evaluates blocks, `if` statements, loops and `return` statements. A `return` stops the
+/// evaluation of the enclosing blocks and loops and is passed up to the caller.
+fn evaluate_flow(ast: &AST, ctx: &mut Context) -> Result<Flow, EvalError> {
+    let value = match ast {
+        AST::Return(value) => return Ok(Flow::Return(evaluate(value, ctx)?)),
+        AST::Lines(lines) => {
+            let mut result = 0.0;
+            for line in lines.iter() {
+                match evaluate_flow(line, ctx)? {
+                    Flow::Normal(value) if produces_value(line) => result = value,
+                    Flow::Normal(_) => (),
+                    flow @ Flow::Return(_) => return Ok(flow),
+                }
+            }
+            result
+        }
+        AST::IfStatement {
+            condition,
+            if_body,
+            else_body,
+        } => {
+            let condition = evaluate(condition, ctx)?;
+            let flow = if is_truthy(condition) {
+                evaluate_flow(if_body, ctx)?
+            } else if let Some(else_body) = else_body {
+                evaluate_flow(else_body, ctx)?
+            } else {
+                Flow::Normal(0.0)
+            };
+            // The value of an if statement is 0, whichever branch ran
+            match flow {
+                Flow::Normal(_) => 0.0,
+                flow @ Flow::Return(_) => return Ok(flow),
+            }
+        }
+        // The value of the loop is the value of the last iteration, or 0 if the body never ran
+        AST::WhileLoop { condition, body } => {
+            let mut result = 0.0;
+            let mut iterations = 0;
+            while is_truthy(evaluate(condition, ctx)?) {
+                if iterations == ctx.max_iterations {
+                    return Err(EvalError::IterationLimit {
+                        limit: ctx.max_iterations,
+                    });
+                }
+                iterations += 1;
+                match evaluate_flow(body, ctx)? {
+                    Flow::Normal(value) => result = value,
+                    flow @ Flow::Return(_) => return Ok(flow),
+                }
+            }
+            result
+        }
+        _ => evaluate(ast, ctx)?,
+    };
+    Ok(Flow::Normal(value))
+}
@@ -0,0 +1,3166 @@
+pub mod csv;
+pub mod error;
+pub mod eval;
+pub mod metrics;
+pub mod optimize;
+pub mod parser;
+mod rng;
+pub mod session;
+pub mod stats;
+pub mod tokenizer;
+mod unparse;
+
+use error::CalcError;
+use eval::{evaluate, evaluate_detailed, Context, EvalResult, Number};
+use optimize::fold_constants;
+use parser::{parse_str, AST};
+use tokenizer::Syntax;
+
+/// Evaluates `s`. Parse errors carry the line and column where parsing `s` failed.
+pub fn eval_str_ctx(s: &str, ctx: &mut Context) -> Result<Number, CalcError> {
+    let ast = parse_str(s, ctx.syntax())?;
+    let result = evaluate(&ast, ctx)?;
+    Ok(result)
+}
+
+/// Like `eval_str_ctx`, but describes what the evaluation did, see `eval::EvalResult`.
+pub fn eval_str_detailed(s: &str, ctx: &mut Context) -> Result<EvalResult, CalcError> {
+    let ast = parse_str(s, ctx.syntax())?;
+    Ok(evaluate_detailed(&ast, ctx)?)
+}
+
+/// Source code that was parsed once and can be evaluated many times, e.g. with different values
+/// of its variables.
+///
+/// Constant subexpressions are folded when compiling (see `optimize::fold_constants`).
+/// Evaluating the compiled source behaves like `eval_str_ctx`, except that the syntax is fixed
+/// when compiling instead of being taken from the context.
+#[derive(Debug, Clone)]
+pub struct CompiledExpr {
+    ast: AST,
+}
+
+impl CompiledExpr {
+    /// Compiles `s` with the default syntax.
+    pub fn compile(s: &str) -> Result<CompiledExpr, CalcError> {
+        Self::compile_with_syntax(s, Syntax::default())
+    }
+
+    pub fn compile_with_syntax(s: &str, syntax: Syntax) -> Result<CompiledExpr, CalcError> {
+        let ast = fold_constants(parse_str(s, syntax)?);
+        Ok(CompiledExpr { ast })
+    }
+
+    pub fn eval(&self, ctx: &mut Context) -> Result<Number, CalcError> {
+        Ok(evaluate(&self.ast, ctx)?)
+    }
+}
+
+/// Evaluates raw bytes, reporting the offset of the first invalid UTF-8 sequence if the bytes are
+/// not valid UTF-8.
+pub fn eval_bytes_ctx(bytes: &[u8], ctx: &mut Context) -> Result<Number, CalcError> {
+    let s = std::str::from_utf8(bytes).map_err(|e| CalcError::InvalidEncoding {
+        offset: e.valid_up_to(),
+    })?;
+    eval_str_ctx(s, ctx)
+}
+
+/// Evaluates the file at `path`. Errors are wrapped in `CalcError::InFile`, so they mention the
+/// path when displayed.
+pub fn eval_file_ctx(path: &str, ctx: &mut Context) -> Result<Number, CalcError> {
+    let in_file = |error| CalcError::InFile {
+        path: path.to_owned(),
+        error: Box::new(error),
+    };
+    let contents = std::fs::read(path).map_err(|e| in_file(e.into()))?;
+    eval_bytes_ctx(&contents, ctx).map_err(in_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{EvalError, TokenizeError};
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+    use std::rc::Rc;
+
+    fn eval_str(s: &str) -> Result<Number, CalcError> {
+        eval_str_ctx(s, &mut Context::new())
+    }
+
+    #[test]
+    fn test_eval_str() {
+        assert!(eval_str("").is_ok());
+        assert!(eval_str("-").is_err());
+        assert!(eval_str("* 2").is_err());
+        assert!(eval_str("2 +").is_err());
+        assert_eq!(eval_str("2").unwrap(), 2.0);
+        assert_eq!(eval_str("2 - 3").unwrap(), -1.0);
+        assert_eq!(eval_str("2-3").unwrap(), -1.0);
+        assert_eq!(eval_str("2 + 2 * 2").unwrap(), 6.0);
+        assert_eq!(eval_str("3 * 2 * 5 + 10 / 5 - 8").unwrap(), 24.0);
+    }
+
+    #[test]
+    fn test_number_parsing() {
+        assert!(eval_str(".1").is_ok());
+        assert!(eval_str("1.1").is_ok());
+        assert!(eval_str("1.").is_ok());
+
+        assert!(eval_str("2.3.4").is_err());
+        assert!(eval_str("..").is_err());
+        assert!(eval_str("..1").is_err());
+        assert!(eval_str("1..").is_err());
+        assert!(eval_str(".1.").is_err());
+
+        assert_eq!(eval_str("1e10").unwrap(), 10_000_000_000.0);
+        assert_eq!(eval_str("2.5e-3").unwrap(), 0.0025);
+        assert_eq!(eval_str("6.022e23").unwrap(), 6.022e23);
+        assert_eq!(eval_str("6.022E+23").unwrap(), 6.022e23);
+        assert_eq!(eval_str(".5e1").unwrap(), 5.0);
+        assert_eq!(eval_str("1.e2").unwrap(), 100.0);
+
+        assert!(eval_str("1e").is_err());
+        assert!(eval_str("1e+").is_err());
+        assert!(eval_str("1e2.3").is_err());
+        assert!(eval_str("1e2e3").is_err());
+        assert!(eval_str("1e 2").is_err());
+    }
+
+    #[test]
+    fn test_digit_separators() {
+        use crate::tokenizer::{parse_number, Token};
+
+        assert_eq!(eval_str("1_000 == 1000").unwrap(), 1.0);
+        assert_eq!(eval_str("1_000_000").unwrap(), 1e6);
+        assert_eq!(eval_str("1.234_567").unwrap(), 1.234567);
+        assert_eq!(eval_str("1_0.5_5e1_0").unwrap(), 10.55e10);
+        assert_eq!(parse_number("12_345").unwrap(), 12345.0);
+        // The separators are not part of the stored literal
+        assert_eq!(
+            tokenize("1_000.0_1").unwrap(),
+            [Token::Number("1000.01".to_owned())]
+        );
+
+        for invalid in ["1_", "1_.0", "1._0", "1__0", "1e_5", "1_e5", "1e5_", "._1"] {
+            assert!(
+                matches!(
+                    tokenize(invalid),
+                    Err(TokenizeError::UnexpectedChar { ch: '_', .. })
+                ),
+                "{}",
+                invalid
+            );
+        }
+        assert!(matches!(
+            tokenize("x = 1__0"),
+            Err(TokenizeError::UnexpectedChar { ch: '_', pos: 5 })
+        ));
+        // A leading underscore makes it a name
+        assert!(matches!(
+            eval_str("_1"),
+            Err(CalcError::EvalError(EvalError::VariableNotDefined(_)))
+        ));
+    }
+
+    #[test]
+    fn test_parse_number() {
+        use crate::tokenizer::parse_number;
+
+        for valid in [".1", "1.", "1.1", "42", "2.5e-3", "1E5"] {
+            assert_eq!(parse_number(valid).unwrap(), eval_str(valid).unwrap());
+        }
+        for invalid in ["2.3.4", "..", "..1", "1..", ".1.", "1e", "1e+"] {
+            assert!(parse_number(invalid).is_err());
+            assert!(eval_str(invalid).is_err());
+        }
+
+        // Things that are fine in an expression, but are not a single number
+        for invalid in ["", "-1", " 1", "1 ", "1a", "pi"] {
+            assert!(parse_number(invalid).is_err());
+        }
+        assert!(matches!(
+            parse_number("1e400"),
+            Err(CalcError::EvalError(EvalError::Overflow))
+        ));
+    }
+
+    #[test]
+    fn test_exponent_sign() {
+        assert_eq!(eval_str("2e-3").unwrap(), 0.002);
+        assert_eq!(eval_str("1e+5").unwrap(), 100000.0);
+        assert_eq!(eval_str("1e-5").unwrap(), 0.00001);
+        assert_eq!(eval_str("2-3").unwrap(), -1.0);
+        assert_eq!(eval_str("2e-3-3").unwrap(), -2.998);
+        assert_eq!(eval_str("2e3-3").unwrap(), 1997.0);
+
+        assert!(matches!(
+            tokenize("2e - 3"),
+            Err(TokenizeError::InvalidNumber(num)) if num == "2e"
+        ));
+        assert!(eval_str("2e-").is_err());
+        assert!(eval_str("2e+-3").is_err());
+    }
+
+    #[test]
+    fn test_unexpected_char() {
+        assert!(matches!(
+            tokenize("2 @ 3"),
+            Err(TokenizeError::UnexpectedChar { ch: '@', pos: 2 })
+        ));
+        assert_eq!(
+            eval_str("2 @ 3").unwrap_err().to_string(),
+            "Tokenize error: Unexpected character '@' at position 2"
+        );
+        // Positions are byte offsets and count the lines before
+        assert!(matches!(
+            tokenize("a = 1\nä = 2"),
+            Err(TokenizeError::UnexpectedChar { ch: 'ä', pos: 6 })
+        ));
+        assert!(matches!(
+            tokenize("ä + €"),
+            Err(TokenizeError::UnexpectedChar { ch: 'ä', pos: 0 })
+        ));
+        assert!(matches!(
+            tokenize("1 + 2 & 3"),
+            Err(TokenizeError::UnexpectedChar { ch: '&', pos: 6 })
+        ));
+        assert!(matches!(
+            tokenize("x + 1.2.3"),
+            Err(TokenizeError::UnexpectedChar { ch: '.', pos: 7 })
+        ));
+        assert!(matches!(
+            tokenize("x + ."),
+            Err(TokenizeError::UnexpectedChar { ch: '.', pos: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_tokenize_with_source() {
+        use crate::tokenizer::{tokenize_with_source, SourcePiece, Syntax, Token};
+
+        let src = "  total = 1_000 * (rate+0x1F)  # yearly\nif (total >= 5) { total }\n";
+        let pieces = tokenize_with_source(src, Syntax::default()).unwrap();
+        let reconstructed: String = pieces.iter().map(SourcePiece::text).collect();
+        assert_eq!(reconstructed, src);
+
+        let tokens: Vec<_> = pieces
+            .iter()
+            .filter_map(|piece| match piece {
+                SourcePiece::Token { token, .. } => Some(token.clone()),
+                SourcePiece::Skipped(_) => None,
+            })
+            .collect();
+        assert_eq!(tokens, tokenize(src).unwrap());
+
+        assert_eq!(
+            pieces[..4],
+            [
+                SourcePiece::Skipped("  "),
+                SourcePiece::Token {
+                    token: Token::Identifier("total".to_owned()),
+                    text: "total"
+                },
+                SourcePiece::Skipped(" "),
+                SourcePiece::Token {
+                    token: Token::Equal,
+                    text: "="
+                },
+            ]
+        );
+        // The text is the original one, even if the token is normalized
+        assert!(pieces.contains(&SourcePiece::Token {
+            token: Token::Number("1000".to_owned()),
+            text: "1_000"
+        }));
+        assert!(pieces.contains(&SourcePiece::Skipped("  # yearly")));
+
+        assert_eq!(tokenize_with_source("", Syntax::default()).unwrap(), []);
+        assert!(tokenize_with_source("1 @ 2", Syntax::default()).is_err());
+    }
+
+    #[test]
+    fn test_hex_literals() {
+        use crate::tokenizer::{parse_number, Token};
+
+        assert_eq!(eval_str("0x1F").unwrap(), 31.0);
+        assert_eq!(eval_str("0Xff + 1").unwrap(), 256.0);
+        assert_eq!(eval_str("-0x10").unwrap(), -16.0);
+        assert_eq!(eval_str("0x0").unwrap(), 0.0);
+        assert_eq!(parse_number("0xA").unwrap(), 10.0);
+
+        // `e` is a hex digit, so there is no exponent in hex literals
+        assert_eq!(
+            tokenize("0x1e5").unwrap(),
+            [Token::Number("0x1e5".to_owned())]
+        );
+        assert_eq!(eval_str("0x1e5").unwrap(), 485.0);
+        assert_eq!(eval_str("1e5").unwrap(), 100000.0);
+        assert_eq!(eval_str("0x1e-5").unwrap(), 25.0);
+
+        assert!(matches!(tokenize("0x"), Err(TokenizeError::InvalidNumber(num)) if num == "0x"));
+        assert!(matches!(
+            tokenize("0x1p3"),
+            Err(TokenizeError::UnexpectedChar { ch: 'p', pos: 3 })
+        ));
+        assert!(matches!(
+            tokenize("0x1.8"),
+            Err(TokenizeError::UnexpectedChar { ch: '.', pos: 3 })
+        ));
+        assert!(matches!(
+            tokenize("0xfg"),
+            Err(TokenizeError::UnexpectedChar { ch: 'g', pos: 3 })
+        ));
+        assert!(eval_str("1x5").is_err());
+        assert!(eval_str("00x5").is_err());
+    }
+
+    #[test]
+    fn test_binary_and_octal_literals() {
+        use crate::tokenizer::parse_number;
+
+        assert_eq!(eval_str("0xFF").unwrap(), 255.0);
+        assert_eq!(eval_str("0b1010").unwrap(), 10.0);
+        assert_eq!(eval_str("0o17").unwrap(), 15.0);
+        assert_eq!(eval_str("0B11 + 0O10").unwrap(), 11.0);
+        assert_eq!(eval_str("0b0").unwrap(), 0.0);
+        assert_eq!(parse_number("0o777").unwrap(), 511.0);
+
+        for empty in ["0b", "0o"] {
+            assert!(
+                matches!(tokenize(empty), Err(TokenizeError::InvalidNumber(num)) if num == empty)
+            );
+        }
+        assert!(matches!(
+            tokenize("0b2"),
+            Err(TokenizeError::UnexpectedChar { ch: '2', pos: 2 })
+        ));
+        assert!(matches!(
+            tokenize("0b102"),
+            Err(TokenizeError::UnexpectedChar { ch: '2', pos: 4 })
+        ));
+        assert!(matches!(
+            tokenize("0o78"),
+            Err(TokenizeError::UnexpectedChar { ch: '8', pos: 3 })
+        ));
+        assert!(eval_str("0b1.1").is_err());
+        assert!(eval_str("0o1e5").is_err());
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        assert_eq!(eval_str("-2").unwrap(), -2.0);
+        assert_eq!(eval_str("2--2").unwrap(), 4.0);
+        assert_eq!(eval_str("2+-2").unwrap(), 0.0);
+        assert_eq!(eval_str("-2+-2").unwrap(), -4.0);
+        assert_eq!(eval_str("2---2").unwrap(), 0.0);
+        assert!(eval_str("2*+-2").is_err());
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        fn num(n: &str) -> Box<AST> {
+            Box::new(AST::Number(n.to_owned()))
+        }
+
+        type Variant = fn(Box<AST>, Box<AST>) -> AST;
+        let binary_ops: [(&str, Variant); 7] = [
+            ("+", AST::Add),
+            ("-", AST::Subtract),
+            ("*", AST::Multiply),
+            ("/", AST::Divide),
+            ("//", AST::IntDivide),
+            ("%", AST::Modulo),
+            ("^", AST::Power),
+        ];
+        for (symbol, variant) in binary_ops {
+            let ast = parse(&tokenize(&format!("1 {} 2", symbol)).unwrap()).unwrap();
+            assert_eq!(
+                format!("{:?}", ast),
+                format!("{:?}", AST::Lines(vec![variant(num("1"), num("2"))])),
+                "{}",
+                symbol
+            );
+            // Unary operators bind stronger than any binary operator
+            let ast = parse(&tokenize(&format!("-1 {} !2", symbol)).unwrap()).unwrap();
+            let expected = AST::Lines(vec![variant(
+                Box::new(AST::UnaryMinus(num("1"))),
+                Box::new(AST::Not(num("2"))),
+            )]);
+            assert_eq!(
+                format!("{:?}", ast),
+                format!("{:?}", expected),
+                "{}",
+                symbol
+            );
+        }
+
+        assert_eq!(eval_str("1 + 2 - 3 * 4 / 2").unwrap(), -3.0);
+        assert_eq!(eval_str("7 // 2 * 2 % 5").unwrap(), 1.0);
+        assert_eq!(eval_str("2 * 3 ^ 2").unwrap(), 18.0);
+        assert_eq!(eval_str("-2 ^ 2").unwrap(), 4.0);
+        assert_eq!(eval_str("2 ^ -1").unwrap(), 0.5);
+        for src in ["+1", "1 +", "1 * / 2", "1 ^^ 2", "//1", "1 % * 2"] {
+            assert!(eval_str(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_factorial() {
+        assert_eq!(eval_str("5!").unwrap(), 120.0);
+        assert_eq!(eval_str("0!").unwrap(), 1.0);
+        assert_eq!(eval_str("1!").unwrap(), 1.0);
+        assert_eq!(eval_str("3!!").unwrap(), 720.0);
+        assert_eq!(eval_str("(2 + 1)! * 2").unwrap(), 12.0);
+        assert_eq!(eval_str("2 * 3!").unwrap(), 12.0);
+        assert_eq!(eval_str("-3!").unwrap(), -6.0);
+        assert_eq!(eval_str("2 ^ 3!").unwrap(), 64.0);
+        // The unary minus binds stronger than `^`, but not than the factorial
+        assert_eq!(eval_str("-2 ^ 3!").unwrap(), 64.0);
+        assert_eq!(eval_str("-(2 ^ 3!)").unwrap(), -64.0);
+        assert_eq!(eval_str("a = 4\na! / a").unwrap(), 6.0);
+        assert_eq!(eval_str("abs(-4)!").unwrap(), 24.0);
+        assert_eq!(eval_str("170!").unwrap(), 7.257415615307994e306);
+        // Prefix `!` is still the logical not, and `!=` the comparison
+        assert_eq!(eval_str("!0!").unwrap(), 0.0);
+        assert_eq!(eval_str("3! != 6").unwrap(), 0.0);
+
+        for invalid in ["(-1)!", "2.5!", "(0 - 3)!"] {
+            assert!(matches!(
+                eval_str(invalid).unwrap_err().eval_error(),
+                Some(EvalError::InvalidArgument(_))
+            ));
+        }
+        assert!(matches!(
+            eval_str("171!").unwrap_err().eval_error(),
+            Some(EvalError::Overflow)
+        ));
+        assert!(eval_str("!").is_err());
+
+        for (src, rendered) in [
+            ("(-3)!", "(-3)!"),
+            ("-3!", "-3!"),
+            ("(2 ^ 3)!", "(2 ^ 3)!"),
+            ("2 ^ 3!!", "2 ^ 3!!"),
+        ] {
+            let ast = parse(&tokenize(src).unwrap()).unwrap();
+            assert_eq!(ast.to_string(), rendered);
+        }
+    }
+
+    #[test]
+    fn test_brackets() {
+        assert_eq!(eval_str("4 * (5 - 1)").unwrap(), 16.0);
+        assert_eq!(eval_str("(2 + 2) * (3 + 3)").unwrap(), 24.0);
+        assert_eq!(eval_str("(2 + 2)").unwrap(), 4.0);
+        assert_eq!(eval_str("-(2 + 2)").unwrap(), -4.0);
+        assert_eq!(eval_str("-((2 + 3) * 4)").unwrap(), -20.0);
+        assert_eq!(eval_str("-((2 + -4) * 5) / 2").unwrap(), 5.0);
+        assert_eq!(eval_str("(1 + 2) + 3").unwrap(), 6.0);
+        assert_eq!(eval_str("1 + (2) * 3").unwrap(), 7.0);
+        assert_eq!(eval_str("1 + abs(2) * 3").unwrap(), 7.0);
+        assert_eq!(eval_str("1 + 2 * 3 * 4").unwrap(), 25.0);
+        assert_eq!(eval_str("2 * abs(3) ^ 2").unwrap(), 18.0);
+        assert!(eval_str("-2 + 2)").is_err());
+        assert!(eval_str("-(2 + 2").is_err());
+        assert!(eval_str("()").is_err());
+    }
+
+    #[test]
+    fn test_implicit_multiplication() {
+        use std::f64::consts;
+
+        let eps = 1e-10;
+        assert!((eval_str("2pi").unwrap() - consts::TAU).abs() < eps);
+        assert_eq!(eval_str("3(4+5)").unwrap(), 27.0);
+        assert_eq!(eval_str("(1+1)(2+2)").unwrap(), 8.0);
+        assert_eq!(eval_str("x = 3\n2x").unwrap(), 6.0);
+        assert_eq!(eval_str("x = 3\n2 x + 1").unwrap(), 7.0);
+        assert_eq!(eval_str("2(3)(4)").unwrap(), 24.0);
+        assert_eq!(eval_str("2abs(-3)").unwrap(), 6.0);
+        assert_eq!(eval_str("sin(0)(1)").unwrap(), 0.0);
+        assert_eq!(eval_str("abs(-2)(3 + 1)").unwrap(), 8.0);
+        assert_eq!(eval_str("max(1, 2)(3)(4)").unwrap(), 24.0);
+        // An `e` followed by a letter starts a name instead of an exponent
+        assert_eq!(eval_str("2exp(1)").unwrap(), 2.0 * consts::E);
+        assert_eq!(eval_str("eps = 0.5\n3eps").unwrap(), 1.5);
+        assert_eq!(eval_str("E1 = 3\n2E1").unwrap(), 20.0);
+        assert_eq!(eval_str("x = 3\n2e1x").unwrap(), 60.0);
+        assert!(eval_str("2e").is_err());
+        assert_eq!(eval_str("-2(3)").unwrap(), -6.0);
+        // The factor binds like the right hand side of `^`
+        assert_eq!(eval_str("x = 3\n2x^2").unwrap(), 18.0);
+        assert_eq!(eval_str("x = 4\n1 / 2x").unwrap(), 0.125);
+        assert_eq!(eval_str("x = 3\n2x!").unwrap(), 12.0);
+        assert_eq!(eval_str("2^2(3)").unwrap(), 64.0);
+        assert_eq!(eval_str("bound x = 2 in [0, 1]\nx").unwrap(), 1.0);
+
+        // Only numbers and brackets are multiplied implicitly
+        for invalid in [
+            "x = 2\nx x",
+            "x = 2\n(x)x",
+            "2 3",
+            "2!(3)",
+            "(2)3",
+            "2 -(3)x",
+        ] {
+            assert!(eval_str(invalid).is_err(), "{}", invalid);
+        }
+
+        let ast = parse(&tokenize("2pi^2 + 3(1 + 2)").unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "2 * pi ^ 2 + 3 * (1 + 2)");
+    }
+
+    #[test]
+    fn test_power() {
+        assert!(eval_str("4 ^").is_err());
+        assert!(eval_str("^ 3").is_err());
+        assert_eq!(eval_str("1 ^ -3").unwrap(), 1.0);
+        assert_eq!(eval_str("(-1) ^ -3").unwrap(), -1.0);
+        assert_eq!(eval_str("(-1) ^ -4").unwrap(), 1.0);
+        assert_eq!(eval_str("2 ^ -3").unwrap(), 0.125);
+        assert_eq!(eval_str("2 ^ 0").unwrap(), 1.0);
+        assert_eq!(eval_str("0 ^ 0").unwrap(), 1.0);
+        assert_eq!(eval_str("(-0) ^ 0").unwrap(), 1.0);
+        assert_eq!(eval_str("0 ^ 2").unwrap(), 0.0);
+        assert_eq!(eval_str("3 ^ 5").unwrap(), 243.0);
+        assert_eq!(eval_str("-1 ^ 4").unwrap(), 1.0);
+        assert_eq!(eval_str("-1 ^ 5").unwrap(), -1.0);
+        assert_eq!(eval_str("-1 ^ -5").unwrap(), -1.0);
+        assert_eq!(eval_str("(1 + 1) ^ (4 * 2)").unwrap(), 256.0);
+
+        // `^` is right-associative
+        assert_eq!(eval_str("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(eval_str("(2 ^ 3) ^ 2").unwrap(), 64.0);
+        assert_eq!(eval_str("2 ^ 2 ^ 3").unwrap(), 256.0);
+        assert_eq!(eval_str("2 ^ 2 ^ 3 + 1").unwrap(), 257.0);
+        assert_eq!(eval_str("1 + 2 ^ 2 ^ 3 * 2").unwrap(), 513.0);
+        assert_eq!(eval_str("2 ^ -1 ^ 2").unwrap(), 2.0);
+        assert_eq!(eval_str("2 ^ 1 ^ 2 ^ 3").unwrap(), 2.0);
+        assert_eq!(eval_str("-2 ^ 2 ^ 3").unwrap(), 256.0);
+    }
+
+    #[test]
+    fn test_mod() {
+        assert!(eval_str("2 %").is_err());
+        assert!(eval_str("% 3").is_err());
+        assert!(eval_str("100 % 0").is_err());
+        assert_eq!(eval_str("7 % 3").unwrap(), 1.0);
+        assert_eq!(eval_str("7 % -3").unwrap(), 1.0);
+        assert_eq!(eval_str("-7 % 3").unwrap(), -1.0);
+        assert_eq!(eval_str("-9 % -3").unwrap(), 0.0);
+        assert_eq!(eval_str("42 % 1337").unwrap(), 42.0);
+        assert_eq!(eval_str("2 + 3 * 4 % 5").unwrap(), 4.0);
+    }
+
+    #[test]
+    fn test_int_divide() {
+        assert_eq!(eval_str("7 // 2").unwrap(), 3.0);
+        assert_eq!(eval_str("-7 // 2").unwrap(), -4.0);
+        assert_eq!(eval_str("7 // -2").unwrap(), -4.0);
+        assert_eq!(eval_str("-7 // -2").unwrap(), 3.0);
+        assert_eq!(eval_str("6 // 3").unwrap(), 2.0);
+        assert_eq!(eval_str("-6 // 3").unwrap(), -2.0);
+        assert_eq!(eval_str("7.5 // 2").unwrap(), 3.0);
+        assert_eq!(eval_str("1 // 3").unwrap(), 0.0);
+        // Same precedence as `*` and `/`, left-associative
+        assert_eq!(eval_str("1 + 7 // 2 * 2").unwrap(), 7.0);
+        assert_eq!(eval_str("100 // 10 // 3").unwrap(), 3.0);
+        assert_eq!(eval_str("2 ^ 3 // 3").unwrap(), 2.0);
+        // Floor division and the floored modulo fit together
+        let mut ctx = Context::new();
+        ctx.set_modulo_mode(crate::eval::ModuloMode::Floored);
+        for (a, b) in [(7, 2), (-7, 2), (7, -2), (-7, -3)] {
+            let src = format!("({a}) // ({b}) * ({b}) + ({a}) % ({b})");
+            assert_eq!(eval_str_ctx(&src, &mut ctx).unwrap(), a as Number);
+        }
+
+        assert!(eval_str("1 // 0").unwrap_err().is_division_by_zero());
+        assert!(eval_str("0 // 0").unwrap_err().is_division_by_zero());
+        assert!(eval_str("7 //").is_err());
+        assert!(eval_str("7 / / 2").is_err());
+
+        let ast = parse(&tokenize("(1 + 7) // 2//3").unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "(1 + 7) // 2 // 3");
+    }
+
+    #[test]
+    fn test_mod_keyword() {
+        use crate::{error::ParseError, tokenizer::Keyword};
+
+        for (a, b) in [("7", "3"), ("-7", "3"), ("7.5", "-2"), ("2 + 3 * 4", "5")] {
+            assert_eq!(
+                eval_str(&format!("{} mod {}", a, b)).unwrap(),
+                eval_str(&format!("{} % {}", a, b)).unwrap()
+            );
+        }
+        assert_eq!(eval_str("10 mod 4 % 3 mod 2").unwrap(), 0.0);
+        assert!(eval_str("5 mod 0").is_err());
+        assert!(eval_str("mod 3").is_err());
+        assert!(eval_str("3 mod").is_err());
+
+        // `mod` is reserved
+        for code in ["mod = 3", "mod + 1", "fn mod(x) { x }", "fn f(mod) { mod }"] {
+            assert!(matches!(
+                eval_str(code),
+                Err(CalcError::ParseError(
+                    ParseError::KeywordAsName(Keyword::Mod),
+                    _
+                ))
+            ));
+        }
+        assert_eq!(
+            eval_str("mod = 3").unwrap_err().to_string(),
+            "line 1, column 1: Parse error: 'mod' is a reserved keyword and can't be used as a name"
+        );
+        assert_eq!(eval_str("modulo = 3\nmodulo mod 2").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_mod_function() {
+        use crate::eval::ModuloMode;
+
+        // `%` truncates, while `mod` takes the sign of the divisor
+        assert_eq!(eval_str("-7 % 3").unwrap(), -1.0);
+        assert_eq!(eval_str("mod(-7, 3)").unwrap(), 2.0);
+        assert_eq!(eval_str("7 % -3").unwrap(), 1.0);
+        assert_eq!(eval_str("mod(7, -3)").unwrap(), -2.0);
+        assert_eq!(eval_str("mod(-7, -3)").unwrap(), -1.0);
+        assert_eq!(eval_str("mod(7, 3)").unwrap(), 1.0);
+        assert_eq!(eval_str("mod(-6, 3)").unwrap(), 0.0);
+        assert_eq!(eval_str("mod(-0.5, 2)").unwrap(), 1.5);
+        assert!(eval_str("mod(1, 0)").unwrap_err().is_division_by_zero());
+        assert!(eval_str("mod(1)").is_err());
+        assert!(eval_str("mod(1, 2, 3)").is_err());
+
+        // The function and the operator can be mixed
+        assert_eq!(eval_str("mod(-7, 3) mod 2").unwrap(), 0.0);
+        assert_eq!(eval_str("10 mod mod(-1, 4)").unwrap(), 1.0);
+        assert_eq!(eval_str("2 * mod(-1, 4)").unwrap(), 6.0);
+
+        // The modulo mode only affects the operator
+        let mut ctx = Context::new();
+        ctx.set_modulo_mode(ModuloMode::Floored);
+        assert_eq!(eval_str_ctx("-7 % 3", &mut ctx).unwrap(), 2.0);
+        assert_eq!(eval_str_ctx("mod(-7, 3)", &mut ctx).unwrap(), 2.0);
+
+        let ast = parse(&tokenize("mod(-7, 3) % 2").unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "mod(-7, 3) % 2");
+    }
+
+    #[test]
+    fn test_modulo_modes() {
+        use crate::eval::ModuloMode;
+
+        let mut ctx = Context::new();
+        assert_eq!(ctx.modulo_mode(), ModuloMode::Truncated);
+
+        ctx.set_modulo_mode(ModuloMode::Floored);
+        assert_eq!(eval_str_ctx("7 % 3", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("7 % -3", &mut ctx).unwrap(), -2.0);
+        assert_eq!(eval_str_ctx("-7 % 3", &mut ctx).unwrap(), 2.0);
+        assert_eq!(eval_str_ctx("-7 % -3", &mut ctx).unwrap(), -1.0);
+        assert_eq!(eval_str_ctx("-9 % -3", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("-9 % 3", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("-5.5 % 2", &mut ctx).unwrap(), 0.5);
+        assert!(eval_str_ctx("-7 % 0", &mut ctx).is_err());
+
+        ctx.set_modulo_mode(ModuloMode::Truncated);
+        assert_eq!(eval_str_ctx("7 % -3", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("-7 % 3", &mut ctx).unwrap(), -1.0);
+        assert_eq!(eval_str_ctx("-7 % -3", &mut ctx).unwrap(), -1.0);
+    }
+
+    #[test]
+    fn test_percentages() {
+        use crate::tokenizer::tokenize_with_syntax;
+
+        // `%` is always the modulo operator by default
+        assert!(eval_str("50%").is_err());
+        assert!(eval_str("200 + 10%").is_err());
+        assert_eq!(eval_str("7 % 3").unwrap(), 1.0);
+
+        let mut ctx = Context::new();
+        ctx.set_syntax(Syntax {
+            percentages: true,
+            ..Syntax::default()
+        });
+        assert_eq!(eval_str_ctx("50%", &mut ctx).unwrap(), 0.5);
+        assert_eq!(eval_str_ctx("200 + 10%", &mut ctx).unwrap(), 220.0);
+        assert_eq!(eval_str_ctx("200 - 10%", &mut ctx).unwrap(), 180.0);
+        assert_eq!(eval_str_ctx("200 + 10% + 10%", &mut ctx).unwrap(), 242.0);
+        assert_eq!(eval_str_ctx("200 * 10%", &mut ctx).unwrap(), 20.0);
+        assert_eq!(eval_str_ctx("200 + (10%)", &mut ctx).unwrap(), 200.1);
+        assert_eq!(eval_str_ctx("200 + 2 * 10%", &mut ctx).unwrap(), 200.2);
+        assert_eq!(
+            eval_str_ctx(
+                "x = 50
+x += 10%
+x",
+                &mut ctx
+            )
+            .unwrap(),
+            55.0
+        );
+        assert_eq!(eval_str_ctx("-50%", &mut ctx).unwrap(), -0.5);
+        assert_eq!(eval_str_ctx("(1 + 1)%", &mut ctx).unwrap(), 0.02);
+        assert_eq!(eval_str_ctx("max(20%, 30%)", &mut ctx).unwrap(), 0.3);
+        assert_eq!(eval_str_ctx("50% - 1", &mut ctx).unwrap(), -0.5);
+        assert_eq!(eval_str_ctx("50%%", &mut ctx).unwrap(), 0.005);
+        assert!(eval_str_ctx("% 3", &mut ctx).is_err());
+
+        // With an operand after it, `%` is still the modulo operator
+        assert_eq!(eval_str_ctx("7 % 3", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("7 % (1 + 2)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(
+            eval_str_ctx(
+                "x = 3
+7 % x",
+                &mut ctx
+            )
+            .unwrap(),
+            1.0
+        );
+        assert_eq!(eval_str_ctx("7 mod 3", &mut ctx).unwrap(), 1.0);
+        assert!(eval_str_ctx("100 % 0", &mut ctx).is_err());
+
+        let ast = parse(&tokenize_with_syntax("200 + 10%", ctx.syntax()).unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "200 + 10%");
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut ctx = Context::new();
+        assert_eq!(eval_str_ctx("a = 2", &mut ctx).unwrap(), 2.0);
+        assert_eq!(eval_str_ctx("b = a + 1", &mut ctx).unwrap(), 3.0);
+        assert_eq!(eval_str_ctx("c = a + b", &mut ctx).unwrap(), 5.0);
+        assert_eq!(ctx.get_var("a"), Some(2.0));
+        assert_eq!(ctx.get_var("b"), Some(3.0));
+        assert_eq!(ctx.get_var("c"), Some(5.0));
+
+        assert!(eval_str("not_defined").is_err());
+
+        let mut ctx = Context::new();
+        assert_eq!(eval_str_ctx("some_longer_name = 2", &mut ctx).unwrap(), 2.0);
+        assert_eq!(ctx.get_var("some_longer_name"), Some(2.0));
+
+        let mut ctx = Context::new();
+        eval_str_ctx("defined_var = 7", &mut ctx).unwrap();
+        assert_eq!(
+            eval_str_ctx("default(undefined_var, 42)", &mut ctx).unwrap(),
+            42.0
+        );
+        assert_eq!(
+            eval_str_ctx("default(defined_var, 42)", &mut ctx).unwrap(),
+            7.0
+        );
+        assert_eq!(
+            eval_str_ctx("default(undefined_var, defined_var * 2)", &mut ctx).unwrap(),
+            14.0
+        );
+        // The fallback is only evaluated if it is needed
+        assert_eq!(
+            eval_str_ctx("default(defined_var, not_defined)", &mut ctx).unwrap(),
+            7.0
+        );
+        assert!(eval_str_ctx("default(undefined_var, not_defined)", &mut ctx).is_err());
+        assert!(eval_str_ctx("default(1 + 1, 42)", &mut ctx).is_err());
+        assert!(eval_str_ctx("default(undefined_var)", &mut ctx).is_err());
+
+        // The name is reserved, so it can't be shadowed by a user function
+        assert!(matches!(
+            eval_str_ctx("fn default(a, b) { a * b }", &mut ctx),
+            Err(CalcError::EvalError(EvalError::FunctionAlreadyDefined(_)))
+        ));
+        assert!(matches!(
+            eval_str_ctx(
+                "fn f() { fn default(a, b) { a * b }\nreturn 0 }\nf()",
+                &mut ctx
+            ),
+            Err(CalcError::EvalError(EvalError::FunctionAlreadyDefined(_)))
+        ));
+        assert_eq!(
+            eval_str_ctx("default(undefined_var, 42)", &mut ctx).unwrap(),
+            42.0
+        );
+
+        assert!(eval_str("a b = 2").is_err());
+        assert!(eval_str("2 = 2").is_err());
+        assert!(eval_str("* = 2").is_err());
+        assert!(eval_str("() = 2").is_err());
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let mut ctx = Context::new();
+        eval_str_ctx("x = 1", &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("x += 4", &mut ctx).unwrap(), 5.0);
+        assert_eq!(ctx.get_var("x"), Some(5.0));
+        assert_eq!(eval_str_ctx("x -= 1", &mut ctx).unwrap(), 4.0);
+        assert_eq!(eval_str_ctx("x *= 2 + 1", &mut ctx).unwrap(), 12.0);
+        assert_eq!(eval_str_ctx("x /= 8", &mut ctx).unwrap(), 1.5);
+        assert_eq!(eval_str_ctx("x-=-1.5", &mut ctx).unwrap(), 3.0);
+        assert_eq!(ctx.get_var("x"), Some(3.0));
+        assert!(eval_str_ctx("x /= 0", &mut ctx)
+            .unwrap_err()
+            .is_division_by_zero());
+
+        assert_eq!(
+            eval_str_ctx(
+                "sum = 0\ni = 1\nwhile (i <= 4) { sum += i\ni += 1 }\nsum",
+                &mut ctx
+            )
+            .unwrap(),
+            10.0
+        );
+
+        // The variable has to exist already, and constants can't be changed either way
+        let err = eval_str_ctx("y += 1", &mut ctx).unwrap_err();
+        assert_eq!(err.undefined_variable(), Some("y"));
+        assert_eq!(ctx.get_var("y"), None);
+        assert!(eval_str_ctx("const c = 1\nc += 1", &mut ctx).is_err());
+        assert!(eval_str("if += 1").unwrap_err().parse_error().is_some());
+
+        for invalid in ["x +=", "+= 1", "2 += 1", "x + = 1", "x += += 1", "(x) += 1"] {
+            assert!(eval_str_ctx(invalid, &mut ctx).is_err(), "{}", invalid);
+        }
+
+        let ast = parse(&tokenize("x *= 2 + 1").unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "x = x * (2 + 1)");
+    }
+
+    #[test]
+    fn test_builtin_functions() {
+        use std::f64::consts;
+
+        let eps = 1e-10;
+        assert!((eval_str("sin(pi/2)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("cos(pi/2)").unwrap() - 0.0).abs() < eps);
+        assert!((eval_str("tan(pi/4)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("asin(1)").unwrap() - consts::FRAC_PI_2).abs() < eps);
+        assert!((eval_str("acos(1)").unwrap() - 0.0).abs() < eps);
+        assert!((eval_str("atan(1)").unwrap() - consts::FRAC_PI_4).abs() < eps);
+        assert!((eval_str("atan2(1, 1)").unwrap() - consts::FRAC_PI_4).abs() < eps);
+        assert!((eval_str("atan2(1, -1)").unwrap() - 3.0 * consts::FRAC_PI_4).abs() < eps);
+        assert!((eval_str("atan2(-1, -1)").unwrap() + 3.0 * consts::FRAC_PI_4).abs() < eps);
+        assert!((eval_str("atan2(0, -1)").unwrap() - consts::PI).abs() < eps);
+        assert!((eval_str("atan2(1, 0)").unwrap() - consts::FRAC_PI_2).abs() < eps);
+        assert!(eval_str("atan2(1)").unwrap_err().is_wrong_arg_amount());
+        assert!((eval_str("sinh(1)").unwrap() - 1_f64.sinh()).abs() < eps);
+        assert!((eval_str("cosh(1)").unwrap() - 1_f64.cosh()).abs() < eps);
+        assert!((eval_str("tanh(1)").unwrap() - 1_f64.tanh()).abs() < eps);
+        assert_eq!(eval_str("hypot(3, 4)").unwrap(), 5.0);
+        assert_eq!(eval_str("hypot(-3, 4)").unwrap(), 5.0);
+        assert_eq!(eval_str("hypot(0, 0)").unwrap(), 0.0);
+        assert!((eval_str("hypot(1e200, 1e200)").unwrap() / 1e200 - consts::SQRT_2).abs() < eps);
+        assert!(eval_str("sqrt(1e200^2 + 1e200^2)").is_err());
+        assert!((eval_str("sind(90)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("sind(-30)").unwrap() + 0.5).abs() < eps);
+        assert!((eval_str("sind(36000090)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("cosd(60)").unwrap() - 0.5).abs() < eps);
+        assert!((eval_str("cosd(90)").unwrap() - 0.0).abs() < eps);
+        assert!((eval_str("tand(45)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("tand(-45)").unwrap() + 1.0).abs() < eps);
+        assert!((eval_str("asind(1)").unwrap() - 90.0).abs() < eps);
+        assert!((eval_str("acosd(0.5)").unwrap() - 60.0).abs() < eps);
+        assert!((eval_str("atand(1)").unwrap() - 45.0).abs() < eps);
+        assert!((eval_str("asind(sind(30))").unwrap() - 30.0).abs() < eps);
+        assert!(eval_str("asind(2)").is_err());
+        assert!((eval_str("deg(pi)").unwrap() - 180.0).abs() < eps);
+        assert!((eval_str("deg(-pi / 2)").unwrap() + 90.0).abs() < eps);
+        assert!((eval_str("deg(1)").unwrap() - 57.29577951308232).abs() < eps);
+        assert!((eval_str("rad(180)").unwrap() - consts::PI).abs() < eps);
+        assert!((eval_str("rad(720)").unwrap() - 4.0 * consts::PI).abs() < eps);
+        assert!((eval_str("rad(-45)").unwrap() + consts::FRAC_PI_4).abs() < eps);
+        assert!((eval_str("deg(rad(123.4))").unwrap() - 123.4).abs() < eps);
+        assert!((eval_str("sin(rad(30))").unwrap() - eval_str("sind(30)").unwrap()).abs() < eps);
+        assert!((eval_str("deg(atan(1))").unwrap() - eval_str("atand(1)").unwrap()).abs() < eps);
+
+        assert!((eval_str("ln(e)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("log2(1024)").unwrap() - 10.0).abs() < eps);
+        assert!((eval_str("log10(1000)").unwrap() - 3.0).abs() < eps);
+        assert!((eval_str("log(27, 3)").unwrap() - 3.0).abs() < eps);
+        assert!((eval_str("log(1000)").unwrap() - 3.0).abs() < eps);
+        assert!((eval_str("log(8, 2)").unwrap() - 3.0).abs() < eps);
+        assert!((eval_str("atan(1, -1)").unwrap() - 3.0 * consts::FRAC_PI_4).abs() < eps);
+        assert!(matches!(
+            eval_str("log(1, 2, 3)"),
+            Err(CalcError::EvalError(EvalError::NoMatchingOverload { name, arg_counts, got: 3 }))
+                if name == "log" && arg_counts == [1, 2]
+        ));
+        // Calling the overloaded function directly reports the same error
+        let mut ctx = Context::new();
+        let log = ctx.get_function("log").unwrap();
+        assert_eq!(log.call(&mut ctx, &[8.0, 2.0]).unwrap(), 3.0);
+        assert!(matches!(
+            log.call(&mut ctx, &[1.0, 2.0, 3.0]),
+            Err(EvalError::NoMatchingOverload { name, arg_counts, got: 3 })
+                if name == "log" && arg_counts == [1, 2]
+        ));
+        assert_eq!(
+            eval_str("log()").unwrap_err().to_string(),
+            "Eval error: Function 'log' was called with 0 arguments but expects 1 or 2"
+        );
+
+        assert!((eval_str("abs(-1)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("abs(1)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("min(1, 5)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("max(1, 5)").unwrap() - 5.0).abs() < eps);
+        for (a, b) in [("1", "5"), ("-0", "0"), ("-2.5", "-2.5"), ("sqrt(-1)", "3")] {
+            let min = |a, b| eval_str(&format!("atan2(min({}, {}), -1)", a, b));
+            let max = |a, b| eval_str(&format!("atan2(max({}, {}), -1)", a, b));
+            assert_eq!(min(a, b).ok(), min(b, a).ok());
+            assert_eq!(max(a, b).ok(), max(b, a).ok());
+        }
+        assert_eq!(eval_str("atan2(min(0, -0), -1)").unwrap(), -consts::PI);
+        assert_eq!(eval_str("atan2(max(-0, 0), -1)").unwrap(), consts::PI);
+        assert!(eval_str("min(sqrt(-1), 3)").is_err());
+        assert!(eval_str("max(3, sqrt(-1))").is_err());
+        assert_eq!(eval_str("clamp(5, 0, 10)").unwrap(), 5.0);
+        assert_eq!(eval_str("clamp(-5, 0, 10)").unwrap(), 0.0);
+        assert_eq!(eval_str("clamp(15, 0, 10)").unwrap(), 10.0);
+        assert_eq!(eval_str("clamp(15, 3, 3)").unwrap(), 3.0);
+        assert!(matches!(
+            eval_str("clamp(5, 10, 0)"),
+            Err(CalcError::EvalError(EvalError::InvalidArgument(_)))
+        ));
+        assert!(eval_str("clamp(5, sqrt(-1), 10)").is_err());
+        assert_eq!(eval_str("sign(-3)").unwrap(), -1.0);
+        assert_eq!(eval_str("sign(0)").unwrap(), 0.0);
+        assert_eq!(eval_str("sign(-0)").unwrap(), 0.0);
+        assert!(eval_str("sign(-0)").unwrap().is_sign_positive());
+        assert_eq!(eval_str("sign(42)").unwrap(), 1.0);
+        assert_eq!(eval_str("sign(-0.001)").unwrap(), -1.0);
+        assert_eq!(eval_str("sign(1e300)").unwrap(), 1.0);
+        let mut ctx = Context::new();
+        ctx.set_var("nan", Number::NAN);
+        assert!(matches!(
+            eval_str_ctx("sign(nan)", &mut ctx)
+                .unwrap_err()
+                .eval_error(),
+            Some(EvalError::Overflow)
+        ));
+        assert_eq!(eval_str("clamp(-1, 0, 10)").unwrap(), 0.0);
+        assert_eq!(eval_str("clamp(99, 0, 10)").unwrap(), 10.0);
+        assert!(eval_str("clamp(1, 10, 0)").is_err());
+        assert!(eval_str("clamp(1, 2)").unwrap_err().is_wrong_arg_amount());
+        assert!(eval_str("clamp(1, 2, 3, 4)")
+            .unwrap_err()
+            .is_wrong_arg_amount());
+        assert!((eval_str("floor(1.5)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("ceil(1.5)").unwrap() - 2.0).abs() < eps);
+        assert!((eval_str("round(1.5)").unwrap() - 2.0).abs() < eps);
+        assert!((eval_str("round(1.4)").unwrap() - 1.0).abs() < eps);
+        assert!((eval_str("round(1.6)").unwrap() - 2.0).abs() < eps);
+        assert_eq!(eval_str("round_to_multiple(7, 5)").unwrap(), 5.0);
+        assert_eq!(eval_str("round_to_multiple(8, 5)").unwrap(), 10.0);
+        assert_eq!(eval_str("round_to_multiple(7.5, 5)").unwrap(), 10.0);
+        assert_eq!(eval_str("round_to_multiple(-7, 5)").unwrap(), -5.0);
+        assert_eq!(eval_str("round_to_multiple(-8, 5)").unwrap(), -10.0);
+        assert_eq!(eval_str("round_to_multiple(8, -5)").unwrap(), 10.0);
+        assert_eq!(eval_str("round_to_multiple(0, 3)").unwrap(), 0.0);
+        assert!((eval_str("round_to_multiple(0.27, 0.05)").unwrap() - 0.25).abs() < eps);
+        assert!((eval_str("round_to_multiple(0.28, 0.05)").unwrap() - 0.3).abs() < eps);
+        assert!(matches!(
+            eval_str("round_to_multiple(7, 0)"),
+            Err(CalcError::EvalError(EvalError::InvalidArgument(_)))
+        ));
+
+        assert!((eval_str("norm_angle(pi)").unwrap() - consts::PI).abs() < eps);
+        assert!((eval_str("norm_angle(-pi)").unwrap() - consts::PI).abs() < eps);
+        assert!((eval_str("norm_angle(3*pi)").unwrap() - consts::PI).abs() < eps);
+        assert!((eval_str("norm_angle(pi/2 - 4*pi)").unwrap() - consts::FRAC_PI_2).abs() < eps);
+        assert!(eval_str("norm_angle(2*pi * 1000000)").unwrap().abs() < 1e-6);
+
+        assert_eq!(eval_str("product()").unwrap(), 1.0);
+        assert_eq!(eval_str("product(7)").unwrap(), 7.0);
+        assert_eq!(eval_str("product(2, 3, 4)").unwrap(), 24.0);
+        assert_eq!(eval_str("product(2, -0.5, 1 + 1)").unwrap(), -2.0);
+
+        assert_eq!(eval_str("sum()").unwrap(), 0.0);
+        assert_eq!(eval_str("sum(1, 2, 3)").unwrap(), 6.0);
+        assert_eq!(eval_str("sum(-1.5)").unwrap(), -1.5);
+        assert_eq!(eval_str("mean(1, 2, 3, 6)").unwrap(), 3.0);
+        assert_eq!(eval_str("mean(-4)").unwrap(), -4.0);
+        assert!(matches!(
+            eval_str("mean()"),
+            Err(CalcError::EvalError(EvalError::InvalidArgument(_)))
+        ));
+        assert_eq!(eval_str("min(4, -2, 7, 0)").unwrap(), -2.0);
+        assert_eq!(eval_str("max(4, -2, 7, 0)").unwrap(), 7.0);
+        assert_eq!(eval_str("min(3)").unwrap(), 3.0);
+        assert_eq!(eval_str("max(-3)").unwrap(), -3.0);
+        assert_eq!(eval_str("atan2(min(1, 0, -0), -1)").unwrap(), -consts::PI);
+        assert_eq!(eval_str("atan2(max(-0, -1, 0), -1)").unwrap(), consts::PI);
+        for empty in ["min()", "max()"] {
+            assert!(matches!(
+                eval_str(empty),
+                Err(CalcError::EvalError(EvalError::InvalidArgument(_)))
+            ));
+        }
+
+        assert!(eval_str("sqrt(-1)").is_err());
+        assert!((eval_str("sqrt(4)").unwrap() - 2.0).abs() < eps);
+        assert_eq!(eval_str("cbrt(27)").unwrap(), 3.0);
+        assert_eq!(eval_str("cbrt(-8)").unwrap(), -2.0);
+        assert_eq!(eval_str("cbrt(0)").unwrap(), 0.0);
+        assert_eq!(eval_str("nroot(16, 4)").unwrap(), 2.0);
+        assert_eq!(eval_str("nroot(27, 3)").unwrap(), 3.0);
+        assert_eq!(eval_str("nroot(-32, 5)").unwrap(), -2.0);
+        assert_eq!(eval_str("nroot(1e6, 6)").unwrap(), 10.0);
+        assert_eq!(eval_str("nroot(0.25, -2)").unwrap(), 2.0);
+        assert_eq!(
+            eval_str("nroot(9, 2)").unwrap(),
+            eval_str("sqrt(9)").unwrap()
+        );
+        assert!((eval_str("nroot(2, 2)").unwrap() - 2_f64.sqrt()).abs() < eps);
+        assert!((eval_str("nroot(8, 1.5)").unwrap() - 4.0).abs() < eps);
+        // Even roots of negative numbers are an error like `sqrt(-1)`
+        assert!(eval_str("nroot(-16, 4)").is_err());
+        assert!(eval_str("nroot(-8, 1.5)").is_err());
+        assert!(matches!(
+            eval_str("nroot(8, 0)"),
+            Err(CalcError::EvalError(EvalError::InvalidArgument(_)))
+        ));
+        assert!((eval_str("exp(2)").unwrap() - 7.389056099).abs() < eps);
+    }
+
+    #[test]
+    fn test_rounding_precision() {
+        // 2^52 - 0.5 is the largest number with a fractional part of .5 that is exactly
+        // representable
+        assert_eq!(
+            eval_str("floor(4503599627370495.5)").unwrap(),
+            4503599627370495.0
+        );
+        assert_eq!(
+            eval_str("ceil(4503599627370495.5)").unwrap(),
+            4503599627370496.0
+        );
+        assert_eq!(
+            eval_str("round(4503599627370495.5)").unwrap(),
+            4503599627370496.0
+        );
+        assert_eq!(
+            eval_str("round(-4503599627370495.5)").unwrap(),
+            -4503599627370496.0
+        );
+
+        // 2^53 and above are returned unchanged
+        assert_eq!(
+            eval_str("round(9007199254740992)").unwrap(),
+            9007199254740992.0
+        );
+        assert_eq!(
+            eval_str("floor(9007199254740993)").unwrap(),
+            9007199254740992.0
+        );
+        assert_eq!(eval_str("ceil(1e20)").unwrap(), 1e20);
+        assert_eq!(eval_str("round(1e16 + 0.4)").unwrap(), 1e16);
+
+        assert_eq!(eval_str("round(2.5)").unwrap(), 3.0);
+        assert_eq!(eval_str("round(-2.5)").unwrap(), -3.0);
+    }
+
+    #[test]
+    fn test_invalid_encoding() {
+        let mut ctx = Context::new();
+        assert_eq!(eval_bytes_ctx(b"1 + 2", &mut ctx).unwrap(), 3.0);
+        assert!(matches!(
+            eval_bytes_ctx(b"1 + \xff2", &mut ctx),
+            Err(CalcError::InvalidEncoding { offset: 4 })
+        ));
+        // Truncated multi-byte sequence at the end
+        assert!(matches!(
+            eval_bytes_ctx(b"a = 1\n\xc3", &mut ctx),
+            Err(CalcError::InvalidEncoding { offset: 6 })
+        ));
+    }
+
+    #[test]
+    fn test_primes() {
+        for prime in ["2", "3", "5", "17", "97", "7919", "2147483647"] {
+            assert_eq!(eval_str(&format!("is_prime({})", prime)).unwrap(), 1.0);
+        }
+        for composite in ["0", "1", "4", "9", "25", "91", "7917", "4294967297"] {
+            assert_eq!(eval_str(&format!("is_prime({})", composite)).unwrap(), 0.0);
+        }
+
+        assert_eq!(eval_str("next_prime(0)").unwrap(), 2.0);
+        assert_eq!(eval_str("next_prime(2)").unwrap(), 3.0);
+        assert_eq!(eval_str("next_prime(17)").unwrap(), 19.0);
+        assert_eq!(eval_str("next_prime(7908)").unwrap(), 7919.0);
+
+        assert!(eval_str("is_prime(-7)").is_err());
+        assert!(eval_str("is_prime(7.5)").is_err());
+        assert!(eval_str("is_prime(1e20)").is_err());
+        assert!(eval_str("next_prime(2.5)").is_err());
+        assert!(eval_str("next_prime(9007199254740992)").is_err());
+    }
+
+    #[test]
+    fn test_gcd_lcm() {
+        for (src, expected) in [
+            ("gcd(12, 18)", 6.0),
+            ("gcd(18, 12)", 6.0),
+            ("gcd(0, 5)", 5.0),
+            ("gcd(5, 0)", 5.0),
+            ("gcd(0, 0)", 0.0),
+            ("gcd(17, 5)", 1.0),
+            ("gcd(9007199254740992, 6)", 2.0),
+            ("lcm(4, 6)", 12.0),
+            ("lcm(0, 5)", 0.0),
+            ("lcm(5, 0)", 0.0),
+            ("lcm(7, 7)", 7.0),
+            ("lcm(4294967296, 2097152)", 4294967296.0),
+            ("lcm(67108864, 134217727)", 9007199187632128.0),
+        ] {
+            assert_eq!(eval_str(src).unwrap(), expected, "{}", src);
+        }
+
+        for invalid in [
+            "gcd(-4, 6)",
+            "gcd(4, 6.5)",
+            "lcm(-4, 6)",
+            "lcm(1e20, 2)",
+            "lcm(94906267, 94906269)",
+        ] {
+            assert!(matches!(
+                eval_str(invalid).unwrap_err().eval_error(),
+                Some(EvalError::InvalidArgument(_))
+            ));
+        }
+        assert!(eval_str("gcd(4)").unwrap_err().is_wrong_arg_amount());
+    }
+
+    #[test]
+    fn test_eval_file() {
+        let path = std::env::temp_dir().join(format!("calc_test_{}.calc", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, "a = 2\nb = 0\na / b\n").unwrap();
+        let err = eval_file_ctx(path, &mut Context::new()).unwrap_err();
+        assert!(matches!(
+            &err,
+            CalcError::InFile { error, .. } if matches!(**error, CalcError::EvalError(EvalError::DivideByZero))
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!("{}: Eval error: Divide by zero", path)
+        );
+
+        std::fs::write(path, "a = 2\na * 3\n").unwrap();
+        assert_eq!(eval_file_ctx(path, &mut Context::new()).unwrap(), 6.0);
+
+        std::fs::remove_file(path).unwrap();
+        let err = eval_file_ctx(path, &mut Context::new()).unwrap_err();
+        assert!(err.to_string().starts_with(&format!("{}: IO error", path)));
+    }
+
+    #[test]
+    fn test_error_location() {
+        use crate::error::ParseError;
+
+        let location = |code: &str| match eval_str(code).unwrap_err() {
+            CalcError::ParseError(_, Some(location)) => location,
+            err => panic!("Error without location: {}", err),
+        };
+        assert_eq!(location("1 +* 2"), (1, 4));
+        assert_eq!(location("a = 1\nb = 2\nc = (a + b))\nc"), (3, 12));
+        assert_eq!(location("x = 1\n  y = * 2"), (2, 7));
+        // Columns count chars instead of bytes
+        assert_eq!(location("1 + # äöü"), (1, 10));
+        assert_eq!(location("a = 1 # äöü\nb = 2 # ä\n)"), (3, 1));
+        // Incomplete input fails after the last char
+        assert_eq!(location("fn f(x) {\n  x"), (2, 4));
+        assert_eq!(location("(1 + "), (1, 6));
+
+        let err = eval_str("a = 1\nb = )").unwrap_err();
+        assert!(matches!(
+            err.parse_error(),
+            Some(ParseError::UnexpectedToken(_))
+        ));
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 5: Parse error: Unexpected token RParen"
+        );
+
+        // Evaluation errors don't have a location
+        assert!(matches!(
+            eval_str("a = 1\nb = a / 0"),
+            Err(CalcError::EvalError(EvalError::DivideByZero))
+        ));
+        assert_eq!(eval_str("a = 1\nb = a / 0").unwrap_err().location(), None);
+
+        let path = std::env::temp_dir().join(format!("calc_location_{}.calc", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "a = 2\n\nb = a +\n   * 3\n").unwrap();
+        let err = eval_file_ctx(path, &mut Context::new()).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert!(err.parse_error().is_some());
+        assert_eq!(err.location(), Some((4, 4)));
+        assert!(err
+            .to_string()
+            .starts_with(&format!("{}: line 4, column 4: Parse error", path)));
+    }
+
+    #[test]
+    fn test_map_csv() {
+        use crate::csv::map_csv;
+
+        let plain = |result: &EvalResult| Some(result.value().to_string());
+
+        let input = "a, b\n1, 2\n-3, 4.5\n\n1e3,0\n";
+        let mut output = Vec::new();
+        map_csv(
+            "a + b",
+            &mut Context::new(),
+            input.as_bytes(),
+            &mut output,
+            plain,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "3\n1.5\n1000\n");
+
+        let mut output = Vec::new();
+        let err = map_csv(
+            "a + c",
+            &mut Context::new(),
+            input.as_bytes(),
+            &mut output,
+            plain,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            &err,
+            CalcError::InCsvRow { row: 1, error }
+                if matches!(**error, CalcError::EvalError(EvalError::VariableNotDefined(_)))
+        ));
+
+        let input = "a,b\n1,2\n3\n";
+        let mut output = Vec::new();
+        let err = map_csv(
+            "a + b",
+            &mut Context::new(),
+            input.as_bytes(),
+            &mut output,
+            plain,
+        )
+        .unwrap_err();
+        assert!(matches!(err, CalcError::InCsvRow { row: 2, .. }));
+        assert_eq!(String::from_utf8(output).unwrap(), "3\n");
+
+        let input = "a,b\n1,x\n";
+        let err = map_csv(
+            "a + b",
+            &mut Context::new(),
+            input.as_bytes(),
+            Vec::new(),
+            plain,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "row 1: Tokenize error: Unexpected character 'x' at position 0"
+        );
+
+        assert!(map_csv(
+            "a +",
+            &mut Context::new(),
+            input.as_bytes(),
+            Vec::new(),
+            plain
+        )
+        .is_err());
+        assert!(map_csv("a", &mut Context::new(), "".as_bytes(), Vec::new(), plain).is_err());
+
+        // Columns must be valid variable names that can be assigned to
+        for header in ["a b", "1a", "if", "a + b", ""] {
+            let input = format!("{},c\n1,2\n", header);
+            let err = map_csv(
+                "c",
+                &mut Context::new(),
+                input.as_bytes(),
+                Vec::new(),
+                plain,
+            )
+            .unwrap_err();
+            assert!(matches!(err, CalcError::InvalidCsv(_)), "{}", header);
+        }
+        let err = map_csv(
+            "pi",
+            &mut Context::new(),
+            "pi\n3\n".as_bytes(),
+            Vec::new(),
+            plain,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            &err,
+            CalcError::InCsvRow { row: 1, error }
+                if matches!(**error, CalcError::EvalError(EvalError::CannotReassignConstant(_)))
+        ));
+
+        // Each result is written as the format closure returns it
+        let input = "a\n2\n3\n";
+        let mut output = Vec::new();
+        let format = |result: &EvalResult| match result {
+            EvalResult::Assigned(name, value) => Some(format!("{} = {}", name, value)),
+            _ => None,
+        };
+        map_csv(
+            "b = a * 2",
+            &mut Context::new(),
+            input.as_bytes(),
+            &mut output,
+            format,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "b = 4\nb = 6\n");
+        // Results without output are written as empty lines, which keeps one line per row
+        let mut output = Vec::new();
+        map_csv(
+            "a * 2",
+            &mut Context::new(),
+            input.as_bytes(),
+            &mut output,
+            format,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "\n\n");
+    }
+
+    #[test]
+    fn test_stats() {
+        use crate::stats::Stats;
+
+        let mut stats = Stats::new();
+        assert_eq!(stats.mean(), None);
+        for x in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            stats.push(x);
+        }
+        assert_eq!(stats.count(), 8);
+        assert_eq!(stats.mean(), Some(5.0));
+        assert_eq!(stats.variance(), Some(4.0));
+        assert_eq!(stats.min(), Some(2.0));
+        assert_eq!(stats.max(), Some(9.0));
+
+        let mut ctx = Context::new();
+        let code = "\
+            s = stats_new()
+            other = stats_new()
+            i = 1
+            while (i <= 5) {
+                stats_push(s, i)
+                i = i + 1
+            }
+            stats_push(other, 100)";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("stats_count(s)", &mut ctx).unwrap(), 5.0);
+        assert_eq!(eval_str_ctx("stats_mean(s)", &mut ctx).unwrap(), 3.0);
+        assert_eq!(eval_str_ctx("stats_variance(s)", &mut ctx).unwrap(), 2.0);
+        assert_eq!(eval_str_ctx("stats_min(s)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("stats_max(s)", &mut ctx).unwrap(), 5.0);
+        assert_eq!(eval_str_ctx("stats_mean(other)", &mut ctx).unwrap(), 100.0);
+        // The mean is updated after every push
+        assert_eq!(
+            eval_str_ctx("stats_push(s, 9)\nstats_mean(s)", &mut ctx).unwrap(),
+            4.0
+        );
+
+        // Forks get a copy of the accumulators
+        let mut fork = ctx.fork();
+        eval_str_ctx("stats_push(s, 100)", &mut fork).unwrap();
+        assert_eq!(eval_str_ctx("stats_count(s)", &mut ctx).unwrap(), 6.0);
+
+        assert!(eval_str_ctx("stats_mean(stats_new())", &mut ctx).is_err());
+        for invalid in [
+            "stats_mean(42)",
+            "stats_mean(-1)",
+            "stats_mean(0.5)",
+            "stats_push(7, 1)",
+        ] {
+            assert!(matches!(
+                eval_str(invalid),
+                Err(CalcError::EvalError(EvalError::InvalidArgument(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        let mut ctx = Context::new();
+        for _ in 0..100 {
+            let r = eval_str_ctx("random()", &mut ctx).unwrap();
+            assert!((0.0..1.0).contains(&r));
+        }
+
+        let mut a = Context::new();
+        eval_str_ctx("seed(42)", &mut a).unwrap();
+        let mut b = a.clone();
+        let first_a = eval_str_ctx("random()", &mut a).unwrap();
+        let second_a = eval_str_ctx("random()", &mut a).unwrap();
+        eval_str_ctx("random()\nrandom()\nrandom()", &mut a).unwrap();
+        assert_eq!(eval_str_ctx("random()", &mut b).unwrap(), first_a);
+        assert_eq!(eval_str_ctx("random()", &mut b).unwrap(), second_a);
+
+        let mut c = Context::new();
+        eval_str_ctx("seed(42)", &mut c).unwrap();
+        assert_eq!(eval_str_ctx("random()", &mut c).unwrap(), first_a);
+        eval_str_ctx("seed(43)", &mut c).unwrap();
+        assert_ne!(eval_str_ctx("random()", &mut c).unwrap(), first_a);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        use crate::eval::Function;
+        use std::{cell::Cell, rc::Rc};
+
+        assert_eq!(eval_str("3 < 5").unwrap(), 1.0);
+        assert_eq!(eval_str("5 < 3").unwrap(), 0.0);
+        assert_eq!(eval_str("3 > 5").unwrap(), 0.0);
+        assert_eq!(eval_str("3 <= 3").unwrap(), 1.0);
+        assert_eq!(eval_str("3 >= 4").unwrap(), 0.0);
+        assert_eq!(eval_str("3 == 3").unwrap(), 1.0);
+        assert_eq!(eval_str("3 != 3").unwrap(), 0.0);
+        assert_eq!(eval_str("1 + 2 == 3").unwrap(), 1.0);
+        assert_eq!(eval_str("(1 < 2) + (2 < 3)").unwrap(), 2.0);
+        assert!(eval_str("1 <").is_err());
+        assert!(eval_str("< 1").is_err());
+        assert!(eval_str("1 = = 1").is_err());
+        assert!(eval_str("1 ! 1").is_err());
+
+        assert_eq!(eval_str("1 < 2 < 3").unwrap(), 1.0);
+        assert_eq!(eval_str("3 < 2 < 1").unwrap(), 0.0);
+        assert_eq!(eval_str("1 < 3 < 2").unwrap(), 0.0);
+        assert_eq!(eval_str("1 < 3 > 2").unwrap(), 1.0);
+        assert_eq!(eval_str("1 == 1 == 1").unwrap(), 1.0);
+
+        let mut ctx = Context::new();
+        let calls = Rc::new(Cell::new(0));
+        let calls_in_fn = Rc::clone(&calls);
+        ctx.add_function(
+            "count",
+            Function::new_builtin(1, move |_ctx, args| {
+                calls_in_fn.set(calls_in_fn.get() + 1);
+                args[0]
+            }),
+        )
+        .unwrap();
+        assert_eq!(eval_str_ctx("1 < count(2) < 3", &mut ctx).unwrap(), 1.0);
+        assert_eq!(calls.get(), 1);
+        // The chain stops at the first comparison that doesn't hold
+        assert_eq!(eval_str_ctx("2 < 1 < count(3)", &mut ctx).unwrap(), 0.0);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        use crate::eval::Function;
+        use std::cell::Cell;
+
+        for (code, expected) in [
+            ("0 && 0", 0.0),
+            ("0 && 5", 0.0),
+            ("5 && 0", 0.0),
+            ("5 && -2", 1.0),
+            ("0 || 0", 0.0),
+            ("0 || 5", 1.0),
+            ("5 || 0", 1.0),
+            ("-2 || 5", 1.0),
+            // `&&` binds stronger than `||`, comparisons bind stronger than both
+            ("1 || 1 && 0", 1.0),
+            ("(1 || 1) && 0", 0.0),
+            ("0 && 1 || 1", 1.0),
+            ("1 < 2 && 2 < 3", 1.0),
+            ("1 + 1 == 2 || 0", 1.0),
+            ("2 > 3 || 3 > 4", 0.0),
+            ("!0", 1.0),
+            ("!3", 0.0),
+            ("!-0.5", 0.0),
+            ("!!7", 1.0),
+            ("!0 && 0", 0.0),
+            ("!(0 && 0)", 1.0),
+            ("!1 + 1", 1.0),
+            ("!(1 < 2)", 0.0),
+            ("-!0", -1.0),
+        ] {
+            assert_eq!(eval_str(code).unwrap(), expected, "{}", code);
+        }
+        assert!(eval_str("1 &&").is_err());
+        assert!(eval_str("|| 1").is_err());
+        assert!(eval_str("1 & 1").is_err());
+        assert!(eval_str("1 | 1").is_err());
+        assert!(eval_str("!").is_err());
+        assert!(eval_str("1 !1").is_err());
+
+        let mut ctx = Context::new();
+        let calls = Rc::new(Cell::new(0));
+        let calls_in_fn = Rc::clone(&calls);
+        ctx.add_function(
+            "count",
+            Function::new_builtin(1, move |_ctx, args| {
+                calls_in_fn.set(calls_in_fn.get() + 1);
+                args[0]
+            }),
+        )
+        .unwrap();
+        // The right hand side is skipped once the result is known
+        assert_eq!(eval_str_ctx("0 && count(1)", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("3 || count(0)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(calls.get(), 0);
+        assert_eq!(eval_str_ctx("3 && count(7)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("0 || count(-7)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("0 || count(0)", &mut ctx).unwrap(), 0.0);
+        assert_eq!(calls.get(), 3);
+        assert_eq!(
+            eval_str_ctx("1 || count(1) && count(1)", &mut ctx).unwrap(),
+            1.0
+        );
+        assert_eq!(calls.get(), 3);
+
+        ctx.add_function(
+            "fail",
+            Function::new_fallible_builtin(0, |_ctx, _args| {
+                Err(EvalError::InvalidArgument("must not be called".to_owned()))
+            }),
+        )
+        .unwrap();
+        assert_eq!(eval_str_ctx("0 && fail()", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("!0 || fail()", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("1 < 0 && fail() || 2", &mut ctx).unwrap(), 1.0);
+        assert!(eval_str_ctx("1 && fail()", &mut ctx).is_err());
+        assert!(eval_str_ctx("!fail() || 1", &mut ctx).is_err());
+    }
+
+    #[test]
+    fn test_function_metadata() {
+        use crate::eval::Function;
+
+        let mut ctx = Context::new();
+        let functions: Vec<_> = ctx.functions().collect();
+        for expected in [
+            ("sin", 1),
+            ("log", 1),
+            ("log", 2),
+            ("atan2", 2),
+            ("random", 0),
+            ("sum", Function::VARIADIC),
+            ("default", 2),
+        ] {
+            assert!(functions.contains(&expected), "{:?}", expected);
+        }
+        assert!(functions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(!functions.iter().any(|(name, _)| *name == "f"));
+
+        eval_str_ctx("fn f(a, b) { a + b }", &mut ctx).unwrap();
+        ctx.define_alias("g", "f").unwrap();
+        ctx.define_alias("lg", "log").unwrap();
+        ctx.set_var("x", 1.0);
+        ctx.define_alias("y", "x").unwrap();
+        let functions: Vec<_> = ctx.functions().collect();
+        assert!(functions.contains(&("f", 2)));
+        assert!(functions.contains(&("g", 2)));
+        assert!(functions.contains(&("lg", 1)) && functions.contains(&("lg", 2)));
+        assert!(!functions
+            .iter()
+            .any(|(name, _)| *name == "y" || *name == "x"));
+        assert_eq!(ctx.user_functions(), [("f", 2)]);
+    }
+
+    #[test]
+    fn test_functions() {
+        use crate::eval::Function;
+
+        let mut ctx = Context::new();
+        ctx.add_function(
+            "add",
+            Function::new_builtin(2, |_ctx, args| args[0] + args[1]),
+        )
+        .unwrap();
+
+        assert!(eval_str_ctx("add()", &mut ctx).is_err());
+        assert!(eval_str_ctx("add(1)", &mut ctx).is_err());
+        assert!(eval_str_ctx("add(1,)", &mut ctx).is_err());
+        assert!(eval_str_ctx("add(,1)", &mut ctx).is_err());
+        assert!(eval_str_ctx("add(,)", &mut ctx).is_err());
+        assert!(eval_str_ctx("add(1, 2,,)", &mut ctx).is_err());
+        assert_eq!(eval_str_ctx("add(1, 2,)", &mut ctx).unwrap(), 3.0);
+        assert!(eval_str_ctx("add(1 1)", &mut ctx).is_err());
+        assert_eq!(eval_str_ctx("add(1, 2)", &mut ctx).unwrap(), 3.0);
+        assert!(eval_str_ctx("add(1, 2, 3)", &mut ctx).is_err());
+
+        assert_eq!(eval_str_ctx("add (1, 2)", &mut ctx).unwrap(), 3.0);
+        eval_str_ctx("foo = 4", &mut ctx).unwrap();
+        assert!(matches!(
+            eval_str_ctx("foo(1)", &mut ctx),
+            Err(CalcError::EvalError(EvalError::FunctionNotDefined(_)))
+        ));
+        assert!(eval_str_ctx("foo (1)", &mut ctx).is_err());
+        assert_eq!(eval_str_ctx("foo\n(1)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("foo * (1)", &mut ctx).unwrap(), 4.0);
+        // A bracket after a number is an implicit multiplication, not a call
+        assert_eq!(eval_str_ctx("2(1)", &mut ctx).unwrap(), 2.0);
+        assert_eq!(eval_str_ctx("add(1, add(2, 3))", &mut ctx).unwrap(), 6.0);
+    }
+
+    #[test]
+    fn test_new_with() {
+        use crate::eval::{Function, ModuloMode};
+
+        let mut calls = 0;
+        let mut ctx = Context::new_with(|ctx| {
+            calls += 1;
+            ctx.add_function(
+                "dist",
+                Function::new_builtin(2, |_ctx, args| args[0].hypot(args[1])),
+            )
+            .unwrap();
+            ctx.set_var("answer", 42.0);
+            ctx.set_modulo_mode(ModuloMode::Floored);
+        });
+        assert_eq!(calls, 1);
+
+        assert_eq!(eval_str_ctx("dist(3, 4)", &mut ctx).unwrap(), 5.0);
+        assert_eq!(eval_str_ctx("answer + pi - pi", &mut ctx).unwrap(), 42.0);
+        assert_eq!(eval_str_ctx("-7 % 3", &mut ctx).unwrap(), 2.0);
+        assert_eq!(eval_str_ctx("sqrt(16)", &mut ctx).unwrap(), 4.0);
+        assert!(eval_str_ctx("fn dist(a, b) { a + b }", &mut ctx).is_err());
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut ctx = Context::new();
+        assert_eq!(eval_str_ctx("const c = 5", &mut ctx).unwrap(), 5.0);
+        assert!(matches!(
+            eval_str_ctx("c = 6", &mut ctx),
+            Err(CalcError::EvalError(EvalError::CannotReassignConstant(name))) if name == "c"
+        ));
+        assert!(eval_str_ctx("const c = 7", &mut ctx).is_err());
+        assert!(eval_str_ctx("bound c = 0 in [0, 1]", &mut ctx).is_err());
+        assert_eq!(ctx.get_var("c"), Some(5.0));
+
+        // The value is computed once at the definition
+        eval_str_ctx("x = 2\nconst d = pi * x\nx = 5", &mut ctx).unwrap();
+        assert_eq!(
+            eval_str_ctx("d", &mut ctx).unwrap(),
+            2.0 * std::f64::consts::PI
+        );
+        assert!(matches!(
+            eval_str_ctx("const g = 9.81 * h", &mut ctx),
+            Err(CalcError::EvalError(EvalError::VariableNotDefined(_)))
+        ));
+        assert_eq!(ctx.get_var("g"), None);
+
+        // Existing variables can be turned into constants and the standard constants are protected
+        assert_eq!(eval_str_ctx("const x = x + 1\nx", &mut ctx).unwrap(), 6.0);
+        assert!(eval_str_ctx("x = 1", &mut ctx).is_err());
+        assert!(eval_str_ctx("pi = 3", &mut ctx).is_err());
+        assert!(eval_str_ctx("e = 3", &mut ctx).is_err());
+        // Also inside of functions, where the assignment would otherwise create a local variable
+        for code in ["fn f() {\npi = 3\npi\n}\nf()", "fn f() {\ne += 1\n}\nf()"] {
+            assert!(matches!(
+                eval_str(code),
+                Err(CalcError::EvalError(EvalError::CannotReassignConstant(_)))
+            ));
+        }
+
+        // Constants in functions only exist during the call and can shadow global ones
+        eval_str_ctx("fn f(v) {\nconst c = v * 2\nc\n}", &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("f(1) + f(2)", &mut ctx).unwrap(), 6.0);
+        assert!(eval_str("fn f(v) {\nconst v = 1\nv = 2\n}\nf(0)").is_err());
+        // Assigning to a global constant in a function doesn't create a local variable, but a
+        // local variable of the same name can be assigned to
+        eval_str_ctx("fn g(v) {\nc = v\nc\n}", &mut ctx).unwrap();
+        assert!(matches!(
+            eval_str_ctx("g(1)", &mut ctx),
+            Err(CalcError::EvalError(EvalError::CannotReassignConstant(name))) if name == "c"
+        ));
+        assert_eq!(
+            eval_str_ctx("fn h(c) {\nc = c + 1\nc\n}\nh(1)", &mut ctx).unwrap(),
+            2.0
+        );
+
+        // The host can still overwrite constants
+        ctx.set_var("c", 8.0);
+        assert_eq!(ctx.get_var("c"), Some(8.0));
+
+        assert!(eval_str("const = 1").is_err());
+        assert!(eval_str("const c").is_err());
+
+        let mut ctx = Context::new();
+        eval_str_ctx("const g = 9.81", &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("2 * g", &mut ctx).unwrap(), 19.62);
+        for code in ["g = 10", "g += 1", "g *= 2"] {
+            assert!(matches!(
+                eval_str_ctx(code, &mut ctx),
+                Err(CalcError::EvalError(EvalError::CannotReassignConstant(name))) if name == "g"
+            ));
+        }
+        assert_eq!(
+            eval_str_ctx("g = 10", &mut ctx).unwrap_err().to_string(),
+            "Eval error: Cannot assign to 'g', since it is a constant"
+        );
+        assert_eq!(eval_str_ctx("g", &mut ctx).unwrap(), 9.81);
+    }
+
+    #[test]
+    fn test_vars() {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.vars(), vec![]);
+
+        eval_str_ctx(
+            "zeta = 3\nalpha = 1\nmid = alpha + 1\nconst c = 4",
+            &mut ctx,
+        )
+        .unwrap();
+        eval_str_ctx("fn f(x) { local = x\nlocal }\nf(5)", &mut ctx).unwrap();
+        assert_eq!(
+            ctx.vars(),
+            vec![("alpha", 1.0), ("mid", 2.0), ("zeta", 3.0)]
+        );
+
+        ctx.remove_var("mid");
+        assert_eq!(ctx.vars(), vec![("alpha", 1.0), ("zeta", 3.0)]);
+    }
+
+    #[test]
+    fn test_vars_json() {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.vars_json(), "{}");
+
+        eval_str_ctx("b = 3\na = 2\nconst c = 4\nfn f(x) { x }", &mut ctx).unwrap();
+        assert_eq!(ctx.vars_json(), r#"{"a": 2.0, "b": 3.0}"#);
+
+        eval_str_ctx("small = -0.1\nbig = 10 ^ 20", &mut ctx).unwrap();
+        ctx.set_var("quo\"te", f64::NAN);
+        assert_eq!(
+            ctx.vars_json(),
+            r#"{"a": 2.0, "b": 3.0, "big": 1e20, "quo\"te": null, "small": -0.1}"#
+        );
+    }
+
+    #[test]
+    fn test_bounded_variables() {
+        let mut ctx = Context::new();
+        assert_eq!(
+            eval_str_ctx("bound speed = 0 in [0, 100]", &mut ctx).unwrap(),
+            0.0
+        );
+        assert_eq!(eval_str_ctx("speed = 150", &mut ctx).unwrap(), 100.0);
+        assert_eq!(ctx.get_var("speed"), Some(100.0));
+        assert_eq!(eval_str_ctx("speed = speed - 30", &mut ctx).unwrap(), 70.0);
+        assert_eq!(eval_str_ctx("speed = -5", &mut ctx).unwrap(), 0.0);
+        ctx.set_var("speed", 1000.0);
+        assert_eq!(ctx.get_var("speed"), Some(100.0));
+
+        // The initial value is clamped as well and the bounds can be expressions
+        eval_str_ctx("lo = -1\nbound x = 5 in [lo * 2, lo + 2]", &mut ctx).unwrap();
+        assert_eq!(ctx.get_var("x"), Some(1.0));
+        // Redeclaring replaces the bounds, renaming keeps them
+        eval_str_ctx("bound x = 5 in [0, 10]", &mut ctx).unwrap();
+        assert_eq!(ctx.get_var("x"), Some(5.0));
+        ctx.rename_var("x", "y").unwrap();
+        assert_eq!(eval_str_ctx("y = 11", &mut ctx).unwrap(), 10.0);
+        assert_eq!(eval_str_ctx("x = 11", &mut ctx).unwrap(), 11.0);
+
+        // Bounds inside functions only apply to the call
+        let code = "\
+            fn f(v) {
+                bound v = v in [0, 1]
+                v = v * 10
+                v
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("f(0.05)", &mut ctx).unwrap(), 0.5);
+        assert_eq!(eval_str_ctx("f(0.5)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("v = 5\nv", &mut ctx).unwrap(), 5.0);
+
+        assert!(matches!(
+            eval_str("bound z = 0 in [1, 0]"),
+            Err(CalcError::EvalError(EvalError::InvalidArgument(_)))
+        ));
+        assert!(eval_str("bound z = 0 in [0 100]").is_err());
+        assert!(eval_str("bound z = 0 in (0, 100)").is_err());
+        assert!(eval_str("bound z = 0").is_err());
+        assert!(eval_str("bound = 1\nin = 2\nbound + in").is_ok());
+    }
+
+    #[test]
+    fn test_rename_var() {
+        let mut ctx = Context::new();
+        eval_str_ctx("a = 2\nb = a * 3", &mut ctx).unwrap();
+
+        ctx.rename_var("a", "x").unwrap();
+        assert_eq!(ctx.get_var("x"), Some(2.0));
+        assert_eq!(ctx.get_var("a"), None);
+        // Values computed from the old name are not recomputed
+        assert_eq!(ctx.get_var("b"), Some(6.0));
+        assert_eq!(eval_str_ctx("x + b", &mut ctx).unwrap(), 8.0);
+
+        assert!(matches!(
+            ctx.rename_var("x", "b"),
+            Err(EvalError::VariableAlreadyDefined(name)) if name == "b"
+        ));
+        assert!(matches!(
+            ctx.rename_var("x", "pi"),
+            Err(EvalError::VariableAlreadyDefined(_))
+        ));
+        assert!(matches!(
+            ctx.rename_var("missing", "y"),
+            Err(EvalError::VariableNotDefined(name)) if name == "missing"
+        ));
+        assert_eq!(ctx.get_var("x"), Some(2.0));
+        assert_eq!(ctx.get_var("b"), Some(6.0));
+        assert_eq!(ctx.get_var("y"), None);
+    }
+
+    #[test]
+    fn test_remove_var_and_clear() {
+        let mut ctx = Context::new();
+        eval_str_ctx(
+            "a = 2\nb = 3\nconst c = 4\nbound d = 5 in [0, 10]",
+            &mut ctx,
+        )
+        .unwrap();
+
+        assert_eq!(ctx.remove_var("a"), Some(2.0));
+        assert_eq!(ctx.get_var("a"), None);
+        assert_eq!(ctx.remove_var("a"), None);
+        assert!(eval_str_ctx("a", &mut ctx).is_err());
+        assert_eq!(ctx.get_var("b"), Some(3.0));
+
+        // Removing a constant or bounded variable also removes its constness or bounds
+        assert_eq!(ctx.remove_var("c"), Some(4.0));
+        assert_eq!(eval_str_ctx("c = 40", &mut ctx).unwrap(), 40.0);
+        assert_eq!(ctx.remove_var("d"), Some(5.0));
+        assert_eq!(eval_str_ctx("d = 50", &mut ctx).unwrap(), 50.0);
+
+        eval_str_ctx("fn f(x) { x }\ns = stats_new()", &mut ctx).unwrap();
+        ctx.define_alias("g", "f").unwrap();
+        ctx.clear();
+        for name in ["b", "c", "d", "s"] {
+            assert_eq!(ctx.get_var(name), None);
+        }
+        assert!(eval_str_ctx("f(1)", &mut ctx).is_err());
+        assert!(eval_str_ctx("g(1)", &mut ctx).is_err());
+        assert!(eval_str_ctx("stats_count(0)", &mut ctx).is_err());
+        // Standard functions and constants are kept
+        assert_eq!(ctx.get_var("pi"), Some(std::f64::consts::PI));
+        assert!(eval_str_ctx("pi = 3", &mut ctx).is_err());
+        assert_eq!(eval_str_ctx("max(1, 2)", &mut ctx).unwrap(), 2.0);
+        // Everything can be defined again
+        assert_eq!(
+            eval_str_ctx("fn f(x) { x * 2 }\nf(2)", &mut ctx).unwrap(),
+            4.0
+        );
+    }
+
+    #[test]
+    fn test_eval_into() {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.eval_into("result", "2 + 2 * 2").unwrap(), 6.0);
+        assert_eq!(ctx.get_var("result"), Some(6.0));
+        assert_eq!(ctx.eval_into("result", "result + 1").unwrap(), 7.0);
+        assert_eq!(ctx.get_var("result"), Some(7.0));
+
+        assert_eq!(ctx.eval_into("a", "b = 2").unwrap(), 2.0);
+        assert_eq!(ctx.get_var("a"), Some(2.0));
+        assert_eq!(ctx.get_var("b"), Some(2.0));
+
+        assert!(ctx.eval_into("result", "1 / 0").is_err());
+        assert!(ctx.eval_into("new", "undefined").is_err());
+        assert_eq!(ctx.get_var("result"), Some(7.0));
+        assert_eq!(ctx.get_var("new"), None);
+
+        // Constants are protected like in an assignment
+        assert!(matches!(
+            ctx.eval_into("pi", "3"),
+            Err(CalcError::EvalError(EvalError::CannotReassignConstant(name))) if name == "pi"
+        ));
+        assert_eq!(ctx.get_var("pi"), Some(std::f64::consts::PI));
+    }
+
+    #[test]
+    fn test_eval_many() {
+        let mut ctx = Context::new();
+        let results = ctx.eval_many(&["a = 2", "a / 0", "b = a * 3", "", "b + 1"]);
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap(), &2.0);
+        assert!(results[1].as_ref().unwrap_err().is_division_by_zero());
+        assert_eq!(results[2].as_ref().unwrap(), &6.0);
+        assert_eq!(results[3].as_ref().unwrap(), &0.0);
+        assert_eq!(results[4].as_ref().unwrap(), &7.0);
+        assert_eq!(ctx.get_var("b"), Some(6.0));
+
+        assert!(ctx.eval_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut parent = Context::new();
+        eval_str_ctx("a = 1\nfn double(x) { 2 * x }", &mut parent).unwrap();
+
+        let mut fork = parent.fork();
+        assert!(fork.shares_builtins_with(&parent));
+        assert!(!Context::new().shares_builtins_with(&parent));
+        assert!(Rc::ptr_eq(
+            &fork.get_function("sin").unwrap(),
+            &parent.get_function("sin").unwrap()
+        ));
+
+        assert_eq!(eval_str_ctx("sin(0) + double(a)", &mut fork).unwrap(), 2.0);
+        eval_str_ctx("a = 5\nb = 6\nfn triple(x) { 3 * x }", &mut fork).unwrap();
+        assert_eq!(fork.get_var("a"), Some(5.0));
+        assert_eq!(parent.get_var("a"), Some(1.0));
+        assert_eq!(parent.get_var("b"), None);
+        assert!(parent.get_function("triple").is_none());
+
+        eval_str_ctx("c = 7", &mut parent).unwrap();
+        assert_eq!(fork.get_var("c"), None);
+
+        // Builtins can't be redefined, neither in the parent nor in a fork
+        assert!(eval_str_ctx("fn sin(x) { x }", &mut fork).is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        use crate::parser::{rewrite, AST};
+
+        let double_numbers = |src: &str| {
+            let ast = parse(&tokenize(src).unwrap()).unwrap();
+            rewrite(ast, |node| match node {
+                AST::Number(num) => Some(AST::Number(
+                    (num.parse::<Number>().unwrap() * 2.0).to_string(),
+                )),
+                _ => None,
+            })
+            .to_string()
+        };
+        assert_eq!(
+            double_numbers("1 + 2 * -3 ^ 4 % 5 / 6"),
+            "2 + 4 * -6 ^ 8 % 10 / 12"
+        );
+        assert_eq!(double_numbers("1 < a <= (2)"), "2 < a <= (4)");
+        assert_eq!(double_numbers("1 ^ 2 ^ 3"), "2 ^ 4 ^ 6");
+        assert_eq!(
+            double_numbers("bound x = 1 in [0, 2]"),
+            "bound x = 2 in [0, 4]"
+        );
+        assert_eq!(
+            double_numbers("x = max(1, 2.5)\nfn f(a) { if (a == 1) { 2 } else { 3 } }"),
+            "x = max(2, 5)\nfn f(a) {\n    if (a == 2) {\n        4\n    } else {\n        6\n    }\n}"
+        );
+
+        // Replacements are not visited again
+        let ast = parse(&tokenize("x + 1").unwrap()).unwrap();
+        let mut visited = 0;
+        let ast = rewrite(ast, |node| {
+            visited += 1;
+            match node {
+                AST::Variable(_) => Some(AST::Brackets(Box::new(node.clone()))),
+                _ => None,
+            }
+        });
+        assert_eq!(ast.to_string(), "(x) + 1");
+        assert_eq!(visited, 4);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let sources = [
+            "2 + 3 * 4",
+            "(2 + 3) * 4",
+            "2 - (3 - 4)",
+            "2 ^ 3 ^ 2",
+            "(2 ^ 3) ^ 2",
+            "-(2 + 3) * -4 // 3 % 2",
+            "(-2) ^ 2 + -2 ^ 2",
+            "(2 + 1)! + !0",
+            "1 < 2 <= (3 == 3) && !(0 || 0)",
+            "max(1, 2 + 3, min(4, 5)) / ((6))",
+            "x = 2 * 3\nconst c = x\nbound b = 5 in [0, -x]",
+            "fn f(a, b) {\nc = a * b\nc + 1\n}\nf(2, 3)",
+            "if (1 > 2) { 3 } else if (0) { 4 } else { if (1) { 5 } }",
+            "i = 0\nwhile (i < 3) { i += 1 }",
+            "unless (0) { 6 }",
+            "2pi",
+        ];
+        for src in sources {
+            let ast = parse(&tokenize(src).unwrap()).unwrap();
+            let displayed = ast.to_string();
+            let reparsed = parse(&tokenize(&displayed).unwrap())
+                .unwrap_or_else(|err| panic!("{:?} displayed as {:?}: {}", src, displayed, err));
+            assert_eq!(reparsed.to_string(), displayed, "{}", src);
+            assert_eq!(
+                eval_str(&displayed).ok(),
+                eval_str(src).ok(),
+                "{} displayed as {}",
+                src,
+                displayed
+            );
+        }
+
+        let ast = parse(&tokenize("2 + 3 * 4").unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "2 + 3 * 4");
+        let ast = parse(&tokenize("(2+3)*4").unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "(2 + 3) * 4");
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        use crate::{optimize::fold_constants, parser::AST};
+
+        let fold = |src: &str| fold_constants(parse(&tokenize(src).unwrap()).unwrap());
+        match fold("2 + 3 * 4") {
+            AST::Lines(lines) => assert!(matches!(lines.as_slice(), [AST::Number(n)] if n == "14")),
+            ast => panic!("Unexpected AST {:?}", ast),
+        }
+
+        for (src, folded) in [
+            ("2 + 3 * 4", "14"),
+            ("(1 + 2) * x", "3 * x"),
+            ("x * (1 + 2)", "x * 3"),
+            ("x + 1 + 2", "x + 1 + 2"),
+            ("0x10 / 2 ^ 2 // 3", "1"),
+            ("1 - 3", "-2"),
+            ("2 * (1 - 3)", "-4"),
+            ("--2", "2"),
+            ("-0 * 1", "-0"),
+            ("3! > 5 && !0", "1"),
+            ("0.1 + 0.2", "0.30000000000000004"),
+            ("max(1 + 1, x)", "max(2, x)"),
+            ("7 % 4 + 1", "7 % 4 + 1"),
+            (
+                "a = 2 * 3\nfn f(y) { y * (4 - 1) }",
+                "a = 6\nfn f(y) {\n    y * 3\n}",
+            ),
+            ("if (1 < 2) { 3 + 4 }", "if (1) {\n    7\n}"),
+            // Failing operations are left for the evaluation
+            ("1 / 0 + 2 * 3", "1 / 0 + 6"),
+            ("10 // (1 - 1)", "10 // 0"),
+            ("(-1)! * 2", "(-1)! * 2"),
+            ("10 ^ 400", "10 ^ 400"),
+        ] {
+            assert_eq!(fold(src).to_string(), folded, "{}", src);
+        }
+
+        // Folding doesn't change the result
+        let mut ctx = Context::new();
+        ctx.set_var("x", 5.0);
+        for src in [
+            "(1 + 2) * x - 2 ^ -(1 + 1)",
+            "-(2 - 3) ^ 2 * x",
+            "!(1 > 2) + 2pi",
+        ] {
+            let expected = eval_str_ctx(src, &mut ctx).unwrap();
+            assert_eq!(evaluate(&fold(src), &mut ctx).unwrap(), expected, "{}", src);
+        }
+        assert!(evaluate(&fold("1 / 0"), &mut ctx)
+            .unwrap_err()
+            .to_string()
+            .contains("Divide by zero"));
+    }
+
+    #[test]
+    fn test_compiled_expr() {
+        let src = "y = x ^ 2 - 3 * x + 2 ^ 0.5\nsqrt(abs(y)) + x % 7";
+        let compiled = CompiledExpr::compile(src).unwrap();
+        let mut ctx = Context::new();
+        let mut reparsed_ctx = Context::new();
+        for i in 0..1000 {
+            let x = i as Number * 0.37 - 50.0;
+            ctx.set_var("x", x);
+            reparsed_ctx.set_var("x", x);
+            let expected = eval_str_ctx(src, &mut reparsed_ctx).unwrap();
+            assert_eq!(compiled.eval(&mut ctx).unwrap(), expected);
+        }
+        assert_eq!(ctx.get_var("y"), reparsed_ctx.get_var("y"));
+
+        // Definitions and errors behave like in `eval_str_ctx`
+        let mut ctx = Context::new();
+        let define = CompiledExpr::compile("fn f(a) { a * 2 }").unwrap();
+        define.eval(&mut ctx).unwrap();
+        assert!(define.eval(&mut ctx).is_err());
+        let call = CompiledExpr::compile("f(n) / (n - 1)").unwrap();
+        ctx.set_var("n", 3.0);
+        assert_eq!(call.eval(&mut ctx).unwrap(), 3.0);
+        ctx.set_var("n", 1.0);
+        assert!(call.eval(&mut ctx).unwrap_err().is_division_by_zero());
+        assert_eq!(
+            CompiledExpr::compile("1 +\n* 2").unwrap_err().to_string(),
+            "line 2, column 1: Parse error: Unexpected token Operator(Star)"
+        );
+        assert!(CompiledExpr::compile("elif = 1").is_ok());
+        let python = Syntax {
+            python_compat: true,
+            ..Syntax::default()
+        };
+        assert!(CompiledExpr::compile_with_syntax("elif = 1", python).is_err());
+    }
+
+    #[test]
+    fn test_eval_detailed() {
+        use crate::eval::EvalResult;
+
+        let mut ctx = Context::new();
+        let mut eval = |src| eval_str_detailed(src, &mut ctx).unwrap();
+        assert_eq!(eval("1 + 2"), EvalResult::Value(3.0));
+        assert_eq!(eval("a = 2"), EvalResult::Assigned("a".to_owned(), 2.0));
+        assert_eq!(
+            eval("const c = a + 1"),
+            EvalResult::Assigned("c".to_owned(), 3.0)
+        );
+        assert_eq!(
+            eval("bound b = 9 in [0, 5]"),
+            EvalResult::Assigned("b".to_owned(), 5.0)
+        );
+        assert_eq!(eval("a += 1"), EvalResult::Assigned("a".to_owned(), 3.0));
+        assert_eq!(
+            eval("fn f(x) { x * 2 }"),
+            EvalResult::Defined("f".to_owned())
+        );
+        assert_eq!(
+            eval("fn g(x) { x }\nfn h(x) { x }"),
+            EvalResult::Defined("h".to_owned())
+        );
+        assert_eq!(eval(""), EvalResult::NoValue);
+        assert_eq!(eval("# only a comment"), EvalResult::NoValue);
+        assert_eq!(eval("print(5)"), EvalResult::NoValue);
+        assert_eq!(eval("if (a > 1) { f(a) }"), EvalResult::Value(0.0));
+        // The last line that produces a value decides, like for the value of `evaluate`
+        assert_eq!(
+            eval("x = 4\nfn k(y) { y }\nprint(x)"),
+            EvalResult::Assigned("x".to_owned(), 4.0)
+        );
+        assert_eq!(
+            eval("f(1)\nz = 7"),
+            EvalResult::Assigned("z".to_owned(), 7.0)
+        );
+
+        for src in [
+            "1 + 2",
+            "q = 2",
+            "fn m(x) { x }",
+            "",
+            "print(1)\n3\nfn n(x) { x }",
+        ] {
+            let detailed = eval_str_detailed(src, &mut Context::new()).unwrap();
+            assert_eq!(detailed.value(), eval_str(src).unwrap(), "{}", src);
+        }
+        assert!(eval_str_detailed("1 / 0", &mut Context::new())
+            .unwrap_err()
+            .is_division_by_zero());
+    }
+
+    #[test]
+    fn test_session_replay() {
+        use crate::session::Session;
+
+        let mut session = Session::new();
+        assert_eq!(session.eval("a = 2").unwrap(), 2.0);
+        assert!(session.eval("b = undefined_var").is_err());
+        assert!(session.eval("1 +").is_err());
+        session.eval("fn square(x) { x ^ 2 }").unwrap();
+        session.eval("b = square(a) + 1\nc = -7 % 3").unwrap();
+        session.eval("a = a + 1").unwrap();
+        assert_eq!(
+            session.history(),
+            [
+                "a = 2",
+                "fn square(x) { x ^ 2 }",
+                "b = square(a) + 1\nc = -7 % 3",
+                "a = a + 1"
+            ]
+        );
+
+        let replayed = session.replay().unwrap();
+        assert_eq!(replayed.history(), session.history());
+        for name in ["a", "b", "c"] {
+            assert_eq!(
+                replayed.context().get_var(name),
+                session.context().get_var(name)
+            );
+        }
+        assert_eq!(replayed.context().get_var("a"), Some(3.0));
+        assert_eq!(replayed.context().get_var("b"), Some(5.0));
+        assert_eq!(
+            replayed.context().functions_source(),
+            session.context().functions_source()
+        );
+    }
+
+    #[test]
+    fn test_session_ans() {
+        use crate::session::Session;
+
+        let mut session = Session::new();
+        assert_eq!(session.context().get_var("ans"), None);
+        assert!(session.eval("ans").is_err());
+
+        session.eval("3 * 4").unwrap();
+        assert_eq!(session.context().get_var("ans"), Some(12.0));
+        assert_eq!(session.eval("ans * 2").unwrap(), 24.0);
+        assert_eq!(session.eval("ans + ans").unwrap(), 48.0);
+
+        // Errors keep the previous value
+        assert!(session.eval("ans / 0").is_err());
+        assert!(session.eval("1 +").is_err());
+        assert_eq!(session.context().get_var("ans"), Some(48.0));
+
+        // Assignments produce a value, but definitions and comments don't change `ans`
+        session.eval("a = 5").unwrap();
+        assert_eq!(session.context().get_var("ans"), Some(5.0));
+        session.eval("fn f(x) { x }").unwrap();
+        assert_eq!(session.context().get_var("ans"), Some(5.0));
+        session.eval("# comment").unwrap();
+        session.eval("print(7)").unwrap();
+        assert_eq!(session.eval("f(ans)").unwrap(), 5.0);
+
+        // Evaluating in the context directly doesn't set `ans`
+        let mut ctx = Context::new();
+        eval_str_ctx("1 + 1", &mut ctx).unwrap();
+        assert_eq!(ctx.get_var("ans"), None);
+
+        assert_eq!(
+            session.replay().unwrap().context().get_var("ans"),
+            Some(5.0)
+        );
+    }
+
+    #[test]
+    fn test_functions_source() {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.functions_source(), "");
+
+        let src = "fn sgn(x) { if (x < 0) { -1 } else { if (x > 0) { 1 } else { 0 } } }
+fn poly(a, b) {
+    c = (a + b) * (a - b)
+    -c ^ 2 - (a - (b - 1)) / 2
+}
+fn add(a, b) { a + b }";
+        eval_str_ctx(src, &mut ctx).unwrap();
+        let dump = ctx.functions_source();
+        assert!(dump.starts_with("fn add(a, b) {\n    a + b\n}\nfn poly(a, b) {"));
+        assert!(dump.contains("-c ^ 2 - (a - (b - 1)) / 2"));
+        assert!(!dump.contains("fn sin"));
+
+        let mut restored = Context::new();
+        eval_str_ctx(&dump, &mut restored).unwrap();
+        assert_eq!(restored.functions_source(), dump);
+        for call in ["sgn(-3)", "sgn(0)", "sgn(2)", "poly(3, 1)", "add(1, 2)"] {
+            assert_eq!(
+                eval_str_ctx(call, &mut restored).unwrap(),
+                eval_str_ctx(call, &mut ctx).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut ctx = Context::new();
+        ctx.define_alias("arcsin", "asin").unwrap();
+        assert_eq!(
+            eval_str_ctx("arcsin(1)", &mut ctx).unwrap(),
+            eval_str_ctx("asin(1)", &mut ctx).unwrap()
+        );
+        assert!(matches!(
+            eval_str_ctx("arcsin(1, 2)", &mut ctx),
+            Err(CalcError::EvalError(EvalError::FunctionWrongArgAmount {
+                expected: 1,
+                got: 2,
+                ..
+            }))
+        ));
+
+        eval_str_ctx("a = 1", &mut ctx).unwrap();
+        ctx.define_alias("b", "a").unwrap();
+        ctx.define_alias("c", "b").unwrap();
+        eval_str_ctx("a = 2", &mut ctx).unwrap();
+        assert_eq!(ctx.get_var("b"), Some(2.0));
+        assert_eq!(eval_str_ctx("c + 1", &mut ctx).unwrap(), 3.0);
+
+        assert!(matches!(
+            ctx.define_alias("x", "not_defined"),
+            Err(EvalError::AliasTargetNotDefined(_))
+        ));
+        assert!(eval_str_ctx("x", &mut ctx).is_err());
+    }
+
+    #[test]
+    fn test_multiple_lines() {
+        let mut ctx = Context::new();
+
+        let result = eval_str_ctx(
+            r"a = 2
+            b = 3
+            c = a + b",
+            &mut ctx,
+        )
+        .unwrap();
+
+        assert_eq!(result, 5.0);
+        assert_eq!(ctx.get_var("a"), Some(2.0));
+        assert_eq!(ctx.get_var("b"), Some(3.0));
+        assert_eq!(ctx.get_var("c"), Some(5.0));
+
+        assert_eq!(eval_str("\n42\n").unwrap(), 42.0);
+        assert_eq!(eval_str("42\n").unwrap(), 42.0);
+        assert_eq!(eval_str("\n42").unwrap(), 42.0);
+        assert_eq!(eval_str("\n\n\n").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_print() {
+        let mut ctx = Context::new();
+        assert_eq!(
+            eval_str_ctx("a = 3\na * 2\nprint(a)", &mut ctx).unwrap(),
+            6.0
+        );
+        assert_eq!(
+            eval_str_ctx("print(a)\nprint(a + 1)", &mut ctx).unwrap(),
+            0.0
+        );
+        assert_eq!(eval_str_ctx("print(a) + 1", &mut ctx).unwrap(), 4.0);
+        assert_eq!(eval_str_ctx("inspect(a)", &mut ctx).unwrap(), 3.0);
+
+        let code = "\
+            fn f(x) {
+                x * 2
+                print(x)
+            }
+            f(5)";
+        assert_eq!(eval_str_ctx(code, &mut ctx).unwrap(), 10.0);
+    }
+
+    #[test]
+    fn test_newlines_not_allowed() {
+        // A line may end with a binary operator or `=`, but the next one can't start with it
+        assert!(eval_str("1\n+ 2").is_err());
+        assert!(eval_str("a\n= 2").is_err());
+        assert!(eval_str("(1 + 2)\n* 3").is_err());
+        assert!(eval_str("-\n1").is_err());
+    }
+
+    #[test]
+    fn test_newlines_in_parentheses() {
+        assert_eq!(eval_str("sin(pi\n/2)").unwrap(), 1.0);
+        assert_eq!(eval_str("sin(\npi/2)").unwrap(), 1.0);
+        assert_eq!(eval_str("1 * (2 + \n 3)").unwrap(), 5.0);
+        assert_eq!(eval_str("(\n(1 +\n2)\n*\n3\n)").unwrap(), 9.0);
+        assert_eq!(eval_str("max(\n  1,\n  max(2,\n 3),\n)").unwrap(), 3.0);
+        assert_eq!(eval_str("a = (1 +\n2)\na * 2").unwrap(), 6.0);
+        assert_eq!(
+            eval_str("fn add(\n  a,\n  b,\n) {\n  a + b\n}\nadd(1, 2)").unwrap(),
+            3.0
+        );
+        assert_eq!(
+            eval_str("x = 1\nif (x > 0 &&\n    x < 2) {\n  x = 5\n}\nx").unwrap(),
+            5.0
+        );
+        // A newline inside of parentheses doesn't end the line, so the statements still need to
+        // be separated
+        assert_eq!(eval_str("(1\n)\n2").unwrap(), 2.0);
+        assert!(eval_str("(1\n) 2").is_err());
+        assert!(eval_str("sin(\n").is_err());
+    }
+
+    #[test]
+    fn test_user_functions() {
+        let code = "\
+            fn add(a, b, c) {\n\
+                a + b + c\n\
+            }\n\
+            \n\
+            fn sub(a, b) {\n\
+                a - b\n\
+            }\n\
+            \n\
+            sub(42, add(1, 2, 3))";
+        assert_eq!(eval_str(code).unwrap(), 36.0);
+
+        assert!(eval_str("fn add(a, {b) a + b }").is_err());
+        assert!(eval_str("fn empty_body() {}").is_ok());
+        assert!(eval_str("fn no_args() {\n inspect(1)\n }").is_ok());
+        assert!(eval_str("fn one_liner(a, b) { a + b }").is_ok());
+        assert_eq!(
+            eval_str("fn trailing_comma(a, b,) { a + b }\ntrailing_comma(1, 2)").unwrap(),
+            3.0
+        );
+        assert!(eval_str("fn only_comma(,) { 1 }").is_err());
+        assert!(eval_str("fn double_comma(a,,) { a }").is_err());
+        assert!(eval_str("fn leading_comma(, a, b) { a + b }").is_err());
+        assert!(eval_str("fn no_comma(a b) { a + b }").is_err());
+        assert!(eval_str("fn contains_expression(a, b, 1 + 1) { a + b }").is_err());
+        assert!(eval_str("fn duplicate_arg_name(a, a) { a + a }").is_err());
+    }
+
+    #[test]
+    fn test_context_call() {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.call("max", &[3.0, 7.0]).unwrap(), 7.0);
+        assert_eq!(ctx.call("sin", &[0.0]).unwrap(), 0.0);
+        assert_eq!(ctx.call("sum", &[1.0, 2.0, 3.0]).unwrap(), 6.0);
+        assert_eq!(ctx.call("log", &[100.0]).unwrap(), 2.0);
+        assert_eq!(ctx.call("log", &[8.0, 2.0]).unwrap(), 3.0);
+
+        eval_str_ctx("fn hyp(a, b) { sqrt(a ^ 2 + b ^ 2) }", &mut ctx).unwrap();
+        assert_eq!(ctx.call("hyp", &[3.0, 4.0]).unwrap(), 5.0);
+        ctx.define_alias("h", "hyp").unwrap();
+        assert_eq!(ctx.call("h", &[6.0, 8.0]).unwrap(), 10.0);
+
+        assert_eq!(ctx.call("max", &[1.0, 9.0, 4.0]).unwrap(), 9.0);
+        assert!(matches!(
+            ctx.call("atan2", &[1.0]),
+            Err(CalcError::EvalError(EvalError::FunctionWrongArgAmount {
+                expected: 2,
+                got: 1,
+                ..
+            }))
+        ));
+        assert!(ctx
+            .call("hyp", &[1.0, 2.0, 3.0])
+            .unwrap_err()
+            .is_wrong_arg_amount());
+        assert!(ctx.call("log", &[]).unwrap_err().is_wrong_arg_amount());
+        assert_eq!(
+            ctx.call("nope", &[]).unwrap_err().undefined_function(),
+            Some("nope")
+        );
+        assert!(matches!(
+            ctx.call("exp", &[1000.0]),
+            Err(CalcError::EvalError(EvalError::Overflow))
+        ));
+    }
+
+    #[test]
+    fn test_block_values() {
+        // The value of a body is the value of its last line that produces a value
+        let mut ctx = Context::new();
+        let code = "\
+            fn one_liner(a, b) { a + b }
+            fn read_back(x) {
+                y = x * 2
+                y
+            }
+            fn assign_last(x) { y = x * 2 }
+            fn const_last(x) { const y = x * 3 }
+            fn define_last(x) {
+                x + 1
+                fn helper() { 0 }
+            }
+            fn print_last(x) {
+                x * 4
+                print(x)
+            }
+            fn nothing(x) { fn helper() { 0 } }
+            fn empty(x) {}
+            fn if_last(x) {
+                x * 5
+                if (x > 100) { 1 }
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("one_liner(1, 2)", &mut ctx).unwrap(), 3.0);
+        assert_eq!(eval_str_ctx("read_back(2)", &mut ctx).unwrap(), 4.0);
+        // Assignments produce the assigned value
+        assert_eq!(eval_str_ctx("assign_last(2)", &mut ctx).unwrap(), 4.0);
+        assert_eq!(eval_str_ctx("const_last(2)", &mut ctx).unwrap(), 6.0);
+        // Definitions and prints are skipped
+        assert_eq!(eval_str_ctx("define_last(2)", &mut ctx).unwrap(), 3.0);
+        assert_eq!(eval_str_ctx("print_last(2)", &mut ctx).unwrap(), 8.0);
+        assert_eq!(eval_str_ctx("nothing(2)", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("empty(2)", &mut ctx).unwrap(), 0.0);
+        // An if statement produces 0, whichever branch ran
+        assert_eq!(eval_str_ctx("if_last(2)", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("if_last(200)", &mut ctx).unwrap(), 0.0);
+
+        // The same rule applies at the top level
+        assert_eq!(eval_str("1\nfn f(x) { x }").unwrap(), 1.0);
+        assert_eq!(eval_str("a = 5").unwrap(), 5.0);
+    }
+
+    #[test]
+    fn test_line_comments() {
+        use crate::tokenizer::Token;
+
+        assert_eq!(
+            tokenize("1 + 1 # hi\n2").unwrap(),
+            tokenize("1 + 1\n2").unwrap()
+        );
+        assert_eq!(tokenize("# only a comment").unwrap(), []);
+        assert_eq!(
+            tokenize("#a\n#b\n").unwrap(),
+            [Token::Newline, Token::Newline]
+        );
+        // Everything up to the newline is skipped, even chars that are invalid otherwise
+        assert_eq!(
+            tokenize("a = 2 # set a @ $\na").unwrap(),
+            tokenize("a = 2\na").unwrap()
+        );
+        assert_eq!(eval_str("a = 2 # set a\na # use a").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn test_comments_in_bodies() {
+        let mut ctx = Context::new();
+        let code = "\
+            # Doubles the argument
+            fn double(x) {
+                # double it
+
+                x * 2 # the return value
+                # trailing comment
+
+            }
+            fn pick(x) {
+                if (x) {
+                    # leading comment
+                    return 1
+
+                } else {
+
+                    return 2
+                    # trailing comment
+                }
+            }
+            # comment at the end";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("double(21)", &mut ctx).unwrap(), 42.0);
+        assert_eq!(eval_str_ctx("pick(1)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("pick(0)", &mut ctx).unwrap(), 2.0);
+        assert!(eval_str_ctx("1 + # 2\n+ 3", &mut ctx).is_err());
+        assert_eq!(eval_str_ctx("1 + 2 # + 3", &mut ctx).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn test_nested_function_definitions() {
+        let mut ctx = Context::new();
+        let code = "\
+            fn helper(y) {
+                y
+            }
+            fn outer(x) {
+                fn helper(y) {
+                    y * y
+                }
+                helper(x) + 1
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("outer(3)", &mut ctx).unwrap(), 10.0);
+        assert_eq!(eval_str_ctx("outer(4)", &mut ctx).unwrap(), 17.0);
+        assert_eq!(eval_str_ctx("helper(3)", &mut ctx).unwrap(), 3.0);
+
+        let mut ctx = Context::new();
+        let code = "\
+            fn outer(x) {
+                fn inner(y) {
+                    y * y
+                }
+                inner(x) + 1
+            }
+            outer(3)";
+        assert_eq!(eval_str_ctx(code, &mut ctx).unwrap(), 10.0);
+        assert!(matches!(
+            eval_str_ctx("inner(3)", &mut ctx),
+            Err(CalcError::EvalError(EvalError::FunctionNotDefined(_)))
+        ));
+    }
+
+    #[test]
+    fn test_function_locals() {
+        let mut ctx = Context::new();
+        let code = "\
+            x = 1
+            const k = 3
+            fn assign_x() {
+                x = 5
+                x
+            }
+            fn read_outer() { x + k }
+            fn shadow_then_read() {
+                y = x
+                x = y * 10
+                x + k
+            }
+            fn set_local_const() {
+                const k = 100
+                k
+            }
+            fn caller() {
+                z = 7
+                callee()
+            }
+            fn callee() { z }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+
+        // Assignments create locals, which don't change the outer variable
+        assert_eq!(eval_str_ctx("assign_x()", &mut ctx).unwrap(), 5.0);
+        assert_eq!(ctx.get_var("x"), Some(1.0));
+        // Reads fall back to the global scope
+        assert_eq!(eval_str_ctx("read_outer()", &mut ctx).unwrap(), 4.0);
+        assert_eq!(eval_str_ctx("x = 2\nread_outer()", &mut ctx).unwrap(), 5.0);
+        assert_eq!(eval_str_ctx("shadow_then_read()", &mut ctx).unwrap(), 23.0);
+        assert_eq!(ctx.get_var("x"), Some(2.0));
+        assert_eq!(ctx.get_var("y"), None);
+        // A local constant shadows the global one only during the call
+        assert_eq!(eval_str_ctx("set_local_const()", &mut ctx).unwrap(), 100.0);
+        assert_eq!(ctx.get_var("k"), Some(3.0));
+        // Locals of the caller are not visible in the callee
+        assert!(matches!(
+            eval_str_ctx("caller()", &mut ctx),
+            Err(CalcError::EvalError(EvalError::VariableNotDefined(name))) if name == "z"
+        ));
+    }
+
+    #[test]
+    fn test_recycled_function_scopes() {
+        let mut ctx = Context::new();
+        let code = "\
+            fn square(x) {
+                fn helper(y) { y * y }
+                helper(x)
+            }
+            fn leak(x) {
+                if (x) {
+                    y = 5
+                }
+                y
+            }
+            fn sum_to(n) {
+                if (n > 0) {
+                    result = n + sum_to(n - 1)
+                } else {
+                    result = 0
+                }
+                result
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+
+        for i in 0..1000 {
+            let value = eval_str_ctx(&format!("square({})", i), &mut ctx).unwrap();
+            assert_eq!(value, (i * i) as f64);
+        }
+        assert_eq!(eval_str_ctx("sum_to(10)", &mut ctx).unwrap(), 55.0);
+        assert_eq!(eval_str_ctx("sum_to(4)", &mut ctx).unwrap(), 10.0);
+
+        // Variables of a previous call must not be visible in the next one
+        assert_eq!(eval_str_ctx("leak(1)", &mut ctx).unwrap(), 5.0);
+        assert!(eval_str_ctx("leak(0)", &mut ctx).is_err());
+    }
+
+    #[test]
+    fn test_complexity() {
+        use crate::metrics::{complexity, Metrics};
+
+        let code = "\
+            fn square(x) {
+                x * x
+            }
+            fn outer(x) {
+                fn inner(y) {
+                    -y
+                }
+                inner(x) + 1
+            }
+            square(2)";
+        assert_eq!(
+            complexity(code).unwrap(),
+            Metrics {
+                tokens: 45,
+                ast_nodes: 18,
+                max_depth: 7,
+                function_definitions: 3,
+            }
+        );
+
+        assert_eq!(
+            complexity("").unwrap(),
+            Metrics {
+                tokens: 0,
+                ast_nodes: 1,
+                max_depth: 1,
+                function_definitions: 0,
+            }
+        );
+        assert!(complexity("1 +").is_err());
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let mut ctx = Context::new();
+        let code = "\
+            fn countdown(n) {
+                if (n <= 0) {
+                    return 42
+                }
+                countdown(n - 1)
+            }
+            fn sum_to(n, acc) {
+                if (n == 0) {
+                    return acc
+                }
+                return sum_to(n - 1, acc + n)
+            }
+            fn not_tail(n) {
+                if (n == 0) {
+                    return 0
+                }
+                1 + not_tail(n - 1)
+            }
+            fn forever(x) { forever(x) }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+
+        assert_eq!(eval_str_ctx("countdown(100000)", &mut ctx).unwrap(), 42.0);
+        assert_eq!(
+            eval_str_ctx("sum_to(100000, 0)", &mut ctx).unwrap(),
+            5000050000.0
+        );
+        assert_eq!(eval_str_ctx("sum_to(10, 0)", &mut ctx).unwrap(), 55.0);
+        assert_eq!(eval_str_ctx("not_tail(5)", &mut ctx).unwrap(), 5.0);
+        assert!(eval_str_ctx("countdown(1, 2)", &mut ctx).is_err());
+
+        // Tail calls run in a loop, which is bounded like while loops
+        ctx.set_max_iterations(10);
+        assert!(matches!(
+            eval_str_ctx("forever(1)", &mut ctx)
+                .unwrap_err()
+                .eval_error(),
+            Some(EvalError::IterationLimit { limit: 10 })
+        ));
+        assert_eq!(eval_str_ctx("countdown(10)", &mut ctx).unwrap(), 42.0);
+        assert!(matches!(
+            eval_str_ctx("countdown(11)", &mut ctx)
+                .unwrap_err()
+                .eval_error(),
+            Some(EvalError::IterationLimit { limit: 10 })
+        ));
+    }
+
+    #[test]
+    fn test_if_statements() {
+        let code = "\
+            a = 0
+            if (0) {
+                a = 2
+            }
+            a";
+        assert_eq!(eval_str(code).unwrap(), 0.0);
+
+        // The if statement itself always has the value 0
+        assert_eq!(eval_str("if (1) { 5 }").unwrap(), 0.0);
+        assert_eq!(eval_str("if (0) { 5 } else { 6 }").unwrap(), 0.0);
+        assert_eq!(eval_str("fn f(x) { if (x) { 5 } }\nf(1)").unwrap(), 0.0);
+
+        let code = "\
+            a = 0
+            if (1) {
+                a = 2
+            }
+            a";
+        assert_eq!(eval_str(code).unwrap(), 2.0);
+
+        let code = "\
+            a = 0
+            if (0) {
+                a = 2
+            } else {
+                a = 3
+            }
+            a";
+        assert_eq!(eval_str(code).unwrap(), 3.0);
+
+        let code = "\
+            a = 0
+            if (1) {
+                a = 2
+            } else {
+                a = 3
+            }
+            a";
+        assert_eq!(eval_str(code).unwrap(), 2.0);
+
+        let mut ctx = Context::new();
+        let code = "\
+            fn sgn(x) {
+                if (x < 0) {
+                    return -1
+                } else if (x == 0) {
+                    return 0
+                }
+                else
+                if (x > 0) { return 1 }
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("sgn(-5)", &mut ctx).unwrap(), -1.0);
+        assert_eq!(eval_str_ctx("sgn(0)", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("sgn(5)", &mut ctx).unwrap(), 1.0);
+
+        assert_eq!(
+            eval_str("if (0) {r = 1} else if (1) {r = 2} else {r = 3}\nr").unwrap(),
+            2.0
+        );
+        assert_eq!(
+            eval_str("if (0) {r = 1} else if (0) {r = 2} else {r = 3}\nr").unwrap(),
+            3.0
+        );
+        assert_eq!(
+            eval_str(
+                "if (0) {r = 1} else if (0) {r = 2} else if (0) {r = 3} else if (1) {r = 4} else {r = 5}\nr"
+            )
+            .unwrap(),
+            4.0
+        );
+
+        // Nested chains inside the branches of a chain
+        let mut ctx = Context::new();
+        let code = "\
+            fn quadrant(x, y) {
+                if (x > 0) {
+                    if (y > 0) { return 1 } else if (y < 0) { return 4 }
+                } else if (x < 0) {
+                    if (y > 0) { return 2 } else if (y < 0) { return 3 }
+                }
+                0
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("quadrant(1, 1)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("quadrant(-1, 1)", &mut ctx).unwrap(), 2.0);
+        assert_eq!(eval_str_ctx("quadrant(-1, -1)", &mut ctx).unwrap(), 3.0);
+        assert_eq!(eval_str_ctx("quadrant(1, -1)", &mut ctx).unwrap(), 4.0);
+        assert_eq!(eval_str_ctx("quadrant(0, 1)", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("quadrant(1, 0)", &mut ctx).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_unless() {
+        use crate::{error::ParseError, tokenizer::Keyword};
+
+        let mut ctx = Context::new();
+        eval_str_ctx("a = 0\nunless (0) { a = 1 }", &mut ctx).unwrap();
+        assert_eq!(ctx.get_var("a"), Some(1.0));
+        eval_str_ctx("a = 0\nunless (1) { a = 1 }", &mut ctx).unwrap();
+        assert_eq!(ctx.get_var("a"), Some(0.0));
+
+        let code = "\
+            fn describe(x) {
+                unless (x > 0) {
+                    return -1
+                } else unless (x > 10) {
+                    return 1
+                } else {
+                    return 2
+                }
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("describe(-5)", &mut ctx).unwrap(), -1.0);
+        assert_eq!(eval_str_ctx("describe(5)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("describe(50)", &mut ctx).unwrap(), 2.0);
+
+        // The whole condition is negated
+        assert_eq!(
+            eval_str("unless (1 && 0) { r = 1 } else { r = 2 }\nr").unwrap(),
+            1.0
+        );
+        let ast = parse(&tokenize("unless (a < b || c) { 1 }").unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "if (!(a < b || c)) {\n    1\n}");
+
+        assert!(matches!(
+            eval_str("unless = 1"),
+            Err(CalcError::ParseError(
+                ParseError::KeywordAsName(Keyword::Unless),
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_while_loops() {
+        let mut ctx = Context::new();
+        let code = "\
+            i = 0
+            total = 0
+            while (i < 10) {
+                i = i + 1
+                total = total + i
+            }
+            total";
+        assert_eq!(eval_str_ctx(code, &mut ctx).unwrap(), 55.0);
+        assert_eq!(ctx.get_var("i"), Some(10.0));
+
+        // The value is the one of the last iteration
+        assert_eq!(
+            eval_str("i = 0\nwhile (i < 3) { i = i + 1\ni * 10 }").unwrap(),
+            30.0
+        );
+        assert_eq!(eval_str("while (0) { 1 }").unwrap(), 0.0);
+
+        let code = "\
+            fn euclid(a, b) {
+                while (b != 0) {
+                    t = b
+                    b = a % b
+                    a = t
+                }
+                a
+            }
+            euclid(48, 18)";
+        assert_eq!(eval_str(code).unwrap(), 6.0);
+
+        let err = eval_str("while (1) { 1 }").unwrap_err();
+        assert!(matches!(
+            err,
+            CalcError::EvalError(EvalError::IterationLimit {
+                limit: eval::DEFAULT_MAX_ITERATIONS
+            })
+        ));
+        assert!(err.to_string().contains("1000000"));
+
+        let mut ctx = Context::new();
+        ctx.set_max_iterations(5);
+        assert_eq!(
+            eval_str_ctx("i = 0\nwhile (i < 5) { i = i + 1 }", &mut ctx).unwrap(),
+            5.0
+        );
+        let err = eval_str_ctx("i = 0\nwhile (i < 6) { i = i + 1 }", &mut ctx).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Eval error: Loop did not finish within the limit of 5 iterations"
+        );
+        assert_eq!(ctx.get_var("i"), Some(5.0));
+
+        assert!(eval_str("while (1)").is_err());
+        assert!(eval_str("while { 1 }").is_err());
+        assert!(eval_str("while = 1").is_err());
+    }
+
+    #[test]
+    fn test_return() {
+        let mut ctx = Context::new();
+        let code = "\
+            fn abs_or_zero(x) {
+                if (x < 0) {
+                    return 0
+                }
+                x
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("abs_or_zero(-5)", &mut ctx).unwrap(), 0.0);
+        assert_eq!(eval_str_ctx("abs_or_zero(5)", &mut ctx).unwrap(), 5.0);
+
+        // `return` leaves loops and nested blocks, but only the innermost function call
+        let code = "\
+            fn first_divisor(n) {
+                d = 2
+                while (d < n) {
+                    if (n % d == 0) { return d } else { }
+                    d += 1
+                }
+                return n
+            }
+            fn twice_first_divisor(n) {
+                2 * first_divisor(n)
+            }";
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("first_divisor(91)", &mut ctx).unwrap(), 7.0);
+        assert_eq!(eval_str_ctx("first_divisor(13)", &mut ctx).unwrap(), 13.0);
+        assert_eq!(
+            eval_str_ctx("twice_first_divisor(15)", &mut ctx).unwrap(),
+            6.0
+        );
+
+        // Lines after a `return` are not evaluated
+        eval_str_ctx("fn f() { return 1\nx = 1 / 0 }", &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("f()", &mut ctx).unwrap(), 1.0);
+
+        // Returning a self-call is still a tail call
+        let code = "\
+            fn count_down(n) {
+                if (n > 0) { return count_down(n - 1) }
+                return 42
+            }
+            count_down(100000)";
+        assert_eq!(eval_str(code).unwrap(), 42.0);
+        let code = "\
+            fn sum_to(n) {
+                if (n > 0) { return n + sum_to(n - 1) }
+                return 0
+            }
+            sum_to(20)";
+        assert_eq!(eval_str(code).unwrap(), 210.0);
+
+        for code in [
+            "return 1",
+            "if (1) { return 2 }",
+            "while (1) { return 3 }",
+            "1\nreturn 2",
+        ] {
+            assert!(matches!(
+                eval_str(code).unwrap_err().eval_error(),
+                Some(EvalError::ReturnOutsideFunction)
+            ));
+        }
+        assert!(eval_str("fn g(x) { return }").is_err());
+        assert!(eval_str("fn g(x) { 1 + return 2 }").is_err());
+        assert!(eval_str("return = 1").is_err());
+        assert_eq!(
+            parse(&tokenize("fn g(x) { return x*2 }").unwrap())
+                .unwrap()
+                .to_string(),
+            "fn g(x) {\n    return x * 2\n}"
+        );
+    }
+
+    #[test]
+    fn test_elif() {
+        use crate::tokenizer::Syntax;
+
+        let code = "\
+            fn grade(x) {
+                if (x >= 90) {
+                    return 1
+                } elif (x >= 80) {
+                    return 2
+                }
+                elif (x >= 70) { return 3 } else { return 4 }
+            }";
+        assert!(eval_str(code).is_err());
+
+        let mut ctx = Context::new();
+        ctx.set_syntax(Syntax {
+            python_compat: true,
+            ..Syntax::default()
+        });
+        eval_str_ctx(code, &mut ctx).unwrap();
+        assert_eq!(eval_str_ctx("grade(95)", &mut ctx).unwrap(), 1.0);
+        assert_eq!(eval_str_ctx("grade(85)", &mut ctx).unwrap(), 2.0);
+        assert_eq!(eval_str_ctx("grade(75)", &mut ctx).unwrap(), 3.0);
+        assert_eq!(eval_str_ctx("grade(5)", &mut ctx).unwrap(), 4.0);
+        assert!(ctx.fork().syntax().python_compat);
+
+        // `elif` is reserved with python_compat, but a normal name without it
+        assert!(eval_str_ctx("elif = 1", &mut ctx).is_err());
+        assert!(eval_str_ctx("elif (1) { 2 }", &mut ctx).is_err());
+        assert_eq!(eval_str("elif = 1\nelif + 1").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn test_if_conditions() {
+        let check = |condition: &str| {
+            let code = format!("a = 0\nif ({}) {{\na = 1\n}}\na", condition);
+            eval_str(&code).unwrap() == 1.0
+        };
+
+        assert!(check("min(2, 3) - 1"));
+        assert!(!check("min(2, 3) - 2"));
+        assert!(!check("cos(0) - 1"));
+        assert!(check("sin(pi)"));
+        assert!(check("0.1 + 0.2 - 0.3"));
+        assert!(check("1e-300"));
+        assert!(!check("-0"));
+        assert!(check("2 > 1"));
+        assert!(!check("2 < 1"));
+        assert!(check("abs(sin(pi)) < 1e-9"));
+        assert!(eval_str("if (not_defined) {\n1\n}").is_err());
+    }
+
+    #[test]
+    fn test_error_accessors() {
+        let err = eval_str("1 / (2 - 2)").unwrap_err();
+        assert!(err.is_division_by_zero());
+        assert!(matches!(err.eval_error(), Some(EvalError::DivideByZero)));
+        assert!(err.parse_error().is_none());
+        assert!(err.undefined_variable().is_none());
+
+        let err = eval_str("a = 1\na + b").unwrap_err();
+        assert_eq!(err.undefined_variable(), Some("b"));
+        assert!(!err.is_division_by_zero());
+
+        assert_eq!(
+            eval_str("f(1)").unwrap_err().undefined_function(),
+            Some("f")
+        );
+        assert!(eval_str("sin(1, 2)").unwrap_err().is_wrong_arg_amount());
+        assert!(eval_str("log(1, 2, 3)").unwrap_err().is_wrong_arg_amount());
+        assert!(eval_str("(1 +").unwrap_err().is_incomplete_input());
+        assert!(!eval_str("1 + + 2").unwrap_err().is_incomplete_input());
+
+        // The helpers look through errors of files
+        let err = CalcError::InFile {
+            path: "test.calc".to_owned(),
+            error: Box::new(eval_str("5 % 0").unwrap_err()),
+        };
+        assert!(err.is_division_by_zero());
+        assert!(matches!(err.root(), CalcError::EvalError(_)));
+    }
+
+    #[test]
+    fn test_incomplete_input() {
+        use crate::error::ParseError;
+
+        let is_incomplete = |code: &str| {
+            matches!(
+                eval_str(code),
+                Err(CalcError::ParseError(ParseError::Incomplete(_), _))
+            )
+        };
+        for code in [
+            "fn f() {",
+            "fn f(x) {\n    x *",
+            "fn f(",
+            "(1 +",
+            "1 *",
+            "max(1,",
+            "if (1) { 2 } else",
+            "if (1",
+            "a =",
+        ] {
+            assert!(is_incomplete(code), "{}", code);
+        }
+        for code in ["1 + + 2", "fn f() x", "(1))", "1 2", "fn 2() {", "}"] {
+            assert!(eval_str(code).is_err());
+            assert!(!is_incomplete(code), "{}", code);
+        }
+
+        // A line may end with a binary operator or `=`, which continues on the next line
+        assert_eq!(eval_str("1 +\n2").unwrap(), 3.0);
+        assert_eq!(eval_str("x =\n\n5\nx").unwrap(), 5.0);
+        assert_eq!(eval_str("x = 2\nx +=\n3\nx").unwrap(), 5.0);
+        assert_eq!(eval_str("const c =\n4\nc").unwrap(), 4.0);
+        assert_eq!(eval_str("1 <\n2 ||\n0").unwrap(), 1.0);
+        assert_eq!(eval_str("fn f(x) {\n    x *\n    2\n}\nf(3)").unwrap(), 6.0);
+        // A newline before the operator still ends the line
+        assert_eq!(eval_str("1\n-2").unwrap(), -2.0);
+
+        // The parser itself reports unclosed braces as incomplete, also in nested blocks
+        for code in [
+            "if (x) {",
+            "if (x) {\n    y = 1\n",
+            "while (x) {\n    if (y) {\n        z\n    }",
+            "unless (x) { 1 } else {",
+            "fn f(x) {\n    if (x) { 1 } else { 2 }\n",
+        ] {
+            assert!(
+                matches!(
+                    parse(&tokenize(code).unwrap()),
+                    Err(ParseError::Incomplete(_))
+                ),
+                "{}",
+                code
+            );
+        }
+        assert!(matches!(
+            parse(&tokenize("if (x) { 1 }}").unwrap()),
+            Err(ParseError::UnexpectedToken(_))
+        ));
+    }
+
+    #[test]
+    fn test_errors_on_missing_newline() {
+        assert!(eval_str("1 + 1 2 + 2").is_err());
+        assert!(eval_str("1 2").is_err());
+        assert!(eval_str("(1 * 3) 2").is_err());
+
+        assert!(eval_str("fn add(a, b) { a + b } fn sub(a, b) { a - b }").is_err());
+        assert!(eval_str("if (1){ 1 } if (2){ 2 }").is_err());
+    }
+}
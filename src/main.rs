@@ -1,60 +1,335 @@
-use std::io;
-
-mod error;
-mod eval;
-mod parser;
-mod tokenizer;
-
-use error::CalcError;
-use eval::{evaluate, Context, Number};
-use parser::parse;
-use tokenizer::tokenize;
-
-fn eval_str_ctx(s: &str, ctx: &mut Context) -> Result<Number, CalcError> {
-    let tokens = tokenize(s)?;
-    let ast = parse(&tokens)?;
-    let result = evaluate(&ast, ctx)?;
-    Ok(result)
+use std::{
+    fs::File,
+    io::{self, BufReader, IsTerminal, Write},
+};
+
+use rust_calculator::{
+    csv::map_csv,
+    error::CalcError,
+    eval::{Context, EvalResult, Number},
+    eval_file_ctx,
+    session::Session,
+};
+
+/// Environment variable that selects the output format, if it isn't set with `--format`.
+const FORMAT_ENV_VAR: &str = "CALC_FORMAT";
+
+/// Magnitudes that `OutputFormat::Auto` prints without an exponent.
+const AUTO_FIXED_RANGE: std::ops::Range<Number> = 1e-4..1e15;
+
+/// Largest number of decimal places of `OutputFormat::Fixed`. Every `Number` is exactly
+/// representable with this many places (the smallest positive one is 2^-1074), so more places
+/// would only add zeros.
+const MAX_FIXED_PLACES: usize = 1074;
+
+/// Magnitude (2^64) below which `OutputFormat::Radix` prints integers in the radix, since they fit
+/// into an `u64`.
+const RADIX_LIMIT: Number = 18446744073709551616.0;
+
+/// How results are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Shortest representation that reads back as the same number. The output doesn't depend on
+    /// the locale or platform: `.` is the decimal separator, digits are never grouped and there is
+    /// no exponent, so it is stable enough for comparing against golden files.
+    #[default]
+    Default,
+    /// Scientific notation like `1.5e3`, selected with `sci`
+    Scientific,
+    /// Like `Default` for magnitudes in `AUTO_FIXED_RANGE` and zero, scientific notation
+    /// otherwise, selected with `auto`
+    Auto,
+    /// Fixed number of decimal places up to `MAX_FIXED_PLACES`, e.g. selected with `2f` for two
+    /// places
+    Fixed(usize),
+    /// Integers below `RADIX_LIMIT` in the given radix (2 to 36) with the digits `0-9a-z`, selected
+    /// with `:base` in the REPL. Other values are printed like `Default` with a note.
+    Radix(u32),
 }
 
-fn eval_file(path: &str) -> Result<(), CalcError> {
+impl OutputFormat {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "default" => Ok(Self::Default),
+            "sci" => Ok(Self::Scientific),
+            "auto" => Ok(Self::Auto),
+            _ => match s.strip_suffix('f').and_then(|places| places.parse().ok()) {
+                Some(places) if places > MAX_FIXED_PLACES => Err(format!(
+                    "Invalid output format '{}', at most {} decimal places are supported",
+                    s, MAX_FIXED_PLACES
+                )),
+                Some(places) => Ok(Self::Fixed(places)),
+                None => Err(format!("Invalid output format '{}'", s)),
+            },
+        }
+    }
+
+    /// Picks the format from the command line if given, else from the environment variable and
+    /// else the default.
+    fn resolve(flag: Option<Self>, env: Option<&str>) -> Result<Self, String> {
+        match (flag, env) {
+            (Some(format), _) => Ok(format),
+            (None, Some(env)) => {
+                Self::parse(env).map_err(|err| format!("{} in {}", err, FORMAT_ENV_VAR))
+            }
+            (None, None) => Ok(Self::default()),
+        }
+    }
+
+    fn format(self, value: Number) -> String {
+        match self {
+            Self::Default => value.to_string(),
+            Self::Scientific => format!("{:e}", value),
+            Self::Auto if value == 0.0 || AUTO_FIXED_RANGE.contains(&value.abs()) => {
+                value.to_string()
+            }
+            Self::Auto => format!("{:e}", value),
+            Self::Fixed(places) => format!("{:.*}", places, value),
+            Self::Radix(radix) if value.fract() == 0.0 && value.abs() < RADIX_LIMIT => {
+                let digits = format_radix(value.abs() as u64, radix);
+                if value < 0.0 {
+                    format!("-{}", digits)
+                } else {
+                    digits
+                }
+            }
+            Self::Radix(_) => format!("{} (not a 64-bit integer, shown in decimal)", value),
+        }
+    }
+}
+
+/// Formats the result of an input in the REPL or of a row with `--map`. Assignments are shown with
+/// the name of the variable, while function definitions and inputs without a value print nothing.
+fn format_result(result: &EvalResult, format: OutputFormat) -> Option<String> {
+    match result {
+        EvalResult::Value(value) => Some(format.format(*value)),
+        EvalResult::Assigned(name, value) => Some(format!("{} = {}", name, format.format(*value))),
+        _ => None,
+    }
+}
+
+/// Formats `value` in `radix`, which must be between 2 and 36.
+fn format_radix(mut value: u64, radix: u32) -> String {
+    let mut digits = Vec::new();
+    loop {
+        let digit = char::from_digit((value % radix as u64) as u32, radix).unwrap();
+        digits.push(digit);
+        value /= radix as u64;
+        if value == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+/// Runs a REPL command like `:base 16`, which starts with a colon. Returns the output of the
+/// command, if any.
+fn run_command(
+    line: &str,
+    session: &mut Session,
+    format: &mut OutputFormat,
+) -> Result<Option<String>, String> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some(":base") => {
+            let radix = match (words.next(), words.next()) {
+                (Some(radix), None) => radix.parse().ok().filter(|r| (2..=36).contains(r)),
+                _ => None,
+            };
+            let radix = radix.ok_or("Usage: :base <radix>, where the radix is from 2 to 36")?;
+            *format = OutputFormat::Radix(radix);
+            Ok(None)
+        }
+        Some(":vars") => {
+            no_args(words, ":vars")?;
+            let vars: Vec<_> = session
+                .context()
+                .vars()
+                .into_iter()
+                .map(|(name, value)| format!("{} = {}", name, format.format(value)))
+                .collect();
+            Ok(Some(vars.join("\n")).filter(|vars| !vars.is_empty()))
+        }
+        Some(":funcs") => {
+            no_args(words, ":funcs")?;
+            let funcs: Vec<_> = session
+                .context()
+                .user_functions()
+                .into_iter()
+                .map(|(name, n_args)| match n_args {
+                    1 => format!("{} (1 argument)", name),
+                    n_args => format!("{} ({} arguments)", name, n_args),
+                })
+                .collect();
+            Ok(Some(funcs.join("\n")).filter(|funcs| !funcs.is_empty()))
+        }
+        Some(":clear") => {
+            no_args(words, ":clear")?;
+            session.clear();
+            Ok(None)
+        }
+        // Prints the source of a function, so it can be copied and edited
+        Some(":edit") => {
+            let name = match (words.next(), words.next()) {
+                (Some(name), None) => name,
+                _ => return Err("Usage: :edit <function>".to_owned()),
+            };
+            let ctx = session.context_mut();
+            match ctx.function_source(name) {
+                Some(source) => Ok(Some(source)),
+                None if ctx.get_function(name).is_some() => Err(format!(
+                    "Function '{}' is built in and can't be edited",
+                    name
+                )),
+                None => Err(format!("Function with name '{}' is not defined", name)),
+            }
+        }
+        Some(command) => Err(format!("Unknown command {}", command)),
+        None => Err("Missing command".to_owned()),
+    }
+}
+
+/// Checks that a REPL command without arguments was called without arguments.
+fn no_args<'a>(mut words: impl Iterator<Item = &'a str>, command: &str) -> Result<(), String> {
+    match words.next() {
+        Some(_) => Err(format!("Usage: {}", command)),
+        None => Ok(()),
+    }
+}
+
+/// Settings of the interactive REPL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ReplConfig {
+    prompt: String,
+    /// Prompt that is shown while the input of a previous line is continued
+    continuation_prompt: String,
+    banner: bool,
+}
+
+impl Default for ReplConfig {
+    fn default() -> Self {
+        Self {
+            prompt: "> ".to_owned(),
+            continuation_prompt: ". ".to_owned(),
+            banner: true,
+        }
+    }
+}
+
+impl ReplConfig {
+    fn prompt(&self, continuation: bool) -> &str {
+        if continuation {
+            &self.continuation_prompt
+        } else {
+            &self.prompt
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Options {
+    file: Option<String>,
+    /// Expression that is evaluated for every row of the CSV in `file`
+    map: Option<String>,
+    /// Output format given with `--format`, which takes precedence over the environment
+    format: Option<OutputFormat>,
+    repl: ReplConfig,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prompt" => {
+                options.repl.prompt = args.next().ok_or("Missing value for --prompt")?.to_owned();
+            }
+            "--no-banner" => options.repl.banner = false,
+            "--format" => {
+                let format = args.next().ok_or("Missing value for --format")?;
+                options.format = Some(OutputFormat::parse(format)?);
+            }
+            "--map" => {
+                options.map = Some(args.next().ok_or("Missing value for --map")?.to_owned());
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            file if options.file.is_none() => options.file = Some(file.to_owned()),
+            _ => return Err("Only one file can be evaluated".to_owned()),
+        }
+    }
+    if options.map.is_some() && options.file.is_none() {
+        return Err("--map requires a CSV file".to_owned());
+    }
+    Ok(options)
+}
+
+fn eval_file(path: &str, format: OutputFormat) -> Result<(), CalcError> {
     let mut ctx = Context::new();
-    let contents = std::fs::read_to_string(path)?;
-    let result = eval_str_ctx(&contents, &mut ctx)?;
-    println!("{}", result);
+    let result = eval_file_ctx(path, &mut ctx)?;
+    println!("{}", format.format(result));
     Ok(())
 }
 
-fn repl() {
-    // TODO: Implement proper multi-line support
+fn eval_csv(expr: &str, path: &str, format: OutputFormat) -> Result<(), CalcError> {
     let mut ctx = Context::new();
-    let _stdout = io::stdout();
+    let file = BufReader::new(File::open(path)?);
+    map_csv(expr, &mut ctx, file, io::stdout().lock(), |result| {
+        format_result(result, format)
+    })
+}
+
+fn repl(config: &ReplConfig, mut format: OutputFormat) {
+    let mut session = Session::new();
+    let mut stdout = io::stdout();
     let mut input = String::new();
 
+    // Banner and prompts are only meant for humans, so they would just pollute the output if the
+    // input is piped in
+    let interactive = io::stdin().is_terminal();
+    if interactive && config.banner {
+        println!("rust-calculator {}", env!("CARGO_PKG_VERSION"));
+    }
+
     loop {
+        if interactive {
+            print!("{}", config.prompt(!input.is_empty()));
+            stdout.flush().expect("Failed to flush stdout");
+        }
+
         let mut line = String::new();
         match std::io::stdin().read_line(&mut line) {
+            // End of input
+            Ok(0) => {
+                // Report the error of unfinished input
+                if !input.is_empty() {
+                    if let Err(err) = session.eval(&input) {
+                        eprintln!("{}", err);
+                    }
+                }
+                break;
+            }
             Ok(_) => {
                 let line = line.trim();
                 if line.is_empty() {
                     continue;
                 }
-                input.push_str(line);
-                match eval_str_ctx(&input, &mut ctx) {
-                    Ok(result) => {
-                        println!("{}", result);
-                        input.clear();
+                if input.is_empty() && line.starts_with(':') {
+                    match run_command(line, &mut session, &mut format) {
+                        Ok(Some(output)) => println!("{}", output),
+                        Ok(None) => (),
+                        Err(err) => eprintln!("{}", err),
                     }
-                    // Artifact from previous band aid multi-line support
-                    // input incomplete => {
-                    //     input.push('\n');
-                    //     print!("> ");
-                    //     stdout.lock().flush().expect("Failed to flush stdout");
-                    // }
-                    Err(err) => {
-                        eprintln!("{}", err);
-                        input.clear();
+                    continue;
+                }
+                match eval_repl_line(&mut session, &mut input, line) {
+                    Some(Ok(result)) => {
+                        if let Some(output) = format_result(&result, format) {
+                            println!("{}", output);
+                        }
                     }
+                    Some(Err(err)) => eprintln!("{}", err),
+                    // Keep reading lines until the input is complete
+                    None => (),
                 }
             }
             Err(err) => eprintln!("Error: {}", err),
@@ -62,286 +337,346 @@ fn repl() {
     }
 }
 
+/// Appends `line` to the lines of unfinished `input` and evaluates all of them. Returns `None` if
+/// the input is still incomplete, otherwise the result of the evaluation. Then `input` is cleared.
+fn eval_repl_line(
+    session: &mut Session,
+    input: &mut String,
+    line: &str,
+) -> Option<Result<EvalResult, CalcError>> {
+    if !input.is_empty() {
+        input.push('\n');
+    }
+    input.push_str(line);
+    match session.eval_detailed(input) {
+        Err(err) if err.is_incomplete_input() => None,
+        result => {
+            input.clear();
+            Some(result)
+        }
+    }
+}
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 {
-        if let Err(err) = eval_file(&args[1]) {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let env_format = std::env::var(FORMAT_ENV_VAR).ok();
+    let format = match OutputFormat::resolve(options.format, env_format.as_deref()) {
+        Ok(format) => format,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(file) = options.file {
+        let result = match options.map {
+            Some(expr) => eval_csv(&expr, &file, format),
+            None => eval_file(&file, format),
+        };
+        if let Err(err) = result {
             eprintln!("{}", err);
         }
         return;
     }
 
-    repl();
+    repl(&options.repl, format);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn eval_str(s: &str) -> Result<Number, CalcError> {
-        eval_str_ctx(s, &mut Context::new())
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
     }
 
     #[test]
-    fn test_eval_str() {
-        assert!(eval_str("").is_ok());
-        assert!(eval_str("-").is_err());
-        assert!(eval_str("* 2").is_err());
-        assert!(eval_str("2 +").is_err());
-        assert_eq!(eval_str("2").unwrap(), 2.0);
-        assert_eq!(eval_str("2 - 3").unwrap(), -1.0);
-        assert_eq!(eval_str("2-3").unwrap(), -1.0);
-        assert_eq!(eval_str("2 + 2 * 2").unwrap(), 6.0);
-        assert_eq!(eval_str("3 * 2 * 5 + 10 / 5 - 8").unwrap(), 24.0);
+    fn test_prompt_selection() {
+        let config = ReplConfig::default();
+        assert_eq!(config.prompt(false), "> ");
+        assert_eq!(config.prompt(true), ". ");
+
+        let options = parse_args(&args(&["--prompt", "calc> ", "--no-banner"])).unwrap();
+        assert_eq!(options.repl.prompt(false), "calc> ");
+        assert_eq!(options.repl.prompt(true), ". ");
+        assert!(!options.repl.banner);
+        assert_eq!(options.file, None);
     }
 
     #[test]
-    fn test_number_parsing() {
-        assert!(eval_str(".1").is_ok());
-        assert!(eval_str("1.1").is_ok());
-        assert!(eval_str("1.").is_ok());
-
-        assert!(eval_str("2.3.4").is_err());
-        assert!(eval_str("..").is_err());
-        assert!(eval_str("..1").is_err());
-        assert!(eval_str("1..").is_err());
-        assert!(eval_str(".1.").is_err());
+    fn test_parse_args() {
+        assert_eq!(parse_args(&[]).unwrap(), Options::default());
+        assert_eq!(
+            parse_args(&args(&["script.calc"])).unwrap().file.as_deref(),
+            Some("script.calc")
+        );
+        assert!(parse_args(&args(&["--prompt"])).is_err());
+        assert!(parse_args(&args(&["--unknown"])).is_err());
+        assert!(parse_args(&args(&["a.calc", "b.calc"])).is_err());
+
+        let options = parse_args(&args(&["--map", "a + b", "data.csv"])).unwrap();
+        assert_eq!(options.map.as_deref(), Some("a + b"));
+        assert_eq!(options.file.as_deref(), Some("data.csv"));
+        assert!(parse_args(&args(&["--map", "a + b"])).is_err());
+        assert!(parse_args(&args(&["data.csv", "--map"])).is_err());
     }
 
     #[test]
-    fn test_unary_minus() {
-        assert_eq!(eval_str("-2").unwrap(), -2.0);
-        assert_eq!(eval_str("2--2").unwrap(), 4.0);
-        assert_eq!(eval_str("2+-2").unwrap(), 0.0);
-        assert_eq!(eval_str("-2+-2").unwrap(), -4.0);
-        assert_eq!(eval_str("2---2").unwrap(), 0.0);
-        assert!(eval_str("2*+-2").is_err());
+    fn test_default_format_is_stable() {
+        for (source, expected) in [
+            ("1 + 2", "3"),
+            ("-7 / 2", "-3.5"),
+            ("0.1 + 0.2", "0.30000000000000004"),
+            ("1 / 3", "0.3333333333333333"),
+            ("2 / 3", "0.6666666666666666"),
+            ("10 ^ 20", "100000000000000000000"),
+            ("1234567.25", "1234567.25"),
+            ("1e-7", "0.0000001"),
+            ("-0", "-0"),
+            ("sqrt(2)", "1.4142135623730951"),
+            ("pi", "3.141592653589793"),
+        ] {
+            let result = rust_calculator::eval_str_ctx(source, &mut Context::new()).unwrap();
+            assert_eq!(OutputFormat::Default.format(result), expected, "{}", source);
+        }
     }
 
     #[test]
-    fn test_brackets() {
-        assert_eq!(eval_str("4 * (5 - 1)").unwrap(), 16.0);
-        assert_eq!(eval_str("(2 + 2) * (3 + 3)").unwrap(), 24.0);
-        assert_eq!(eval_str("(2 + 2)").unwrap(), 4.0);
-        assert_eq!(eval_str("-(2 + 2)").unwrap(), -4.0);
-        assert_eq!(eval_str("-((2 + 3) * 4)").unwrap(), -20.0);
-        assert_eq!(eval_str("-((2 + -4) * 5) / 2").unwrap(), 5.0);
-        assert_eq!(eval_str("(1 + 2) + 3").unwrap(), 6.0);
-        assert!(eval_str("-2 + 2)").is_err());
-        assert!(eval_str("-(2 + 2").is_err());
-        assert!(eval_str("()").is_err());
-    }
+    fn test_output_format() {
+        assert_eq!(OutputFormat::parse("sci"), Ok(OutputFormat::Scientific));
+        assert_eq!(OutputFormat::parse("2f"), Ok(OutputFormat::Fixed(2)));
+        assert_eq!(OutputFormat::parse("0f"), Ok(OutputFormat::Fixed(0)));
+        assert_eq!(OutputFormat::parse("default"), Ok(OutputFormat::Default));
+        for invalid in [
+            "",
+            "f",
+            "-1f",
+            "2",
+            "2.5f",
+            "SCI",
+            "99999999999999999999999f",
+        ] {
+            assert!(OutputFormat::parse(invalid).is_err());
+        }
+        // More places than any number has are refused instead of printing huge strings
+        assert_eq!(OutputFormat::parse("1074f"), Ok(OutputFormat::Fixed(1074)));
+        assert_eq!(
+            OutputFormat::parse("99999999999f"),
+            Err(
+                "Invalid output format '99999999999f', at most 1074 decimal places are supported"
+                    .to_owned()
+            )
+        );
+
+        assert_eq!(OutputFormat::Default.format(1500.0), "1500");
+        assert_eq!(
+            OutputFormat::Default.format(0.1 + 0.2),
+            "0.30000000000000004"
+        );
+        assert_eq!(OutputFormat::Scientific.format(1500.0), "1.5e3");
+        assert_eq!(OutputFormat::Fixed(2).format(2.0 / 3.0), "0.67");
+        assert_eq!(OutputFormat::Fixed(0).format(-2.5), "-2");
+
+        assert_eq!(OutputFormat::parse("auto"), Ok(OutputFormat::Auto));
+        for (value, expected) in [
+            (1e-12, "1e-12"),
+            (1e20, "1e20"),
+            (-2.5e-7, "-2.5e-7"),
+            (1234.5, "1234.5"),
+            (-0.001, "-0.001"),
+            (0.0, "0"),
+            (123456789012345.0, "123456789012345"),
+            (1e15, "1e15"),
+            (1.0 / 3.0, "0.3333333333333333"),
+        ] {
+            assert_eq!(OutputFormat::Auto.format(value), expected);
+        }
 
-    #[test]
-    fn test_power() {
-        assert!(eval_str("4 ^").is_err());
-        assert!(eval_str("^ 3").is_err());
-        assert_eq!(eval_str("1 ^ -3").unwrap(), 1.0);
-        assert_eq!(eval_str("(-1) ^ -3").unwrap(), -1.0);
-        assert_eq!(eval_str("(-1) ^ -4").unwrap(), 1.0);
-        assert_eq!(eval_str("2 ^ -3").unwrap(), 0.125);
-        assert_eq!(eval_str("2 ^ 0").unwrap(), 1.0);
-        assert_eq!(eval_str("3 ^ 5").unwrap(), 243.0);
-        assert_eq!(eval_str("-1 ^ 4").unwrap(), 1.0);
-        assert_eq!(eval_str("-1 ^ 5").unwrap(), -1.0);
-        assert_eq!(eval_str("-1 ^ -5").unwrap(), -1.0);
-        assert_eq!(eval_str("(1 + 1) ^ (4 * 2)").unwrap(), 256.0);
+        // --format > environment > default
+        let flag = parse_args(&args(&["--format", "3f"])).unwrap().format;
+        assert_eq!(flag, Some(OutputFormat::Fixed(3)));
+        assert_eq!(
+            OutputFormat::resolve(flag, Some("sci")),
+            Ok(OutputFormat::Fixed(3))
+        );
+        assert_eq!(
+            OutputFormat::resolve(flag, Some("invalid")),
+            Ok(OutputFormat::Fixed(3))
+        );
+        assert_eq!(
+            OutputFormat::resolve(None, Some("sci")),
+            Ok(OutputFormat::Scientific)
+        );
+        assert!(OutputFormat::resolve(None, Some("invalid")).is_err());
+        assert_eq!(OutputFormat::resolve(None, None), Ok(OutputFormat::Default));
+
+        assert!(parse_args(&args(&["--format"])).is_err());
+        assert!(parse_args(&args(&["--format", "x"])).is_err());
     }
 
     #[test]
-    fn test_mod() {
-        assert!(eval_str("2 %").is_err());
-        assert!(eval_str("% 3").is_err());
-        assert!(eval_str("100 % 0").is_err());
-        assert_eq!(eval_str("7 % 3").unwrap(), 1.0);
-        assert_eq!(eval_str("7 % -3").unwrap(), 1.0);
-        assert_eq!(eval_str("-7 % 3").unwrap(), -1.0);
-        assert_eq!(eval_str("-9 % -3").unwrap(), 0.0);
-        assert_eq!(eval_str("42 % 1337").unwrap(), 42.0);
-        assert_eq!(eval_str("2 + 3 * 4 % 5").unwrap(), 4.0);
+    fn test_radix_format() {
+        assert_eq!(OutputFormat::Radix(16).format(255.0), "ff");
+        assert_eq!(OutputFormat::Radix(16).format(-4096.0), "-1000");
+        assert_eq!(OutputFormat::Radix(2).format(10.0), "1010");
+        assert_eq!(OutputFormat::Radix(2).format(0.0), "0");
+        assert_eq!(OutputFormat::Radix(36).format(35.0), "z");
+        assert_eq!(OutputFormat::Radix(36).format(1295.0), "zz");
+        assert_eq!(
+            OutputFormat::Radix(8).format(1e19),
+            "1053071060221172000000"
+        );
+        assert_eq!(
+            OutputFormat::Radix(16).format(2.5),
+            "2.5 (not a 64-bit integer, shown in decimal)"
+        );
+        assert_eq!(
+            OutputFormat::Radix(16).format(1e20),
+            "100000000000000000000 (not a 64-bit integer, shown in decimal)"
+        );
+
+        let mut session = Session::new();
+        let mut format = OutputFormat::Default;
+        run_command(":base 16", &mut session, &mut format).unwrap();
+        assert_eq!(format, OutputFormat::Radix(16));
+        run_command(":base   2", &mut session, &mut format).unwrap();
+        assert_eq!(format, OutputFormat::Radix(2));
+        for invalid in [
+            ":base",
+            ":base 1",
+            ":base 37",
+            ":base x",
+            ":base 2 3",
+            ":unknown",
+            ":",
+        ] {
+            assert!(run_command(invalid, &mut session, &mut format).is_err());
+        }
+        assert_eq!(format, OutputFormat::Radix(2));
     }
 
     #[test]
-    fn test_variables() {
-        let mut ctx = Context::new();
-        assert_eq!(eval_str_ctx("a = 2", &mut ctx).unwrap(), 2.0);
-        assert_eq!(eval_str_ctx("b = a + 1", &mut ctx).unwrap(), 3.0);
-        assert_eq!(eval_str_ctx("c = a + b", &mut ctx).unwrap(), 5.0);
-        assert_eq!(ctx.get_var("a"), Some(2.0));
-        assert_eq!(ctx.get_var("b"), Some(3.0));
-        assert_eq!(ctx.get_var("c"), Some(5.0));
-
-        assert!(eval_str("not_defined").is_err());
-
-        let mut ctx = Context::new();
-        assert_eq!(eval_str_ctx("some_longer_name = 2", &mut ctx).unwrap(), 2.0);
-        assert_eq!(ctx.get_var("some_longer_name"), Some(2.0));
-
-        assert!(eval_str("a b = 2").is_err());
-        assert!(eval_str("2 = 2").is_err());
-        assert!(eval_str("* = 2").is_err());
-        assert!(eval_str("() = 2").is_err());
+    fn test_introspection_commands() {
+        let mut session = Session::new();
+        let mut format = OutputFormat::Default;
+        let mut run = |command| run_command(command, &mut session, &mut format);
+        assert_eq!(run(":vars"), Ok(None));
+        assert_eq!(run(":funcs"), Ok(None));
+
+        let mut session = Session::new();
+        session
+            .eval("b = 2.5\na = 1\nconst c = 3\nfn sq(x) { x * x }\nfn add(x, y) { x + y }")
+            .unwrap();
+        let mut run = |command| run_command(command, &mut session, &mut format);
+        assert_eq!(run(":vars"), Ok(Some("a = 1\nans = 3\nb = 2.5".to_owned())));
+        assert_eq!(
+            run(":funcs"),
+            Ok(Some("add (2 arguments)\nsq (1 argument)".to_owned()))
+        );
+        for invalid in [":vars a", ":funcs 1", ":clear now"] {
+            assert!(run(invalid).is_err());
+        }
+
+        assert_eq!(run(":clear"), Ok(None));
+        assert_eq!(run(":vars"), Ok(None));
+        assert_eq!(run(":funcs"), Ok(None));
+        assert!(session.eval("sq(2)").is_err());
+        assert_eq!(session.eval("pi").unwrap(), std::f64::consts::PI);
+        assert_eq!(session.history().len(), 1);
     }
 
     #[test]
-    fn test_builtin_functions() {
-        use std::f64::consts;
-
-        let eps = 1e-10;
-        assert!((eval_str("sin(pi/2)").unwrap() - 1.0).abs() < eps);
-        assert!((eval_str("cos(pi/2)").unwrap() - 0.0).abs() < eps);
-        assert!((eval_str("tan(pi/4)").unwrap() - 1.0).abs() < eps);
-        assert!((eval_str("asin(1)").unwrap() - consts::FRAC_PI_2).abs() < eps);
-        assert!((eval_str("acos(1)").unwrap() - 0.0).abs() < eps);
-        assert!((eval_str("atan(1)").unwrap() - consts::FRAC_PI_4).abs() < eps);
-        assert!((eval_str("sinh(1)").unwrap() - 1_f64.sinh()).abs() < eps);
-        assert!((eval_str("cosh(1)").unwrap() - 1_f64.cosh()).abs() < eps);
-        assert!((eval_str("tanh(1)").unwrap() - 1_f64.tanh()).abs() < eps);
-
-        assert!((eval_str("ln(e)").unwrap() - 1.0).abs() < eps);
-        assert!((eval_str("log2(1024)").unwrap() - 10.0).abs() < eps);
-        assert!((eval_str("log10(1000)").unwrap() - 3.0).abs() < eps);
-        assert!((eval_str("log(27, 3)").unwrap() - 3.0).abs() < eps);
-
-        assert!((eval_str("abs(-1)").unwrap() - 1.0).abs() < eps);
-        assert!((eval_str("abs(1)").unwrap() - 1.0).abs() < eps);
-        assert!((eval_str("min(1, 5)").unwrap() - 1.0).abs() < eps);
-        assert!((eval_str("max(1, 5)").unwrap() - 5.0).abs() < eps);
-        assert!((eval_str("floor(1.5)").unwrap() - 1.0).abs() < eps);
-        assert!((eval_str("ceil(1.5)").unwrap() - 2.0).abs() < eps);
-        assert!((eval_str("round(1.5)").unwrap() - 2.0).abs() < eps);
-        assert!((eval_str("round(1.4)").unwrap() - 1.0).abs() < eps);
-        assert!((eval_str("round(1.6)").unwrap() - 2.0).abs() < eps);
-
-        assert!(eval_str("sqrt(-1)").is_err());
-        assert!((eval_str("sqrt(4)").unwrap() - 2.0).abs() < eps);
-        assert!((eval_str("exp(2)").unwrap() - 7.389056099).abs() < eps);
+    fn test_format_result() {
+        let format = OutputFormat::Fixed(1);
+        let mut session = Session::new();
+        let mut output = |src| format_result(&session.eval_detailed(src).unwrap(), format);
+        assert_eq!(output("1 + 1"), Some("2.0".to_owned()));
+        assert_eq!(output("a = 3"), Some("a = 3.0".to_owned()));
+        assert_eq!(output("fn f(x) { x }"), None);
+        assert_eq!(output("print(1)"), None);
+        assert_eq!(output("a"), Some("3.0".to_owned()));
     }
 
     #[test]
-    fn test_functions() {
-        use crate::eval::Function;
-
-        let mut ctx = Context::new();
-        ctx.add_function(
-            "add",
-            Function::new_builtin(2, |_ctx, args| args[0] + args[1]),
-        )
-        .unwrap();
-
-        assert!(eval_str_ctx("add()", &mut ctx).is_err());
-        assert!(eval_str_ctx("add(1)", &mut ctx).is_err());
-        assert!(eval_str_ctx("add(1,)", &mut ctx).is_err());
-        assert!(eval_str_ctx("add(,1)", &mut ctx).is_err());
-        assert!(eval_str_ctx("add(1 1)", &mut ctx).is_err());
-        assert_eq!(eval_str_ctx("add(1, 2)", &mut ctx).unwrap(), 3.0);
-        assert!(eval_str_ctx("add(1, 2, 3)", &mut ctx).is_err());
-        assert_eq!(eval_str_ctx("add(1, add(2, 3))", &mut ctx).unwrap(), 6.0);
+    fn test_multiline_input() {
+        let mut session = Session::new();
+        let mut input = String::new();
+        let mut eval_lines = |lines: &[&str]| {
+            let (last, rest) = lines.split_last().unwrap();
+            for line in rest {
+                assert!(eval_repl_line(&mut session, &mut input, line).is_none());
+            }
+            eval_repl_line(&mut session, &mut input, last)
+                .expect("Input should be complete")
+                .map(|result| result.value())
+        };
+
+        assert_eq!(eval_lines(&["1 +", "2"]).unwrap(), 3.0);
+        assert_eq!(eval_lines(&["x =", "5"]).unwrap(), 5.0);
+        assert_eq!(eval_lines(&["x *=", "2 *", "x"]).unwrap(), 50.0);
+        assert_eq!(eval_lines(&["1 <", "2 &&", "x"]).unwrap(), 1.0);
+        assert_eq!(
+            eval_lines(&["fn f(a) {", "  y = a *", "    2", "  y", "}"]).unwrap(),
+            0.0
+        );
+        assert_eq!(eval_lines(&["f(3)"]).unwrap(), 6.0);
+        // Invalid input is reported right away and doesn't leave lines behind
+        assert!(eval_lines(&["1 + + 2"]).is_err());
+        assert_eq!(eval_lines(&["x"]).unwrap(), 50.0);
     }
 
     #[test]
-    fn test_multiple_lines() {
-        let mut ctx = Context::new();
-
-        let result = eval_str_ctx(
-            r"a = 2
-            b = 3
-            c = a + b",
-            &mut ctx,
+    fn test_csv_format() {
+        let input = "a,b\n1,2\n0.5,0.25\n";
+        let mut output = Vec::new();
+        let format = OutputFormat::Fixed(2);
+        map_csv(
+            "a + b",
+            &mut Context::new(),
+            input.as_bytes(),
+            &mut output,
+            |result| format_result(result, format),
         )
         .unwrap();
-
-        assert_eq!(result, 5.0);
-        assert_eq!(ctx.get_var("a"), Some(2.0));
-        assert_eq!(ctx.get_var("b"), Some(3.0));
-        assert_eq!(ctx.get_var("c"), Some(5.0));
-
-        assert_eq!(eval_str("\n42\n").unwrap(), 42.0);
-        assert_eq!(eval_str("42\n").unwrap(), 42.0);
-        assert_eq!(eval_str("\n42").unwrap(), 42.0);
-        assert_eq!(eval_str("\n\n\n").unwrap(), 0.0);
-    }
-
-    #[test]
-    fn test_newlines_not_allowed() {
-        assert!(eval_str("1 + \n 2").is_err());
-        assert!(eval_str("sin(pi\n/2)").is_err());
-        assert!(eval_str("sin(\npi/2)").is_err());
-        assert!(eval_str("1 * (2 + \n 3)").is_err());
-        assert!(eval_str("a = \n2").is_err());
+        assert_eq!(String::from_utf8(output).unwrap(), "3.00\n0.75\n");
     }
 
     #[test]
-    fn test_user_functions() {
-        let code = "\
-            fn add(a, b, c) {\n\
-                a + b + c\n\
-            }\n\
-            \n\
-            fn sub(a, b) {\n\
-                a - b\n\
-            }\n\
-            \n\
-            sub(42, add(1, 2, 3))";
-        assert_eq!(eval_str(code).unwrap(), 36.0);
-
-        assert!(eval_str("fn add(a, {b) a + b }").is_err());
-        assert!(eval_str("fn empty_body() {}").is_ok());
-        assert!(eval_str("fn no_args() {\n inspect(1)\n }").is_ok());
-        assert!(eval_str("fn one_liner(a, b) { a + b }").is_ok());
-        assert!(eval_str("fn trailing_comma(a, b,) { a + b }").is_err());
-        assert!(eval_str("fn leading_comma(, a, b) { a + b }").is_err());
-        assert!(eval_str("fn no_comma(a b) { a + b }").is_err());
-        assert!(eval_str("fn contains_expression(a, b, 1 + 1) { a + b }").is_err());
-        assert!(eval_str("fn duplicate_arg_name(a, a) { a + a }").is_err());
-    }
-
-    #[test]
-    fn test_if_statements() {
-        let code = "\
-            a = 0
-            if (0) {
-                a = 2
-            }
-            a";
-        assert_eq!(eval_str(code).unwrap(), 0.0);
-
-        let code = "\
-            a = 0
-            if (1) {
-                a = 2
-            }
-            a";
-        assert_eq!(eval_str(code).unwrap(), 2.0);
-
-        let code = "\
-            a = 0
-            if (0) {
-                a = 2
-            } else {
-                a = 3
-            }
-            a";
-        assert_eq!(eval_str(code).unwrap(), 3.0);
-
-        let code = "\
-            a = 0
-            if (1) {
-                a = 2
-            } else {
-                a = 3
-            }
-            a";
-        assert_eq!(eval_str(code).unwrap(), 2.0);
-    }
-
-    #[test]
-    fn test_errors_on_missing_newline() {
-        assert!(eval_str("1 + 1 2 + 2").is_err());
-        assert!(eval_str("1 2").is_err());
-        assert!(eval_str("(1 * 3) 2").is_err());
-
-        assert!(eval_str("fn add(a, b) { a + b } fn sub(a, b) { a - b }").is_err());
-        assert!(eval_str("if (1){ 1 } if (2){ 2 }").is_err());
+    fn test_edit_command() {
+        let mut session = Session::new();
+        let mut format = OutputFormat::Default;
+        session.eval("fn f(x) {\n  y = x*(2 + 1)\n  y\n}").unwrap();
+
+        let source = run_command(":edit f", &mut session, &mut format).unwrap();
+        assert_eq!(
+            source.as_deref(),
+            Some("fn f(x) {\n    y = x * (2 + 1)\n    y\n}")
+        );
+        // The function is only printed, not removed
+        assert_eq!(session.eval("f(2)").unwrap(), 6.0);
+
+        for (command, error) in [
+            (
+                ":edit sqrt",
+                "Function 'sqrt' is built in and can't be edited",
+            ),
+            (":edit g", "Function with name 'g' is not defined"),
+            (":edit", "Usage: :edit <function>"),
+            (":edit f g", "Usage: :edit <function>"),
+        ] {
+            assert_eq!(
+                run_command(command, &mut session, &mut format),
+                Err(error.to_owned())
+            );
+        }
+        assert_eq!(session.eval("sqrt(4)").unwrap(), 2.0);
     }
 }
@@ -0,0 +1,40 @@
+use crate::{error::CalcError, parser::parse, parser::AST, tokenizer::tokenize};
+
+/// Simple size and complexity metrics of a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    /// Number of tokens, including newlines.
+    pub tokens: usize,
+    /// Number of nodes in the AST, including the `Lines` node at the root.
+    pub ast_nodes: usize,
+    /// Length of the longest path from the root of the AST to a leaf, counted in nodes.
+    pub max_depth: usize,
+    /// Number of function definitions, including nested ones.
+    pub function_definitions: usize,
+}
+
+/// Tokenizes and parses `src` and computes its metrics. Nothing is evaluated.
+pub fn complexity(src: &str) -> Result<Metrics, CalcError> {
+    let tokens = tokenize(src)?;
+    let ast = parse(&tokens)?;
+
+    let mut metrics = Metrics {
+        tokens: tokens.len(),
+        ast_nodes: 0,
+        max_depth: 0,
+        function_definitions: 0,
+    };
+    walk(&ast, 1, &mut metrics);
+    Ok(metrics)
+}
+
+fn walk(ast: &AST, depth: usize, metrics: &mut Metrics) {
+    metrics.ast_nodes += 1;
+    metrics.max_depth = metrics.max_depth.max(depth);
+    if let AST::FunctionDefinition { .. } = ast {
+        metrics.function_definitions += 1;
+    }
+    for child in ast.children() {
+        walk(child, depth + 1, metrics);
+    }
+}
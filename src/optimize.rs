@@ -0,0 +1,82 @@
+//! Optimizations of the AST that don't change the result of evaluating it.
+
+use crate::{
+    eval::{evaluate, Context, Number},
+    parser::AST,
+    tokenizer::number_value,
+};
+
+/// Replaces subexpressions that only consist of numbers and operators with their value, so they
+/// aren't calculated again on every evaluation (e.g. in a function body that is called in a
+/// loop).
+///
+/// The tree is folded bottom-up, so `2 + 3 * 4` becomes the number `14`. Negative values are
+/// stored as a unary minus of the absolute value, like the parser does for `-14`.
+///
+/// Variables, function calls and statements are left alone, but their operands are folded. `%`
+/// isn't folded either, since its result depends on the modulo mode of the context. Operations
+/// that fail (like a division by zero) are kept, so the error is reported when the tree is
+/// evaluated.
+pub fn fold_constants(mut ast: AST) -> AST {
+    let mut ctx = Context::new();
+    fold_in_place(&mut ast, &mut ctx);
+    ast
+}
+
+fn fold_in_place(ast: &mut AST, ctx: &mut Context) {
+    for child in ast.children_mut() {
+        fold_in_place(child, ctx);
+    }
+
+    let foldable = match ast {
+        AST::Add(..)
+        | AST::Subtract(..)
+        | AST::Multiply(..)
+        | AST::Divide(..)
+        | AST::IntDivide(..)
+        | AST::Power(..)
+        | AST::Factorial(_)
+        | AST::Not(_)
+        | AST::Comparison { .. }
+        | AST::And(..)
+        | AST::Or(..)
+        | AST::Brackets(_) => true,
+        // A unary minus of a number is already folded
+        AST::UnaryMinus(_) => !is_constant(ast),
+        // The value of a percentage depends on whether it is added to something, see
+        // `eval::evaluate_summand`
+        AST::Percent(_) => false,
+        _ => false,
+    };
+    if !foldable || !ast.children().into_iter().all(is_constant) {
+        return;
+    }
+    if let Ok(value) = evaluate(ast, ctx) {
+        *ast = constant(value);
+    }
+}
+
+fn is_constant(ast: &AST) -> bool {
+    constant_value(ast).is_some()
+}
+
+/// Returns the value of a number or a negated number.
+fn constant_value(ast: &AST) -> Option<Number> {
+    match ast {
+        AST::Number(literal) => number_value(literal).ok(),
+        AST::UnaryMinus(inner) => match inner.as_ref() {
+            AST::Number(literal) => number_value(literal).ok().map(|value| -value),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn constant(value: Number) -> AST {
+    let number = AST::Number(value.abs().to_string());
+    if value.is_sign_negative() {
+        AST::UnaryMinus(Box::new(number))
+    } else {
+        number
+    }
+}
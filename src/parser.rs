@@ -1,8 +1,9 @@
 use crate::{
-    error::ParseError,
-    tokenizer::{Keyword, Operator, Token},
+    error::{CalcError, ParseError},
+    tokenizer::{tokenize_with_spans, Comparator, Keyword, Operator, Syntax, Token},
 };
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 pub enum AST {
     Lines(Vec<AST>),
@@ -12,11 +13,40 @@ pub enum AST {
     Subtract(Box<AST>, Box<AST>),
     Multiply(Box<AST>, Box<AST>),
     Divide(Box<AST>, Box<AST>),
+    /// `a // b`, the quotient rounded towards negative infinity
+    IntDivide(Box<AST>, Box<AST>),
     Modulo(Box<AST>, Box<AST>),
     Power(Box<AST>, Box<AST>),
     UnaryMinus(Box<AST>),
+    /// `x!`, the factorial of `x`
+    Factorial(Box<AST>),
+    /// `x%`, which is `x / 100`, or `x` percent of the left hand side of `+` or `-` (only with
+    /// `Syntax::percentages`)
+    Percent(Box<AST>),
+    /// `!x`, which is 1 if `x` is false and 0 otherwise
+    Not(Box<AST>),
+    /// A chain of comparisons like `a < b <= c`, which is true if every adjacent pair satisfies
+    /// its comparator. Each operand is evaluated at most once.
+    Comparison {
+        first: Box<AST>,
+        rest: Vec<(Comparator, AST)>,
+    },
+    /// `a && b`, where `b` is only evaluated if `a` is true
+    And(Box<AST>, Box<AST>),
+    /// `a || b`, where `b` is only evaluated if `a` is false
+    Or(Box<AST>, Box<AST>),
     Brackets(Box<AST>),
     Assign(String, Box<AST>),
+    /// `const <name> = <value>`, which declares a variable that can't be assigned to anymore
+    ConstDeclaration(String, Box<AST>),
+    /// `bound <name> = <value> in [<lo>, <hi>]`, which declares a variable whose assignments are
+    /// clamped to the range
+    BoundDeclaration {
+        name: String,
+        value: Box<AST>,
+        lo: Box<AST>,
+        hi: Box<AST>,
+    },
     FunctionCall(String, Vec<AST>),
     FunctionDefinition {
         name: String,
@@ -28,58 +58,233 @@ pub enum AST {
         if_body: Box<AST>,
         else_body: Option<Box<AST>>,
     },
+    /// `while (<condition>) { <body> }`
+    WhileLoop {
+        condition: Box<AST>,
+        body: Box<AST>,
+    },
+    /// `return <value>`, which ends the current function call
+    Return(Box<AST>),
+}
+
+impl AST {
+    /// Returns the direct child nodes of this node in source order.
+    pub fn children(&self) -> Vec<&AST> {
+        match self {
+            AST::Lines(lines) => lines.iter().collect(),
+            AST::Number(_) | AST::Variable(_) => Vec::new(),
+            AST::Add(lhs, rhs)
+            | AST::Subtract(lhs, rhs)
+            | AST::Multiply(lhs, rhs)
+            | AST::Divide(lhs, rhs)
+            | AST::IntDivide(lhs, rhs)
+            | AST::Modulo(lhs, rhs)
+            | AST::Power(lhs, rhs)
+            | AST::And(lhs, rhs)
+            | AST::Or(lhs, rhs) => vec![lhs, rhs],
+            AST::UnaryMinus(inner)
+            | AST::Factorial(inner)
+            | AST::Percent(inner)
+            | AST::Not(inner)
+            | AST::Brackets(inner)
+            | AST::Assign(_, inner)
+            | AST::ConstDeclaration(_, inner)
+            | AST::Return(inner) => vec![inner],
+            AST::Comparison { first, rest } => std::iter::once(first.as_ref())
+                .chain(rest.iter().map(|(_, ast)| ast))
+                .collect(),
+            AST::BoundDeclaration { value, lo, hi, .. } => vec![value, lo, hi],
+            AST::FunctionCall(_, args) => args.iter().collect(),
+            AST::FunctionDefinition { body, .. } => vec![body],
+            AST::WhileLoop { condition, body } => vec![condition, body],
+            AST::IfStatement {
+                condition,
+                if_body,
+                else_body,
+            } => {
+                let mut children = vec![condition.as_ref(), if_body.as_ref()];
+                if let Some(else_body) = else_body {
+                    children.push(else_body);
+                }
+                children
+            }
+        }
+    }
+
+    /// Like `children`, but returns mutable references.
+    pub fn children_mut(&mut self) -> Vec<&mut AST> {
+        match self {
+            AST::Lines(lines) => lines.iter_mut().collect(),
+            AST::Number(_) | AST::Variable(_) => Vec::new(),
+            AST::Add(lhs, rhs)
+            | AST::Subtract(lhs, rhs)
+            | AST::Multiply(lhs, rhs)
+            | AST::Divide(lhs, rhs)
+            | AST::IntDivide(lhs, rhs)
+            | AST::Modulo(lhs, rhs)
+            | AST::Power(lhs, rhs)
+            | AST::And(lhs, rhs)
+            | AST::Or(lhs, rhs) => vec![lhs, rhs],
+            AST::UnaryMinus(inner)
+            | AST::Factorial(inner)
+            | AST::Percent(inner)
+            | AST::Not(inner)
+            | AST::Brackets(inner)
+            | AST::Assign(_, inner)
+            | AST::ConstDeclaration(_, inner)
+            | AST::Return(inner) => vec![inner],
+            AST::Comparison { first, rest } => std::iter::once(first.as_mut())
+                .chain(rest.iter_mut().map(|(_, ast)| ast))
+                .collect(),
+            AST::BoundDeclaration { value, lo, hi, .. } => vec![value, lo, hi],
+            AST::FunctionCall(_, args) => args.iter_mut().collect(),
+            AST::FunctionDefinition { body, .. } => vec![body],
+            AST::WhileLoop { condition, body } => vec![condition, body],
+            AST::IfStatement {
+                condition,
+                if_body,
+                else_body,
+            } => {
+                let mut children = vec![condition.as_mut(), if_body.as_mut()];
+                if let Some(else_body) = else_body {
+                    children.push(else_body);
+                }
+                children
+            }
+        }
+    }
 }
 
-/// Returns the precedence of the operator.
+/// Transforms `ast` by replacing nodes in a single pass.
+///
+/// `replace` is called for every node, starting at the root. If it returns a new node, that node
+/// takes the place of the old one and is not visited itself. Otherwise the children of the node
+/// are visited.
+///
+/// ## Example
+///
+/// Replacing every variable `x` with the number `5`:
+///
+/// ```
+/// use rust_calculator::{parser::{parse, rewrite, AST}, tokenizer::tokenize};
+///
+/// let ast = parse(&tokenize("x * (x + 1)").unwrap()).unwrap();
+/// let ast = rewrite(ast, |node| match node {
+///     AST::Variable(name) if name == "x" => Some(AST::Number("5".to_owned())),
+///     _ => None,
+/// });
+/// assert_eq!(ast.to_string(), "5 * (5 + 1)");
+/// ```
+pub fn rewrite<F>(mut ast: AST, mut replace: F) -> AST
+where
+    F: FnMut(&AST) -> Option<AST>,
+{
+    rewrite_in_place(&mut ast, &mut replace);
+    ast
+}
+
+fn rewrite_in_place<F>(ast: &mut AST, replace: &mut F)
+where
+    F: FnMut(&AST) -> Option<AST>,
+{
+    match replace(ast) {
+        Some(replacement) => *ast = replacement,
+        None => {
+            for child in ast.children_mut() {
+                rewrite_in_place(child, replace);
+            }
+        }
+    }
+}
+
+/// Returns the precedence of the operator when it is used as a binary operator.
 ///
 /// Higher precedence means that the operator is calculated first (e.g. multiplication has higher
 /// precedence than addition).
-/// `is_binary` provides information about the operator being used as a
-/// unary or binary operator (i.e. if `is_binary` is false, the operator is unary).
-fn op_precedence(op: Operator, is_binary: bool) -> u8 {
-    match (op, is_binary) {
-        (Operator::Plus | Operator::Minus, true) => 1,
-        (Operator::Star | Operator::Slash | Operator::Percent, true) => 2,
-        (Operator::Caret, true) => 3,
-        (Operator::Minus, false) => 4,
-        _ => {
-            let op_kind = if is_binary { "binary" } else { "unary" };
-            panic!("'{:?}' cannot be used as a {} operator", op, op_kind);
-        }
+fn op_precedence(op: Operator) -> u8 {
+    match op {
+        Operator::Plus | Operator::Minus => 1,
+        Operator::Star | Operator::Slash | Operator::SlashSlash | Operator::Percent => 2,
+        Operator::Caret => 3,
     }
 }
 
-fn combine_lhs_rhs(op: Operator, lhs: AST, rhs: AST) -> Result<AST, ParseError> {
-    let combined = match op {
-        Operator::Plus => AST::Add(Box::new(lhs), Box::new(rhs)),
-        Operator::Minus => AST::Subtract(Box::new(lhs), Box::new(rhs)),
-        Operator::Star => AST::Multiply(Box::new(lhs), Box::new(rhs)),
-        Operator::Slash => AST::Divide(Box::new(lhs), Box::new(rhs)),
-        Operator::Percent => AST::Modulo(Box::new(lhs), Box::new(rhs)),
-        Operator::Caret => AST::Power(Box::new(lhs), Box::new(rhs)),
-    };
-    Ok(combined)
+/// Precedence of the unary minus and `!`, which bind stronger than all binary operators.
+const UNARY_PRECEDENCE: u8 = 4;
+
+fn combine_lhs_rhs(op: Operator, lhs: AST, rhs: AST) -> AST {
+    let (lhs, rhs) = (Box::new(lhs), Box::new(rhs));
+    match op {
+        Operator::Plus => AST::Add(lhs, rhs),
+        Operator::Minus => AST::Subtract(lhs, rhs),
+        Operator::Star => AST::Multiply(lhs, rhs),
+        Operator::Slash => AST::Divide(lhs, rhs),
+        Operator::SlashSlash => AST::IntDivide(lhs, rhs),
+        Operator::Percent => AST::Modulo(lhs, rhs),
+        Operator::Caret => AST::Power(lhs, rhs),
+    }
 }
 
 pub fn parse(tokens: &[Token]) -> Result<AST, ParseError> {
-    Parser::new(tokens).parse()
+    parse_located(tokens).map_err(|(err, _)| err)
+}
+
+/// Like `parse`, but also returns the index of the token at which the error occurred. The index is
+/// the number of tokens if the input ended too early.
+pub fn parse_located(tokens: &[Token]) -> Result<AST, (ParseError, usize)> {
+    let mut parser = Parser::new(tokens);
+    parser
+        .parse()
+        .map_err(|err| (err, parser.pos.min(tokens.len())))
+}
+
+/// Tokenizes and parses `s`, adding the location to parse errors.
+pub(crate) fn parse_str(s: &str, syntax: Syntax) -> Result<AST, CalcError> {
+    let (tokens, spans) = tokenize_with_spans(s, syntax)?;
+    parse_located(&tokens).map_err(|(error, idx)| {
+        // Errors at the end of the input are located after the last char
+        let offset = spans.get(idx).map_or(s.len(), |span| span.start);
+        CalcError::ParseError(error, Some(line_column(s, offset)))
+    })
+}
+
+/// Converts a byte offset in `s` to a 1-based line and a 1-based column, which counts chars.
+fn line_column(s: &str, offset: usize) -> (usize, usize) {
+    let before = &s[..offset];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
 }
 
 struct Parser<'a> {
     tokens: &'a [Token],
     pos: usize,
+    /// Number of parentheses the current position is in. Newlines are ignored inside of
+    /// parentheses, so expressions and argument lists can span multiple lines.
+    paren_depth: usize,
 }
 
-// TODO: Allow newlines in more places (e.g. argument list of function definition)
-// TODO: After that, allow optional comma at the end of argument lists
 impl<'a> Parser<'a> {
     fn new(tokens: &'a [Token]) -> Self {
-        Parser { tokens, pos: 0 }
+        Parser {
+            tokens,
+            pos: 0,
+            paren_depth: 0,
+        }
     }
 
     /// Entrypoint to the parser
     fn parse(&mut self) -> Result<AST, ParseError> {
-        let ast = self.parse_block()?;
+        let ast = match self.parse_block() {
+            Ok(ast) => ast,
+            // Functions only fail at the current position, so if it is at the end, more tokens
+            // could have made the input valid
+            Err(err) if self.pos >= self.tokens.len() => {
+                return Err(ParseError::Incomplete(Box::new(err)))
+            }
+            Err(err) => return Err(err),
+        };
         // If some function stopped parsing for some reason and we haven't parsed all tokens, the
         // token at the current position is unexpected.
         //
@@ -105,9 +310,25 @@ impl<'a> Parser<'a> {
                 _ if want_newline_this_iteration => {
                     return Err(ParseError::ExpectedToken(Token::Newline));
                 }
+                Token::Keyword(keyword) if self.peek_nth_is_assignment(2) => {
+                    return Err(ParseError::KeywordAsName(keyword.clone()));
+                }
                 Token::Keyword(Keyword::Fn) => (Some(self.parse_function_definition()?), true),
-                Token::Keyword(Keyword::If) => (Some(self.parse_if_statement()?), true),
-                Token::Identifier(_) if self.peek_nth(2) == Some(&Token::Equal) => {
+                Token::Keyword(Keyword::If | Keyword::Unless) => {
+                    (Some(self.parse_if_statement()?), true)
+                }
+                Token::Keyword(Keyword::While) => (Some(self.parse_while_loop()?), true),
+                Token::Keyword(Keyword::Return) => (Some(self.parse_return()?), true),
+                Token::Keyword(Keyword::Const) => (Some(self.parse_const_declaration()?), true),
+                // `bound` and `in` are only keywords in a bound declaration, so they can still be
+                // used as names
+                Token::Identifier(word)
+                    if word == "bound"
+                        && matches!(self.peek_nth(2), Some(Token::Identifier(_))) =>
+                {
+                    (Some(self.parse_bound_declaration()?), true)
+                }
+                Token::Identifier(_) if self.peek_nth_is_assignment(2) => {
                     (Some(self.parse_assignment()?), true)
                 }
                 _ => (Some(self.parse_expression()?), true),
@@ -122,84 +343,167 @@ impl<'a> Parser<'a> {
         Ok(AST::Lines(lines))
     }
 
-    /// Parses an expression.
+    /// Parses an expression, which may combine operands with the logical operators `&&` and `||`.
     ///
-    /// This works by calling another function that attaches expressions with operators of higher
-    /// precedence to the right hand side of the current operator. Once there are no operators of
-    /// higher precedence, it reads the next operator and creates a new AST node. The currently
-    /// parsed AST becomes the left hand side of the new node and the right hand side is once again
-    /// determined by the other function.
+    /// `||` has a lower precedence than `&&`, which has a lower precedence than comparisons. Both
+    /// are left-associative.
     fn parse_expression(&mut self) -> Result<AST, ParseError> {
-        let mut lhs = self.parse_expression_with_min_precedence(0)?;
-        while let Some(Token::Operator(op)) = self.peek() {
-            let op = *op;
-            self.next();
-            let precedence = op_precedence(op, true);
-            let rhs = self.parse_expression_with_min_precedence(precedence + 1)?;
-            lhs = combine_lhs_rhs(op, lhs, rhs)?;
+        let mut lhs = self.parse_and_expression()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next_operator();
+            let rhs = self.parse_and_expression()?;
+            lhs = AST::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and_expression(&mut self) -> Result<AST, ParseError> {
+        let mut lhs = self.parse_comparison()?;
+        while self.peek() == Some(&Token::And) {
+            self.next_operator();
+            let rhs = self.parse_comparison()?;
+            lhs = AST::And(Box::new(lhs), Box::new(rhs));
         }
         Ok(lhs)
     }
 
-    /// Helper function for `parse_expression` that parses an expression that includes operators of
-    /// equal or higher precedence than `min_precedence`.
+    /// Parses an expression, which may be a chain of comparisons.
     ///
-    /// This function recursively calls itself to build up a chain of operators of increasing
-    /// precedence. The base case of the recursion is reached when the next operator has smaller or
-    /// equal precedence than the previous one. This will return the current chain.
+    /// Comparisons have a lower precedence than all arithmetic operators. A chain like `a < b < c`
+    /// means `a < b` and `b < c`, where `b` is only evaluated once. Comparators of different
+    /// directions may be mixed (`a < b > c`) and follow the same rule, as in Python.
+    fn parse_comparison(&mut self) -> Result<AST, ParseError> {
+        let first = self.parse_arithmetic_expression()?;
+        let mut rest = Vec::new();
+        while let Some(Token::Comparator(cmp)) = self.peek() {
+            let cmp = *cmp;
+            self.next_operator();
+            rest.push((cmp, self.parse_arithmetic_expression()?));
+        }
+
+        if rest.is_empty() {
+            Ok(first)
+        } else {
+            Ok(AST::Comparison {
+                first: Box::new(first),
+                rest,
+            })
+        }
+    }
+
+    /// Parses an expression consisting of arithmetic operators.
+    fn parse_arithmetic_expression(&mut self) -> Result<AST, ParseError> {
+        self.parse_expression_with_min_precedence(0)
+    }
+
+    /// Helper function for `parse_arithmetic_expression` that parses an expression that only
+    /// consists of operators of equal or higher precedence than `min_precedence`.
+    ///
+    /// After parsing an operand, operators are attached as long as their precedence is high
+    /// enough. The right hand side of each operator is parsed by recursively calling this function
+    /// with a higher minimum precedence, so it only takes the operators that bind stronger. Once
+    /// an operator with smaller precedence is reached, the current expression is returned and the
+    /// operator is left for the caller.
     ///
     /// ## Example
     ///
-    /// Calling the function with the input `1 + 2 * 3 ^ 4 + 5` would stop at the last `+` and
-    /// would produce the following AST:
+    /// Calling the function with the input `1 + 2 * 3 ^ 4 + 5` and a minimum precedence of 1
+    /// would first produce the following AST and then attach it as the left hand side of the last
+    /// `+`:
     ///
-    ///              +
-    ///            1   *
-    ///              2   ^
-    ///                3   4
+    /// ```text
+    ///      +
+    ///    1   *
+    ///      2   ^
+    ///        3   4
+    /// ```
     ///
     /// Or in another notation: Add(1, Multiply(2, Power(3, 4))
     fn parse_expression_with_min_precedence(
         &mut self,
         min_precedence: u8,
     ) -> Result<AST, ParseError> {
+        let mut lhs = self.parse_operand()?;
+        while let Some(op) = self.peek_binary_operator() {
+            let precedence = op_precedence(op);
+            if precedence < min_precedence {
+                break;
+            }
+            self.next_operator();
+            // `^` is right-associative, so the right hand side may contain further `^` operators
+            let rhs_min_precedence = if op == Operator::Caret {
+                precedence
+            } else {
+                precedence + 1
+            };
+            let rhs = self.parse_expression_with_min_precedence(rhs_min_precedence)?;
+            lhs = combine_lhs_rhs(op, lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    /// Parses a single operand of an arithmetic operator, including its unary operators and
+    /// factorials.
+    fn parse_operand(&mut self) -> Result<AST, ParseError> {
+        let mut operand = self.parse_prefixed_operand()?;
+        // `!` after an operand is the factorial, which binds stronger than any other operator,
+        // including a unary minus before the operand. So `-3!` means `-(3!)` and `-2^3!` means
+        // `(-2)^(3!)`, since the unary minus binds stronger than `^`. The same goes for
+        // percentages.
+        loop {
+            if self.peek() == Some(&Token::Not) {
+                operand = AST::Factorial(Box::new(operand));
+            } else if self.peek_is_percentage() {
+                operand = AST::Percent(Box::new(operand));
+            } else {
+                break;
+            }
+            self.next();
+        }
+        // Implicit multiplication like `2pi` or `(1 + 1)(2 + 2)`. The factor is parsed like the
+        // right hand side of `^`, so `2pi^2` means `2 * pi^2` and `1 / 2pi` means `1 / (2 * pi)`.
+        if self.peek_is_implicit_factor(&operand) {
+            let factor =
+                self.parse_expression_with_min_precedence(op_precedence(Operator::Caret))?;
+            operand = AST::Multiply(Box::new(operand), Box::new(factor));
+        }
+        Ok(operand)
+    }
+
+    fn parse_prefixed_operand(&mut self) -> Result<AST, ParseError> {
         match self.peek() {
             Some(Token::Operator(Operator::Minus)) => {
                 self.next();
-                let unary_minus_precedence = op_precedence(Operator::Minus, false);
                 // Not `+ 1` like in the other cases so we can take multiple unary minus operators
                 // after each other
-                let rhs = self.parse_expression_with_min_precedence(unary_minus_precedence)?;
+                let rhs = self.parse_expression_with_min_precedence(UNARY_PRECEDENCE)?;
                 Ok(AST::UnaryMinus(Box::new(rhs)))
             }
-            Some(Token::LParen) => {
+            // `!` binds as strongly as unary minus, so `!a && b` means `(!a) && b`
+            Some(Token::Not) => {
                 self.next();
+                let rhs = self.parse_expression_with_min_precedence(UNARY_PRECEDENCE)?;
+                Ok(AST::Not(Box::new(rhs)))
+            }
+            Some(Token::LParen) => {
+                self.expect_lparen()?;
                 let inner = self.parse_expression()?;
-                self.expect(Token::RParen)?;
+                self.expect_rparen()?;
                 Ok(AST::Brackets(Box::new(inner)))
             }
             Some(Token::Identifier(_) | Token::Number(_)) => {
-                if self.peek_nth(2) == Some(&Token::LParen) {
+                if self.peek_is_function_call() {
                     self.parse_function_call()
                 } else {
-                    let lhs = self.parse_identifier_or_value()?;
-                    match self.peek() {
-                        Some(Token::Operator(op)) => {
-                            let precedence = op_precedence(*op, true);
-                            if precedence >= min_precedence {
-                                let op = op.clone();
-                                self.next();
-                                let rhs =
-                                    self.parse_expression_with_min_precedence(precedence + 1)?;
-                                combine_lhs_rhs(op, lhs, rhs)
-                            } else {
-                                Ok(lhs)
-                            }
-                        }
-                        _ => Ok(lhs),
-                    }
+                    self.parse_identifier_or_value()
                 }
             }
+            // Where an operand is expected, `mod` can't be the operator, so `mod(a, b)` is a call
+            // of the builtin function
+            Some(Token::Keyword(Keyword::Mod)) if self.peek_nth(2) == Some(&Token::LParen) => {
+                self.parse_function_call()
+            }
+            Some(Token::Keyword(Keyword::Mod)) => Err(ParseError::KeywordAsName(Keyword::Mod)),
             Some(token) => Err(ParseError::UnexpectedToken(token.clone())),
             None => Err(ParseError::NoTokensLeft),
         }
@@ -215,55 +519,102 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_function_call(&mut self) -> Result<AST, ParseError> {
-        // <name>(<val1>, <val2>, ...)
-        let fn_name = self.expect_identifier()?.to_string();
-        self.expect(Token::LParen)?;
+        // <name>(<val1>, <val2>, ...[,])
+        let fn_name = match self.peek() {
+            Some(Token::Keyword(Keyword::Mod)) => {
+                self.next();
+                Keyword::Mod.as_str().to_owned()
+            }
+            _ => self.expect_identifier()?.to_string(),
+        };
+        self.expect_lparen()?;
         let mut args = Vec::new();
         while self.peek() != Some(&Token::RParen) {
             let arg = self.parse_expression()?;
             args.push(arg);
 
-            match self.peek() {
-                // TODO: Remove guard once trailing commas are allowed
-                Some(Token::Comma) if self.peek_nth(2) != Some(&Token::RParen) => {
-                    self.next();
-                }
+            // A comma may also follow the last argument, the loop stops at the closing bracket
+            if self.peek() == Some(&Token::Comma) {
+                self.next();
+            } else {
                 // Let `expect` after loop handle the error
-                _ => break,
+                break;
             }
         }
-        self.expect(Token::RParen)?;
+        self.expect_rparen()?;
         Ok(AST::FunctionCall(fn_name, args))
     }
 
     fn parse_assignment(&mut self) -> Result<AST, ParseError> {
         let var_name = self.expect_identifier()?.to_string();
+        match self.next() {
+            Some(Token::Equal) => {
+                self.skip_newlines();
+                let rhs = self.parse_expression()?;
+                Ok(AST::Assign(var_name, Box::new(rhs)))
+            }
+            // `x += y` is short for `x = x + y`, so it fails like `x + y` if `x` is not defined
+            Some(&Token::CompoundAssign(op)) => {
+                self.skip_newlines();
+                let rhs = self.parse_expression()?;
+                let value = combine_lhs_rhs(op, AST::Variable(var_name.clone()), rhs);
+                Ok(AST::Assign(var_name, Box::new(value)))
+            }
+            Some(token) => Err(ParseError::UnexpectedToken(token.clone())),
+            None => Err(ParseError::NoTokensLeft),
+        }
+    }
+
+    fn parse_const_declaration(&mut self) -> Result<AST, ParseError> {
+        // const <name> = <value>
+        self.expect(Token::Keyword(Keyword::Const))?;
+        let name = self.expect_identifier()?.to_string();
         self.expect(Token::Equal)?;
-        let rhs = self.parse_expression()?;
-        Ok(AST::Assign(var_name, Box::new(rhs)))
+        self.skip_newlines();
+        let value = self.parse_expression()?;
+        Ok(AST::ConstDeclaration(name, Box::new(value)))
+    }
+
+    fn parse_bound_declaration(&mut self) -> Result<AST, ParseError> {
+        // bound <name> = <value> in [<lo>, <hi>]
+        self.expect(Token::Identifier("bound".to_owned()))?;
+        let name = self.expect_identifier()?.to_string();
+        self.expect(Token::Equal)?;
+        self.skip_newlines();
+        let value = self.parse_expression()?;
+        self.expect(Token::Identifier("in".to_owned()))?;
+        self.expect(Token::LBracket)?;
+        let lo = self.parse_expression()?;
+        self.expect(Token::Comma)?;
+        let hi = self.parse_expression()?;
+        self.expect(Token::RBracket)?;
+        Ok(AST::BoundDeclaration {
+            name,
+            value: Box::new(value),
+            lo: Box::new(lo),
+            hi: Box::new(hi),
+        })
     }
 
     fn parse_function_definition(&mut self) -> Result<AST, ParseError> {
-        // fn <name> (<arg1>, <arg2>, ...) { <body> }
+        // fn <name> (<arg1>, <arg2>, ...[,]) { <body> }
         self.expect(Token::Keyword(Keyword::Fn))?;
         let fn_name = self.expect_identifier()?.to_string();
-        self.expect(Token::LParen)?;
+        self.expect_lparen()?;
 
         let mut arg_names = Vec::new();
-        while let Some(Token::Identifier(arg_name)) = self.peek() {
-            arg_names.push(arg_name.to_string());
-            self.next();
+        while let Some(Token::Identifier(_) | Token::Keyword(_)) = self.peek() {
+            let arg_name = self.expect_identifier()?.to_string();
+            arg_names.push(arg_name);
 
-            match self.peek() {
-                // TODO: Remove guard when trailing commas are allowed
-                Some(Token::Comma) if self.peek_nth(2) != Some(&Token::RParen) => {
-                    self.next();
-                }
-                _ => break,
+            if self.peek() == Some(&Token::Comma) {
+                self.next();
+            } else {
+                break;
             }
         }
 
-        self.expect(Token::RParen)?;
+        self.expect_rparen()?;
         self.skip_newlines();
         self.expect(Token::LBrace)?;
         let body = self.parse_block()?;
@@ -276,19 +627,32 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_if_statement(&mut self) -> Result<AST, ParseError> {
-        // if ( <expr> ) { <body> } [ else { <body> } ]
+        // if ( <expr> ) { <body> } [ else { <body> } | else <if statement> | elif <...> ]
+        //
+        // `unless` is parsed as an if statement with the negated condition
+        if self.peek() == Some(&Token::Keyword(Keyword::Unless)) {
+            self.next();
+            return self.parse_if_statement_after_keyword(true);
+        }
         self.expect(Token::Keyword(Keyword::If))?;
-        self.expect(Token::LParen)?;
-        let condition = self.parse_expression()?;
-        self.expect(Token::RParen)?;
+        self.parse_if_statement_after_keyword(false)
+    }
+
+    /// Parses the rest of an if statement, whose `if`, `unless` or `elif` keyword was already
+    /// consumed.
+    fn parse_if_statement_after_keyword(&mut self, negated: bool) -> Result<AST, ParseError> {
+        self.expect_lparen()?;
+        let mut condition = self.parse_expression()?;
+        if negated {
+            condition = AST::Not(Box::new(condition));
+        }
+        self.expect_rparen()?;
         self.skip_newlines();
         self.expect(Token::LBrace)?;
         let if_body = self.parse_block()?;
         self.skip_newlines();
         self.expect(Token::RBrace)?;
 
-        // TODO: Add support for `else if`
-
         // We can't just skip newlines unconditionally here, since a newline is required after the
         // if statement and there may not be an else
         let mut peek_idx = 1;
@@ -296,17 +660,34 @@ impl<'a> Parser<'a> {
             peek_idx += 1;
         }
 
-        let else_body = if self.peek_nth(peek_idx) == Some(&Token::Keyword(Keyword::Else)) {
-            self.skip_newlines();
-            self.next();
-            self.skip_newlines();
-            self.expect(Token::LBrace)?;
-            let else_body = self.parse_block()?;
-            self.skip_newlines();
-            self.expect(Token::RBrace)?;
-            Some(Box::new(else_body))
-        } else {
-            None
+        // `else if` and `elif` are parsed as an else branch that only contains the nested if
+        // statement
+        let else_body = match self.peek_nth(peek_idx) {
+            Some(Token::Keyword(Keyword::Else)) => {
+                self.skip_newlines();
+                self.next();
+                self.skip_newlines();
+                if matches!(
+                    self.peek(),
+                    Some(Token::Keyword(Keyword::If | Keyword::Unless))
+                ) {
+                    let else_if = self.parse_if_statement()?;
+                    Some(Box::new(AST::Lines(vec![else_if])))
+                } else {
+                    self.expect(Token::LBrace)?;
+                    let else_body = self.parse_block()?;
+                    self.skip_newlines();
+                    self.expect(Token::RBrace)?;
+                    Some(Box::new(else_body))
+                }
+            }
+            Some(Token::Keyword(Keyword::Elif)) => {
+                self.skip_newlines();
+                self.next();
+                let elif = self.parse_if_statement_after_keyword(false)?;
+                Some(Box::new(AST::Lines(vec![elif])))
+            }
+            _ => None,
         };
 
         Ok(AST::IfStatement {
@@ -316,18 +697,66 @@ impl<'a> Parser<'a> {
         })
     }
 
+    fn parse_while_loop(&mut self) -> Result<AST, ParseError> {
+        // while ( <expr> ) { <body> }
+        self.expect(Token::Keyword(Keyword::While))?;
+        self.expect_lparen()?;
+        let condition = self.parse_expression()?;
+        self.expect_rparen()?;
+        self.skip_newlines();
+        self.expect(Token::LBrace)?;
+        let body = self.parse_block()?;
+        self.skip_newlines();
+        self.expect(Token::RBrace)?;
+        Ok(AST::WhileLoop {
+            condition: Box::new(condition),
+            body: Box::new(body),
+        })
+    }
+
+    fn parse_return(&mut self) -> Result<AST, ParseError> {
+        // return <value>
+        self.expect(Token::Keyword(Keyword::Return))?;
+        let value = self.parse_expression()?;
+        Ok(AST::Return(Box::new(value)))
+    }
+
     /// Takes the next token, behaving like `next` of an iterator.
     fn next(&mut self) -> Option<&Token> {
+        self.skip_ignored_newlines();
         let token = self.tokens.get(self.pos);
         self.pos += 1;
         token
     }
 
+    /// Takes the binary operator that `peek` returned. A line can't end with an operator, so the
+    /// right hand side may continue on the next line, like after `=` in an assignment.
+    fn next_operator(&mut self) {
+        self.next();
+        self.skip_newlines();
+    }
+
     /// Peeks the nth token.
     ///
     /// Peek with n = 1 behaves like `peek` of an iterator, peeking the next available token.
+    /// Inside of parentheses, newlines are skipped.
     fn peek_nth(&self, n: usize) -> Option<&Token> {
-        self.tokens.get(self.pos + n - 1)
+        if self.paren_depth == 0 {
+            return self.tokens.get(self.pos + n - 1);
+        }
+        self.tokens
+            .iter()
+            .skip(self.pos)
+            .filter(|token| **token != Token::Newline)
+            .nth(n - 1)
+    }
+
+    /// Checks whether the `n`th next token is `=` or a compound assignment like `+=`.
+    fn peek_nth_is_assignment(&self, n: usize) -> bool {
+        matches!(
+            self.peek_nth(n),
+            Some(Token::Equal | Token::CompoundAssign(_))
+        )
     }
 
     /// Peeks the next token, behaving like `peek` of an iterator.
@@ -335,20 +764,94 @@ impl<'a> Parser<'a> {
         self.peek_nth(1)
     }
 
+    /// Checks whether the next tokens start a function call, i.e. an identifier that is directly
+    /// followed by `(`.
+    ///
+    /// Whitespace is not a token, so `foo (1)` is a call just like `foo(1)`. A newline between the
+    /// name and the bracket ends the line though, so `foo` followed by `(1)` on the next line is a
+    /// variable and a separate bracket expression. Inside of parentheses, where newlines are
+    /// ignored, it is a call again.
+    fn peek_is_function_call(&self) -> bool {
+        matches!(self.peek(), Some(Token::Identifier(_)))
+            && self.peek_nth(2) == Some(&Token::LParen)
+    }
+
+    /// Checks whether the next token starts a factor that is implicitly multiplied with `lhs`,
+    /// i.e. a name or bracket after a number, or a bracket after a bracket or a function call.
+    fn peek_is_implicit_factor(&self, lhs: &AST) -> bool {
+        match (lhs, self.peek()) {
+            // `in` continues a bound declaration like `bound x = 1 in [0, 2]`
+            (AST::Number(_), Some(Token::Identifier(name))) => name != "in",
+            (AST::Number(_) | AST::Brackets(_) | AST::FunctionCall(..), Some(Token::LParen)) => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks whether the next token is a `%` that means a percentage, which is only possible with
+    /// `Syntax::percentages`.
+    ///
+    /// `%` after an operand is the modulo operator if the token after it starts another operand,
+    /// i.e. a number, a name, `(` or `!`. Otherwise it is a percentage. Since `-` is taken as a
+    /// binary operator here, `50% - 1` subtracts one from the percentage instead of calculating
+    /// `50 % (-1)`.
+    fn peek_is_percentage(&self) -> bool {
+        self.peek() == Some(&Token::Percent)
+            && !matches!(
+                self.peek_nth(2),
+                Some(Token::Number(_) | Token::Identifier(_) | Token::LParen | Token::Not)
+            )
+    }
+
+    /// Peeks the next token if it is a binary operator, returning the operator. The keyword `mod`
+    /// is the same operator as `%`.
+    fn peek_binary_operator(&self) -> Option<Operator> {
+        match self.peek() {
+            Some(Token::Operator(op)) => Some(*op),
+            Some(Token::Keyword(Keyword::Mod)) => Some(Operator::Percent),
+            // `parse_operand` already took the `%` if it is a percentage
+            Some(Token::Percent) => Some(Operator::Percent),
+            _ => None,
+        }
+    }
+
     /// Asserts that `expected` is the next token, while also advancing the position.
+    ///
+    /// On failure, the position is not advanced, so the position points to the unexpected token.
     fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
-        let actual = self.next();
-        if actual != Some(&expected) {
+        if self.peek() != Some(&expected) {
             return Err(ParseError::ExpectedToken(expected));
         }
+        self.next();
+        Ok(())
+    }
+
+    /// Asserts that the next token is `(`, which starts a part where newlines are ignored.
+    fn expect_lparen(&mut self) -> Result<(), ParseError> {
+        self.expect(Token::LParen)?;
+        self.paren_depth += 1;
+        Ok(())
+    }
+
+    /// Asserts that the next token is the `)` that ends the part started by `expect_lparen`.
+    fn expect_rparen(&mut self) -> Result<(), ParseError> {
+        self.expect(Token::RParen)?;
+        self.paren_depth -= 1;
         Ok(())
     }
 
     /// Asserts that the next token is an identifier, returning the inner string slice of the
     /// identifier and advancing the position.
-    fn expect_identifier(&mut self) -> Result<&str, ParseError> {
-        match self.next() {
-            Some(Token::Identifier(ref name)) => Ok(name),
+    fn expect_identifier(&mut self) -> Result<&'a str, ParseError> {
+        self.skip_ignored_newlines();
+        let tokens = self.tokens;
+        match tokens.get(self.pos) {
+            Some(Token::Identifier(name)) => {
+                self.pos += 1;
+                Ok(name)
+            }
+            Some(Token::Keyword(keyword)) => Err(ParseError::KeywordAsName(keyword.clone())),
             _ => Err(ParseError::ExpectedIdentifier),
         }
     }
@@ -359,4 +862,14 @@ impl<'a> Parser<'a> {
             self.next();
         }
     }
+
+    /// Advances the position past newlines if they are ignored, i.e. inside of parentheses.
+    fn skip_ignored_newlines(&mut self) {
+        if self.paren_depth == 0 {
+            return;
+        }
+        while self.tokens.get(self.pos) == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
 }
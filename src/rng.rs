@@ -0,0 +1,46 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Small pseudo random number generator (xorshift64*).
+///
+/// This is not suitable for anything security related, but it is fast, has no global state and
+/// can be cloned, which gives every `Context` its own reproducible stream.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates a generator seeded from the current time.
+    pub fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self::with_seed(nanos)
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        // Run the seed through splitmix64, since xorshift gets stuck on a state of 0 and produces
+        // poor output for seeds with only few bits set.
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Self {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a uniformly distributed number in the range `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        // Use the upper 53 bits, which is exactly the precision of an f64 mantissa
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
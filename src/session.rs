@@ -0,0 +1,76 @@
+use crate::{
+    error::CalcError,
+    eval::{Context, EvalResult, Number},
+    eval_str_detailed,
+};
+
+/// Name of the variable that holds the result of the last successful evaluation of a session.
+pub const ANS_VAR: &str = "ans";
+
+/// A context together with the source of everything that was successfully evaluated in it.
+///
+/// After every successful evaluation that produces a value, the value is stored in the variable
+/// `ans` (see `ANS_VAR`), so the next input can refer to it. Inputs without a value, like a
+/// function definition or a comment, keep the previous `ans`.
+///
+/// Failed inputs are not recorded. Note that a failed input may still have changed the context
+/// before the error occurred (e.g. `a = 1` followed by a line with an error), which `replay` won't
+/// reproduce.
+#[derive(Default)]
+pub struct Session {
+    ctx: Context,
+    history: Vec<String>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Evaluates `src` and records it in the history and its value in `ans` if evaluation
+    /// succeeded.
+    pub fn eval(&mut self, src: &str) -> Result<Number, CalcError> {
+        self.eval_detailed(src).map(|result| result.value())
+    }
+
+    /// Like `eval`, but describes what the evaluation did, see `EvalResult`.
+    pub fn eval_detailed(&mut self, src: &str) -> Result<EvalResult, CalcError> {
+        let result = eval_str_detailed(src, &mut self.ctx)?;
+        self.history.push(src.to_owned());
+        if let EvalResult::Value(value) | EvalResult::Assigned(_, value) = result {
+            self.ctx.set_var(ANS_VAR, value);
+        }
+        Ok(result)
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.ctx
+    }
+
+    /// Changes made through the context directly are not recorded in the history.
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.ctx
+    }
+
+    /// Removes everything that was defined, see `Context::clear`, and forgets the history.
+    pub fn clear(&mut self) {
+        self.ctx.clear();
+        self.history.clear();
+    }
+
+    /// Inputs that were evaluated successfully, in the order of evaluation.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Evaluates the history again in a fresh context, returning the new session.
+    ///
+    /// Random numbers are only reproduced if the history seeds the generator.
+    pub fn replay(&self) -> Result<Session, CalcError> {
+        let mut session = Session::new();
+        for src in &self.history {
+            session.eval(src)?;
+        }
+        Ok(session)
+    }
+}
@@ -0,0 +1,57 @@
+use crate::eval::Number;
+
+/// Running statistics of a stream of numbers, which doesn't store the numbers themselves.
+///
+/// Mean and variance are updated with Welford's algorithm, which stays accurate for long streams
+/// where summing up the squares would lose precision.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    count: usize,
+    mean: Number,
+    /// Sum of the squared differences from the current mean
+    m2: Number,
+    min: Number,
+    max: Number,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, x: Number) {
+        if self.count == 0 {
+            self.min = x;
+            self.max = x;
+        } else {
+            self.min = self.min.min(x);
+            self.max = self.max.max(x);
+        }
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as Number;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns `None` if no number was pushed yet. The same applies to the other queries.
+    pub fn mean(&self) -> Option<Number> {
+        (self.count > 0).then_some(self.mean)
+    }
+
+    /// Population variance, i.e. the mean of the squared differences from the mean.
+    pub fn variance(&self) -> Option<Number> {
+        (self.count > 0).then(|| self.m2 / self.count as Number)
+    }
+
+    pub fn min(&self) -> Option<Number> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<Number> {
+        (self.count > 0).then_some(self.max)
+    }
+}
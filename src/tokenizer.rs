@@ -1,10 +1,40 @@
-use crate::error::TokenizeError;
+use std::{iter::Peekable, str::Chars};
+
+use crate::{
+    error::{CalcError, EvalError, TokenizeError},
+    eval::Number,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Keyword {
     Fn,
     If,
     Else,
+    /// Short for `else if`, only a keyword with `Syntax::python_compat`
+    Elif,
+    /// Word form of the `%` operator
+    Mod,
+    Const,
+    /// `unless (<cond>)` is short for `if (!(<cond>))`
+    Unless,
+    While,
+    Return,
+}
+
+impl Keyword {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Keyword::Fn => "fn",
+            Keyword::If => "if",
+            Keyword::Else => "else",
+            Keyword::Elif => "elif",
+            Keyword::Mod => "mod",
+            Keyword::Const => "const",
+            Keyword::Unless => "unless",
+            Keyword::While => "while",
+            Keyword::Return => "return",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -13,70 +43,237 @@ pub enum Operator {
     Minus,
     Star,
     Slash,
+    /// `//`, the floor division
+    SlashSlash,
     Percent,
     Caret,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparator {
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token {
     Keyword(Keyword),
     Identifier(String),
     Number(String),
     Operator(Operator),
+    Comparator(Comparator),
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+    /// `!`, the logical not before an operand and the factorial after it
+    Not,
     Comma,
     LParen,
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
     Equal,
+    /// `+=`, `-=`, `*=` or `/=`, which assigns the result of the operator to the variable
+    CompoundAssign(Operator),
+    /// `%` with `Syntax::percentages`, which is either a percentage or the modulo operator
+    /// depending on the tokens around it
+    Percent,
     Newline,
 }
 
+/// Optional extensions of the syntax.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Syntax {
+    /// Makes `elif` a keyword that can be used instead of `else if`, like in Python. Without it,
+    /// `elif` is a normal identifier.
+    pub python_compat: bool,
+    /// Makes `%` after an operand a percentage, unless another operand follows it. So `50%` is
+    /// `0.5` and `200 + 10%` is `220` like on a calculator, but `7 % 3` is still `1`. Without it,
+    /// `%` is always the modulo operator.
+    pub percentages: bool,
+}
+
+/// Byte offsets of the start and the end of a token in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Returns the text of the span in `src`, which must be the source the span was created from.
+    pub fn text(self, src: &str) -> &str {
+        &src[self.start..self.end]
+    }
+}
+
+/// A part of the source as returned by `tokenize_with_source`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourcePiece<'a> {
+    /// A token and the exact text it was scanned from, e.g. `1_000` for the number `1000`
+    Token { token: Token, text: &'a str },
+    /// Text between tokens that doesn't produce a token, i.e. whitespace and comments
+    Skipped(&'a str),
+}
+
+impl SourcePiece<'_> {
+    pub fn text(&self) -> &str {
+        match self {
+            SourcePiece::Token { text, .. } | SourcePiece::Skipped(text) => text,
+        }
+    }
+}
+
 pub fn tokenize(s: &str) -> Result<Vec<Token>, TokenizeError> {
+    tokenize_with_syntax(s, Syntax::default())
+}
+
+/// The chars of the source, which keeps track of the position for error messages.
+struct CharStream<'a> {
+    chars: Peekable<Chars<'a>>,
+    /// Byte offset of the next char
+    pos: usize,
+}
+
+impl<'a> CharStream<'a> {
+    fn new(s: &'a str) -> Self {
+        Self {
+            chars: s.chars().peekable(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    /// Returns the char `n` positions after the one that `peek` returns, without consuming
+    /// anything.
+    fn peek_after(&self, n: usize) -> Option<char> {
+        self.chars.clone().nth(n)
+    }
+
+    fn next_if(&mut self, func: impl FnOnce(&char) -> bool) -> Option<char> {
+        let c = self.chars.next_if(func)?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn next_if_eq(&mut self, expected: &char) -> Option<char> {
+        self.next_if(|c| c == expected)
+    }
+
+    /// Error for the char that was returned by the last call to `next`.
+    fn unexpected_last(&self, c: char) -> TokenizeError {
+        TokenizeError::UnexpectedChar {
+            ch: c,
+            pos: self.pos - c.len_utf8(),
+        }
+    }
+
+    /// Error for the char that `peek` returns.
+    fn unexpected_next(&self, c: char) -> TokenizeError {
+        TokenizeError::UnexpectedChar {
+            ch: c,
+            pos: self.pos,
+        }
+    }
+}
+
+impl Iterator for CharStream<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+}
+
+pub fn tokenize_with_syntax(s: &str, syntax: Syntax) -> Result<Vec<Token>, TokenizeError> {
+    tokenize_with_spans(s, syntax).map(|(tokens, _)| tokens)
+}
+
+/// Splits `s` into tokens and the text between them, so that concatenating the text of all pieces
+/// yields `s` again. This is meant for tools like formatters and highlighters that need the exact
+/// source.
+pub fn tokenize_with_source(
+    s: &str,
+    syntax: Syntax,
+) -> Result<Vec<SourcePiece<'_>>, TokenizeError> {
+    let (tokens, spans) = tokenize_with_spans(s, syntax)?;
+    let mut pieces = Vec::with_capacity(tokens.len() * 2 + 1);
+    let mut pos = 0;
+    for (token, span) in tokens.into_iter().zip(spans) {
+        if span.start > pos {
+            pieces.push(SourcePiece::Skipped(&s[pos..span.start]));
+        }
+        pieces.push(SourcePiece::Token {
+            token,
+            text: span.text(s),
+        });
+        pos = span.end;
+    }
+    if pos < s.len() {
+        pieces.push(SourcePiece::Skipped(&s[pos..]));
+    }
+    Ok(pieces)
+}
+
+/// Like `tokenize_with_syntax`, but also returns the span of every token, which is at the same
+/// index as the token.
+pub fn tokenize_with_spans(
+    s: &str,
+    syntax: Syntax,
+) -> Result<(Vec<Token>, Vec<Span>), TokenizeError> {
     let mut tokens = vec![];
+    let mut spans = vec![];
 
-    let mut chars = s.chars().peekable();
+    let mut chars = CharStream::new(s);
 
-    while let Some(c) = chars.next() {
+    loop {
+        let start = chars.pos;
+        let Some(c) = chars.next() else {
+            break;
+        };
         let token = match c {
+            '+' if chars.next_if_eq(&'=').is_some() => Token::CompoundAssign(Operator::Plus),
             '+' => Token::Operator(Operator::Plus),
+            '-' if chars.next_if_eq(&'=').is_some() => Token::CompoundAssign(Operator::Minus),
             '-' => Token::Operator(Operator::Minus),
+            '*' if chars.next_if_eq(&'=').is_some() => Token::CompoundAssign(Operator::Star),
             '*' => Token::Operator(Operator::Star),
+            '/' if chars.next_if_eq(&'=').is_some() => Token::CompoundAssign(Operator::Slash),
+            '/' if chars.next_if_eq(&'/').is_some() => Token::Operator(Operator::SlashSlash),
             '/' => Token::Operator(Operator::Slash),
             '^' => Token::Operator(Operator::Caret),
+            '%' if syntax.percentages => Token::Percent,
             '%' => Token::Operator(Operator::Percent),
             ',' => Token::Comma,
             '(' => Token::LParen,
             ')' => Token::RParen,
             '{' => Token::LBrace,
             '}' => Token::RBrace,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '<' if chars.next_if_eq(&'=').is_some() => Token::Comparator(Comparator::LessEqual),
+            '<' => Token::Comparator(Comparator::Less),
+            '>' if chars.next_if_eq(&'=').is_some() => Token::Comparator(Comparator::GreaterEqual),
+            '>' => Token::Comparator(Comparator::Greater),
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Comparator(Comparator::Equal),
             '=' => Token::Equal,
-            c @ ('0'..='9' | '.') => {
-                let mut has_dot = c == '.';
-
-                let mut num = String::new();
-                num.push(c);
-                while let Some(c) = chars.peek() {
-                    match c {
-                        '.' => {
-                            if has_dot {
-                                return Err(TokenizeError::UnexpectedChar('.'));
-                            }
-                            has_dot = true;
-                        }
-                        '0'..='9' => (),
-                        _ => break,
-                    }
-                    let c = chars.next().unwrap();
-                    num.push(c);
-                }
-
-                if num == "." {
-                    return Err(TokenizeError::UnexpectedChar('.'));
-                }
-
-                Token::Number(num)
-            }
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Comparator(Comparator::NotEqual),
+            '!' => Token::Not,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            c @ ('0'..='9' | '.') => Token::Number(scan_number(c, &mut chars)?),
             c @ ('a'..='z' | 'A'..='Z' | '_') => {
                 let mut ident = String::new();
                 ident.push(c);
@@ -94,16 +291,190 @@ pub fn tokenize(s: &str) -> Result<Vec<Token>, TokenizeError> {
                     "fn" => Token::Keyword(Keyword::Fn),
                     "if" => Token::Keyword(Keyword::If),
                     "else" => Token::Keyword(Keyword::Else),
+                    "elif" if syntax.python_compat => Token::Keyword(Keyword::Elif),
+                    "mod" => Token::Keyword(Keyword::Mod),
+                    "const" => Token::Keyword(Keyword::Const),
+                    "unless" => Token::Keyword(Keyword::Unless),
+                    "while" => Token::Keyword(Keyword::While),
+                    "return" => Token::Keyword(Keyword::Return),
                     _ => Token::Identifier(ident),
                 }
             }
+            // Line comment, the newline at its end is still a token
+            '#' => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
             // TODO: Account for \r\n
             '\n' => Token::Newline,
             c if c.is_ascii_whitespace() => continue,
-            c => return Err(TokenizeError::UnexpectedChar(c)),
+            c => return Err(chars.unexpected_last(c)),
         };
         tokens.push(token);
+        spans.push(Span {
+            start,
+            end: chars.pos,
+        });
+    }
+
+    Ok((tokens, spans))
+}
+
+/// Scans the rest of a number literal whose first char `first` was already consumed, returning
+/// the text of the literal.
+fn scan_number(first: char, chars: &mut CharStream) -> Result<String, TokenizeError> {
+    if first == '0' {
+        if let Some(prefix) = chars.next_if(|&c| radix_of_prefix(c).is_some()) {
+            return scan_prefixed_number(prefix, chars);
+        }
     }
 
-    Ok(tokens)
+    let mut has_dot = first == '.';
+
+    let mut num = String::new();
+    num.push(first);
+    while let Some(c) = chars.peek() {
+        match c {
+            '.' => {
+                if has_dot {
+                    return Err(chars.unexpected_next('.'));
+                }
+                has_dot = true;
+            }
+            '0'..='9' => (),
+            '_' => {
+                skip_digit_separator(&num, chars)?;
+                continue;
+            }
+            _ => break,
+        }
+        let c = chars.next().unwrap();
+        num.push(c);
+    }
+
+    if num == "." {
+        return Err(chars.unexpected_last('.'));
+    }
+
+    // Scientific notation: The sign is only consumed directly after the `e`, so something like
+    // `2-3` is never swallowed into a number. An `e` that is followed by a letter starts a name
+    // instead, which is multiplied implicitly like in `2exp(1)`.
+    let starts_name = matches!(chars.peek_after(1), Some('a'..='z' | 'A'..='Z'));
+    if matches!(chars.peek(), Some('e' | 'E')) && !starts_name {
+        num.push(chars.next().unwrap());
+        if let Some('+' | '-') = chars.peek() {
+            num.push(chars.next().unwrap());
+        }
+
+        let mut has_exponent_digits = false;
+        loop {
+            match chars.peek() {
+                Some(c @ '0'..='9') => {
+                    num.push(*c);
+                    chars.next();
+                    has_exponent_digits = true;
+                }
+                Some('_') => skip_digit_separator(&num, chars)?,
+                _ => break,
+            }
+        }
+
+        if !has_exponent_digits {
+            return Err(TokenizeError::InvalidNumber(num));
+        }
+        if let Some('.') = chars.peek() {
+            return Err(chars.unexpected_next('.'));
+        }
+    }
+
+    Ok(num)
+}
+
+/// Skips the `_` that `chars` peeks, which may only be used to separate two digits like in
+/// `1_000`. `num` is the literal up to the `_`. The separator is not part of the literal.
+fn skip_digit_separator(num: &str, chars: &mut CharStream) -> Result<(), TokenizeError> {
+    let err = chars.unexpected_next('_');
+    chars.next();
+    let follows_digit = num.ends_with(|c: char| c.is_ascii_digit());
+    if follows_digit && matches!(chars.peek(), Some('0'..='9')) {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+/// Returns the radix of integer literals whose `0` is followed by `prefix`, like 16 for `0x1F`.
+fn radix_of_prefix(prefix: char) -> Option<u32> {
+    match prefix {
+        'x' | 'X' => Some(16),
+        'o' | 'O' => Some(8),
+        'b' | 'B' => Some(2),
+        _ => None,
+    }
+}
+
+/// Scans the digits of a hexadecimal (`0x1F`), octal (`0o17`) or binary (`0b101`) literal, whose
+/// `0` and `prefix` were already consumed.
+///
+/// These literals are integers, so neither a dot nor an exponent is allowed. In particular, `e` is
+/// a hex digit, so `0x1e5` is the hex value 485 and not scientific notation.
+fn scan_prefixed_number(prefix: char, chars: &mut CharStream) -> Result<String, TokenizeError> {
+    let radix = radix_of_prefix(prefix).expect("Invalid number prefix");
+    let mut num = format!("0{}", prefix);
+    while let Some(c) = chars.next_if(|c| c.is_digit(radix)) {
+        num.push(c);
+    }
+
+    // Something like `0x1p3`, `0x1g` or `0b12` shouldn't be split into a number and an identifier
+    // or another number
+    if let Some(&c) = chars.peek() {
+        if c == '.' || c == '_' || c.is_ascii_alphanumeric() {
+            return Err(chars.unexpected_next(c));
+        }
+    }
+    if num.len() == 2 {
+        return Err(TokenizeError::InvalidNumber(num));
+    }
+    Ok(num)
+}
+
+/// Parses a single number literal with the exact grammar that is used for numbers in expressions.
+///
+/// Surrounding whitespace is not allowed and neither is a sign, since `-` is an operator.
+pub fn parse_number(s: &str) -> Result<Number, CalcError> {
+    let mut chars = CharStream::new(s);
+    let literal = match chars.next() {
+        Some(c @ ('0'..='9' | '.')) => scan_number(c, &mut chars)?,
+        Some(c) => return Err(chars.unexpected_last(c).into()),
+        None => return Err(TokenizeError::InvalidNumber(String::new()).into()),
+    };
+    if let Some(c) = chars.next() {
+        return Err(chars.unexpected_last(c).into());
+    }
+    Ok(number_value(&literal)?)
+}
+
+/// Converts the text of a `Token::Number` to its value.
+pub fn number_value(literal: &str) -> Result<Number, EvalError> {
+    let mut chars = literal.chars();
+    let radix = match (chars.next(), chars.next()) {
+        (Some('0'), Some(prefix)) => radix_of_prefix(prefix),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        // Values above 2^53 lose precision, just like large decimal literals
+        let value = chars.fold(0.0, |value, digit| {
+            value * radix as Number + digit.to_digit(radix).expect("Invalid digit") as Number
+        });
+        return if value.is_finite() {
+            Ok(value)
+        } else {
+            Err(EvalError::Overflow)
+        };
+    }
+
+    match literal.parse::<Number>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(EvalError::Overflow),
+    }
 }
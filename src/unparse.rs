@@ -0,0 +1,225 @@
+//! Renders an AST back to source code.
+//!
+//! The output only contains the brackets that are needed to keep the structure of the tree (plus
+//! the ones that were explicitly written, which are stored as `AST::Brackets`), so parsing the
+//! output again yields an equivalent tree.
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::{parser::AST, tokenizer::Comparator};
+
+const INDENT: &str = "    ";
+
+/// Binding strength of the node when it is used as an operand. Operands that bind weaker than
+/// their operator need brackets.
+fn precedence(ast: &AST) -> u8 {
+    match ast {
+        AST::Or(..) => 1,
+        AST::And(..) => 2,
+        AST::Comparison { .. } => 3,
+        AST::Add(..) | AST::Subtract(..) => 4,
+        AST::Multiply(..) | AST::Divide(..) | AST::IntDivide(..) | AST::Modulo(..) => 5,
+        AST::Power(..) => 6,
+        AST::UnaryMinus(_) | AST::Not(_) => 7,
+        AST::Factorial(_) | AST::Percent(_) => 8,
+        AST::Number(_) | AST::Variable(_) | AST::Brackets(_) | AST::FunctionCall(..) => 9,
+        // Statements can't be used as operands
+        AST::Lines(_)
+        | AST::Assign(..)
+        | AST::ConstDeclaration(..)
+        | AST::BoundDeclaration { .. }
+        | AST::FunctionDefinition { .. }
+        | AST::IfStatement { .. }
+        | AST::WhileLoop { .. }
+        | AST::Return(_) => 0,
+    }
+}
+
+fn comparator_symbol(cmp: Comparator) -> &'static str {
+    match cmp {
+        Comparator::Less => "<",
+        Comparator::Greater => ">",
+        Comparator::LessEqual => "<=",
+        Comparator::GreaterEqual => ">=",
+        Comparator::Equal => "==",
+        Comparator::NotEqual => "!=",
+    }
+}
+
+/// Writes `operand`, surrounded by brackets if it binds weaker than `min_precedence`.
+fn write_operand(f: &mut Formatter<'_>, operand: &AST, min_precedence: u8) -> fmt::Result {
+    if precedence(operand) < min_precedence {
+        write!(f, "(")?;
+        write_ast(f, operand, 0)?;
+        write!(f, ")")
+    } else {
+        write_ast(f, operand, 0)
+    }
+}
+
+/// Writes `{`, the lines of `body` indented by one level more than `indent` and `}`.
+fn write_block(f: &mut Formatter<'_>, body: &AST, indent: usize) -> fmt::Result {
+    writeln!(f, "{{")?;
+    match body {
+        AST::Lines(lines) => {
+            for line in lines {
+                write!(f, "{}", INDENT.repeat(indent + 1))?;
+                write_ast(f, line, indent + 1)?;
+                writeln!(f)?;
+            }
+        }
+        body => {
+            write!(f, "{}", INDENT.repeat(indent + 1))?;
+            write_ast(f, body, indent + 1)?;
+            writeln!(f)?;
+        }
+    }
+    write!(f, "{}}}", INDENT.repeat(indent))
+}
+
+fn write_ast(f: &mut Formatter<'_>, ast: &AST, indent: usize) -> fmt::Result {
+    let binary = |op, lhs, rhs| (op, lhs, rhs);
+    let (op, lhs, rhs) = match ast {
+        AST::Add(lhs, rhs) => binary("+", lhs, rhs),
+        AST::Subtract(lhs, rhs) => binary("-", lhs, rhs),
+        AST::Multiply(lhs, rhs) => binary("*", lhs, rhs),
+        AST::Divide(lhs, rhs) => binary("/", lhs, rhs),
+        AST::IntDivide(lhs, rhs) => binary("//", lhs, rhs),
+        AST::Modulo(lhs, rhs) => binary("%", lhs, rhs),
+        AST::Power(lhs, rhs) => binary("^", lhs, rhs),
+        AST::And(lhs, rhs) => binary("&&", lhs, rhs),
+        AST::Or(lhs, rhs) => binary("||", lhs, rhs),
+        AST::Lines(lines) => {
+            for (idx, line) in lines.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, "\n{}", INDENT.repeat(indent))?;
+                }
+                write_ast(f, line, indent)?;
+            }
+            return Ok(());
+        }
+        AST::Number(num) => return write!(f, "{}", num),
+        AST::Variable(name) => return write!(f, "{}", name),
+        AST::UnaryMinus(inner) => {
+            write!(f, "-")?;
+            return write_operand(f, inner, precedence(ast));
+        }
+        AST::Not(inner) => {
+            write!(f, "!")?;
+            return write_operand(f, inner, precedence(ast));
+        }
+        AST::Factorial(inner) => {
+            write_operand(f, inner, precedence(ast))?;
+            return write!(f, "!");
+        }
+        AST::Percent(inner) => {
+            write_operand(f, inner, precedence(ast))?;
+            return write!(f, "%");
+        }
+        AST::Comparison { first, rest } => {
+            // Nested comparisons need brackets, since they would otherwise extend the chain
+            write_operand(f, first, precedence(ast) + 1)?;
+            for (cmp, operand) in rest {
+                write!(f, " {} ", comparator_symbol(*cmp))?;
+                write_operand(f, operand, precedence(ast) + 1)?;
+            }
+            return Ok(());
+        }
+        AST::Brackets(inner) => {
+            write!(f, "(")?;
+            write_ast(f, inner, indent)?;
+            return write!(f, ")");
+        }
+        AST::Assign(name, rhs) => {
+            write!(f, "{} = ", name)?;
+            return write_ast(f, rhs, indent);
+        }
+        AST::ConstDeclaration(name, value) => {
+            write!(f, "const {} = ", name)?;
+            return write_ast(f, value, indent);
+        }
+        AST::BoundDeclaration {
+            name,
+            value,
+            lo,
+            hi,
+        } => {
+            write!(f, "bound {} = ", name)?;
+            write_ast(f, value, indent)?;
+            write!(f, " in [")?;
+            write_ast(f, lo, indent)?;
+            write!(f, ", ")?;
+            write_ast(f, hi, indent)?;
+            return write!(f, "]");
+        }
+        AST::FunctionCall(name, args) => {
+            write!(f, "{}(", name)?;
+            for (idx, arg) in args.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, ", ")?;
+                }
+                write_ast(f, arg, indent)?;
+            }
+            return write!(f, ")");
+        }
+        AST::FunctionDefinition {
+            name,
+            arg_names,
+            body,
+        } => {
+            write!(f, "fn {}({}) ", name, arg_names.join(", "))?;
+            return write_block(f, body, indent);
+        }
+        AST::IfStatement {
+            condition,
+            if_body,
+            else_body,
+        } => {
+            write!(f, "if (")?;
+            write_ast(f, condition, indent)?;
+            write!(f, ") ")?;
+            write_block(f, if_body, indent)?;
+            match else_body.as_deref() {
+                Some(AST::Lines(lines))
+                    if matches!(lines.as_slice(), [AST::IfStatement { .. }]) =>
+                {
+                    write!(f, " else ")?;
+                    write_ast(f, &lines[0], indent)?;
+                }
+                Some(else_body) => {
+                    write!(f, " else ")?;
+                    write_block(f, else_body, indent)?;
+                }
+                None => (),
+            }
+            return Ok(());
+        }
+        AST::Return(value) => {
+            write!(f, "return ")?;
+            return write_ast(f, value, indent);
+        }
+        AST::WhileLoop { condition, body } => {
+            write!(f, "while (")?;
+            write_ast(f, condition, indent)?;
+            write!(f, ") ")?;
+            return write_block(f, body, indent);
+        }
+    };
+
+    // The operand on the side that an operator associates away from needs brackets if it has the
+    // same precedence. All binary operators except `^` are left-associative.
+    let own_precedence = precedence(ast);
+    let (lhs_precedence, rhs_precedence) = match ast {
+        AST::Power(..) => (own_precedence + 1, own_precedence),
+        _ => (own_precedence, own_precedence + 1),
+    };
+    write_operand(f, lhs, lhs_precedence)?;
+    write!(f, " {} ", op)?;
+    write_operand(f, rhs, rhs_precedence)
+}
+
+impl Display for AST {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write_ast(f, self, 0)
+    }
+}